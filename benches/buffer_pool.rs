@@ -0,0 +1,32 @@
+// Measures a lease and return against pools of different sizes (which should take the same time, however many buffers
+// the pool holds).
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use rusty_socks::buffer_pool::BufferPool;
+
+const POOL_SIZES: [usize; 4] = [1, 64, 1024, 16384];
+
+fn lease_and_return(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut group = c.benchmark_group("lease_and_return");
+
+    for pool_size in POOL_SIZES {
+        let pool = BufferPool::new(64, 0, false);
+
+        // Grow the pool to its size, and then return every buffer (so a lease takes an existing one).
+        runtime.block_on(async {
+            let buffers = futures::future::join_all((0..pool_size).map(|_| pool.lease())).await;
+            drop(buffers);
+        });
+
+        group.bench_with_input(BenchmarkId::from_parameter(pool_size), &pool, |b, pool| {
+            b.to_async(&runtime).iter(|| async { drop(pool.lease().await) });
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, lease_and_return);
+criterion_main!(benches);
@@ -0,0 +1,25 @@
+// Measures parsing CIDRs, and matching IPs against them.
+
+use std::net::IpAddr;
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use rusty_socks::helpers::Helpers;
+
+fn parse_cidr(c: &mut Criterion) {
+    c.bench_function("parse_cidr/ipv4", |b| b.iter(|| Helpers::parse_cidr(black_box("10.20.0.0/16")).unwrap()));
+    c.bench_function("parse_cidr/ipv6", |b| b.iter(|| Helpers::parse_cidr(black_box("2001:db8:abcd::/48")).unwrap()));
+}
+
+fn is_ip_in_cidr(c: &mut Criterion) {
+    let ipv4_cidr = Helpers::parse_cidr("10.20.0.0/16").unwrap();
+    let ipv6_cidr = Helpers::parse_cidr("2001:db8:abcd::/48").unwrap();
+    let ipv4 = "10.20.30.40".parse::<IpAddr>().unwrap();
+    let ipv6 = "2001:db8:abcd:12::1".parse::<IpAddr>().unwrap();
+
+    c.bench_function("is_ip_in_cidr/ipv4", |b| b.iter(|| Helpers::is_ip_in_cidr(black_box(&ipv4), black_box(&ipv4_cidr)).unwrap()));
+    c.bench_function("is_ip_in_cidr/ipv6", |b| b.iter(|| Helpers::is_ip_in_cidr(black_box(&ipv6), black_box(&ipv6_cidr)).unwrap()));
+}
+
+criterion_group!(benches, parse_cidr, is_ip_in_cidr);
+criterion_main!(benches);
@@ -0,0 +1,104 @@
+// Measures the throughput of a CONNECT through the proxy (over loopback) for each pump and buffer size.  Run it with
+// `cargo bench --bench throughput`.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use clap::Parser;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use rusty_socks::config::{self, Args};
+use rusty_socks::server;
+
+// The bytes sent through each connection.
+const TRANSFER: usize = 256 * 1024 * 1024;
+
+const BUFFER_SIZES: [usize; 5] = [2 * 1024, 8 * 1024, 32 * 1024, 128 * 1024, 256 * 1024];
+
+const PUMP_MODES: [&str; 2] = ["copy", "custom"];
+
+// Starts the proxy on an ephemeral port, and returns its address.
+async fn start_proxy(buffer_size: usize, pump_mode: &str) -> SocketAddr {
+    let buffer_size = buffer_size.to_string();
+    let args = Args::try_parse_from(["rusty_socks", "--buffer-size", &buffer_size, "--pump-mode", pump_mode]).unwrap();
+    let config = Arc::new(config::from_args_file_and_env(args).await.unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        server::serve_on(vec![listener], config).await.map_err(|e| e.to_string()).unwrap();
+    });
+
+    addr
+}
+
+// Starts a server that reads (and discards) everything, and returns its address.
+async fn start_sink() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            tokio::spawn(async move {
+                tokio::io::copy(&mut stream, &mut tokio::io::sink()).await.unwrap_or_default();
+            });
+        }
+    });
+
+    addr
+}
+
+// Connects to the sink through the proxy, and returns the transfer rate (in MiB/s).
+async fn measure(proxy: SocketAddr, sink: SocketAddr) -> f64 {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.set_nodelay(true).unwrap();
+
+    let ip = match sink {
+        SocketAddr::V4(a) => a.ip().octets(),
+        SocketAddr::V6(_) => unreachable!()
+    };
+
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    client.read_exact(&mut [0u8; 2]).await.unwrap();
+    client.write_all(&[&[0x05, 0x01, 0x00, 0x01][..], &ip, &sink.port().to_be_bytes()].concat()).await.unwrap();
+    client.read_exact(&mut [0u8; 10]).await.unwrap();
+
+    let chunk = vec![0xA5u8; 1024 * 1024];
+    let start = Instant::now();
+
+    for _ in 0..TRANSFER / chunk.len() {
+        client.write_all(&chunk).await.unwrap();
+    }
+
+    // Wait for the proxy to pass everything along (it closes the client once the sink has read to the EOF).
+    client.shutdown().await.unwrap();
+    client.read_to_end(&mut Vec::new()).await.unwrap();
+
+    TRANSFER as f64 / (1024.0 * 1024.0) / start.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    runtime.block_on(async {
+        let sink = start_sink().await;
+
+        println!("{:>12}  {:>6}  {:>10}", "buffer_size", "pump", "MiB/s");
+
+        for buffer_size in BUFFER_SIZES {
+            for pump_mode in PUMP_MODES {
+                let proxy = start_proxy(buffer_size, pump_mode).await;
+
+                // Warm up (the first connection allocates the pooled buffer).
+                measure(proxy, sink).await;
+
+                println!("{:>12}  {:>6}  {:>10.0}", buffer_size, pump_mode, measure(proxy, sink).await);
+            }
+        }
+    });
+}
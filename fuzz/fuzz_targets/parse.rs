@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use rusty_socks::handshake::Handshake;
+use rusty_socks::request::Request;
+
+// Both parsers run on attacker-controlled bytes, so they must only ever return `Ok` or `Err`.
+fuzz_target!(|data: &[u8]| {
+    let _ = Handshake::from_data(data);
+    let _ = Request::from_data(data);
+});
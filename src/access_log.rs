@@ -0,0 +1,87 @@
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use log::warn;
+
+use crate::helpers::Res;
+
+// One completed connection, as recorded in the access log.
+pub struct Entry<'a> {
+    pub client: &'a str,
+    pub destination: Option<&'a str>,
+    pub port: Option<u16>,
+    pub reply: Option<u8>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration_ms: u64
+}
+
+impl Entry<'_> {
+    // Renders the entry as space-separated fields (in a fixed order, with `-` for anything unknown):
+    // `timestamp client destination port reply bytes_up bytes_down duration_ms`.
+    fn to_line(&self, timestamp: &str) -> String {
+        format!(
+            "{} {} {} {} {} {} {} {}\n",
+            timestamp,
+            sanitize(self.client),
+            self.destination.map_or("-".to_owned(), sanitize),
+            self.port.map_or("-".to_owned(), |p| p.to_string()),
+            self.reply.map_or("-".to_owned(), |r| r.to_string()),
+            self.bytes_up,
+            self.bytes_down,
+            self.duration_ms
+        )
+    }
+}
+
+// The access log file, shared by every connection (the lock keeps each line whole).
+pub struct AccessLog {
+    file: Mutex<File>
+}
+
+impl AccessLog {
+    pub async fn open(path: &str) -> Res<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path).await?;
+
+        Ok(AccessLog { file: Mutex::new(file) })
+    }
+
+    // Failing to write the access log should never end a connection, so errors are only logged.
+    pub async fn write(&self, entry: &Entry<'_>) {
+        let line = entry.to_line(&chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true));
+        let mut file = self.file.lock().await;
+
+        let result = async {
+            file.write_all(line.as_bytes()).await?;
+            file.flush().await
+        }.await;
+
+        if let Err(e) = result {
+            warn!("Could not write to the access log.  {}", e);
+        }
+    }
+}
+
+// Domain names come from the client, so keep them from breaking the line into extra fields.
+fn sanitize(field: &str) -> String {
+    field.chars().map(|c| if c.is_whitespace() || c.is_control() { '?' } else { c }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_line_renders_every_field() {
+        let entry = Entry { client: "10.0.0.1", destination: Some("example.com"), port: Some(443), reply: Some(0), bytes_up: 12, bytes_down: 345, duration_ms: 67 };
+
+        assert_eq!("2020-01-01T00:00:00.000Z 10.0.0.1 example.com 443 0 12 345 67\n", entry.to_line("2020-01-01T00:00:00.000Z"));
+    }
+
+    #[test]
+    fn to_line_marks_unknown_fields_and_sanitizes() {
+        let entry = Entry { client: "10.0.0.1", destination: Some("bad host\n"), port: None, reply: None, bytes_up: 0, bytes_down: 0, duration_ms: 1 };
+
+        assert_eq!("t 10.0.0.1 bad?host? - - 0 0 1\n", entry.to_line("t"));
+    }
+}
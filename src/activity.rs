@@ -0,0 +1,85 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, ReadBuf};
+use tokio::time::{Duration, Instant};
+
+// Tracks the last time data moved through a connection, so that timeouts can be measured from the
+// last activity rather than from the start of the connection.
+pub struct Activity {
+    start: Instant,
+    last_millis: AtomicU64
+}
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity { start: Instant::now(), last_millis: AtomicU64::new(0) }
+    }
+
+    pub fn touch(&self) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last_millis.store(elapsed, Ordering::Relaxed);
+    }
+
+    pub fn idle_deadline(&self, timeout: Duration) -> Instant {
+        self.start + Duration::from_millis(self.last_millis.load(Ordering::Relaxed)) + timeout
+    }
+
+    // Resolves once no activity has been recorded for `timeout`.
+    pub async fn idle(&self, timeout: Duration) {
+        loop {
+            let deadline = self.idle_deadline(timeout);
+
+            if Instant::now() >= deadline {
+                return;
+            }
+
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+// The number of bytes moved in each direction of a connection.
+#[derive(Clone, Copy, Default)]
+pub struct Transfer {
+    pub bytes_up: u64,
+    pub bytes_down: u64
+}
+
+// Wraps a reader, and records activity (and the byte count) whenever bytes are read from it.
+pub struct ActivityReader<'a, R> {
+    inner: R,
+    activity: &'a Activity,
+    bytes: u64
+}
+
+impl<'a, R> ActivityReader<'a, R> {
+    pub fn new(inner: R, activity: &'a Activity) -> Self {
+        ActivityReader { inner, activity, bytes: 0 }
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ActivityReader<'_, R> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled_before = buf.filled().len();
+
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+
+        if let Poll::Ready(Ok(())) = poll {
+            let read = buf.filled().len() - filled_before;
+
+            if read > 0 {
+                this.bytes += read as u64;
+                this.activity.touch();
+            }
+        }
+
+        poll
+    }
+}
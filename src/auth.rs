@@ -0,0 +1,498 @@
+use std::collections::HashMap;
+
+use crate::helpers::{Res, Void, IntoError, Helpers, Cidr};
+use crate::request::Destination;
+
+// The methods (RFC 1928) that the server can select.
+pub const NO_AUTH: u8 = 0x00;
+pub const GSSAPI: u8 = 0x01;
+pub const USER_PASS: u8 = 0x02;
+pub const NO_ACCEPTABLE_METHODS: u8 = 0xFF;
+
+// The username/password sub-negotiation (RFC 1929) has its own version, and a status of zero for success.
+const USER_PASS_VERSION: u8 = 0x01;
+pub const USER_PASS_SUCCESS: [u8; 2] = [USER_PASS_VERSION, 0x00];
+pub const USER_PASS_FAILURE: [u8; 2] = [USER_PASS_VERSION, 0x01];
+
+// The users that clients may authenticate as.
+#[derive(Default)]
+pub struct Credentials {
+    users: HashMap<String, User>
+}
+
+struct User {
+    secret: Secret,
+    // The destinations the user is limited to (a user without a list may connect anywhere).
+    allowed: Option<Vec<Allowed>>
+}
+
+enum Secret {
+    Plain(String),
+    Bcrypt(String)
+}
+
+// An IP destination matches a CIDR, and a domain destination matches a host pattern (domains are not resolved for the check).
+enum Allowed {
+    Cidr(Cidr),
+    Host(String)
+}
+
+impl Credentials {
+    // Reads a file of `username:password` lines, where the password may be a bcrypt hash (blank lines, and lines
+    // starting with `#`, are skipped).  A line may go on to limit the user's destinations, after whitespace, with a comma-
+    // separated list of CIDRs and host patterns (e.g., `alice:secret 10.0.0.0/8,*.example.com`), so passwords in the file
+    // cannot contain whitespace.
+    pub async fn load(path: &str) -> Res<Self> {
+        let text = match tokio::fs::read_to_string(path).await {
+            Ok(t) => t,
+            Err(e) => return format!("Could not read the credentials file `{}`.  {}", path, e).into_error()
+        };
+
+        let mut credentials = Credentials::default();
+
+        for (number, line) in text.lines().enumerate().map(|(n, l)| (n + 1, l.trim())) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (user_pass, allowed) = match line.split_once(char::is_whitespace) {
+                Some((user_pass, allowed)) => (user_pass, Some(allowed.trim())),
+                None => (line, None)
+            };
+
+            let result = match user_pass.split_once(':') {
+                Some((username, password)) => credentials.add(username.to_owned(), password.to_owned(), allowed),
+                None => "The line must be `username:password`, optionally followed by the allowed destinations.".into_error()
+            };
+
+            if let Err(e) = result {
+                return format!("Line {} of the credentials file `{}` is invalid.  {}", number, path, e).into_error();
+            }
+        }
+
+        Ok(credentials)
+    }
+
+    // Passwords that look like bcrypt hashes (`$2a$`, `$2b$`, or `$2y$`) are checked as hashes.  Both the username and a
+    // plain password must fit the one-byte length prefixes of the sub-negotiation.
+    pub fn add(&mut self, username: String, password: String, allowed: Option<&str>) -> Void {
+        let secret = if ["$2a$", "$2b$", "$2y$"].iter().any(|p| password.starts_with(p)) {
+            Secret::Bcrypt(password)
+        } else {
+            Secret::Plain(password)
+        };
+
+        let password_length = match &secret {
+            Secret::Plain(p) => p.len(),
+            Secret::Bcrypt(_) => 1
+        };
+
+        if username.is_empty() || username.len() > 255 || password_length == 0 || password_length > 255 {
+            return "The username and password must each be between 1 and 255 bytes.".into_error();
+        }
+
+        let allowed = match allowed {
+            Some(list) => Some(list.split(',').map(str::trim).filter(|a| !a.is_empty()).map(Allowed::parse).collect::<Res<Vec<_>>>()?),
+            None => None
+        };
+
+        if self.users.insert(username, User { secret, allowed }).is_some() {
+            return "The username is listed more than once.".into_error();
+        }
+
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty()
+    }
+
+    pub async fn verify(&self, username: &[u8], password: &[u8]) -> bool {
+        match std::str::from_utf8(username).ok().and_then(|u| self.users.get(u)) {
+            Some(u) => u.secret.verify(password).await,
+            // An unknown user is still checked (against a bcrypt secret, when there is one, with the result ignored), so that
+            // the timing does not reveal which usernames exist.
+            None => {
+                let decoy = self.users.values().find(|u| matches!(u.secret, Secret::Bcrypt(_))).or_else(|| self.users.values().next());
+
+                if let Some(u) = decoy {
+                    u.secret.verify(password).await;
+                }
+
+                false
+            }
+        }
+    }
+
+    // Whether the (authenticated) user may connect to the destination.
+    pub fn is_allowed(&self, username: &str, destination: &Destination) -> bool {
+        let allowed = match self.users.get(username).and_then(|u| u.allowed.as_ref()) {
+            Some(a) => a,
+            None => return true
+        };
+
+        allowed.iter().any(|a| match (a, destination) {
+            (Allowed::Cidr(cidr), Destination::Ipv4Addr(ip)) => cidr.contains(&(*ip).into()),
+            (Allowed::Cidr(cidr), Destination::Ipv6Addr(ip)) => cidr.contains(&(*ip).into()),
+            (Allowed::Host(pattern), Destination::Domain(domain)) => Helpers::is_host_match(pattern, domain),
+            _ => false
+        })
+    }
+}
+
+impl Secret {
+    async fn verify(&self, password: &[u8]) -> bool {
+        match self {
+            // Compare in constant time, so that the timing does not reveal how much of a guess was right.
+            Secret::Plain(expected) => constant_time_eq(expected.as_bytes(), password),
+            // Hashing is slow (by design), so keep it off of the runtime's threads.
+            Secret::Bcrypt(hash) => {
+                let (hash, password) = (hash.clone(), password.to_vec());
+
+                tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false)).await.unwrap_or(false)
+            }
+        }
+    }
+}
+
+impl Allowed {
+    // Anything that parses as an IP (or a CIDR) is a CIDR, and the rest are host patterns.
+    fn parse(entry: &str) -> Res<Allowed> {
+        match Helpers::parse_cidr(entry) {
+            Ok(cidr) => Ok(Allowed::Cidr(cidr)),
+            Err(_) if entry.contains('/') || entry.contains(':') => format!("The allowed destination `{}` is not a valid CIDR.", entry).into_error(),
+            Err(_) => Ok(Allowed::Host(entry.to_owned()))
+        }
+    }
+}
+
+// The methods that the server can run, named as in the config.
+//
+// GSSAPI (RFC 1961) is not supported, so a client that only offers it is refused with NO ACCEPTABLE METHODS.  To plug
+// it in, add a `Gssapi` variant here (with its code and name), the condition it can run under to `can_run`, and its
+// message exchange to `sub_negotiate` (which runs once per message, until it returns `Done`, `Failed`, or `Refused`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    NoAuth,
+    UserPass
+}
+
+// The preference order, unless it is configured: USERNAME/PASSWORD (when there are credentials), and then NO AUTH.
+pub const DEFAULT_METHODS: [Method; 2] = [Method::UserPass, Method::NoAuth];
+
+impl Method {
+    pub fn code(self) -> u8 {
+        match self {
+            Method::NoAuth => NO_AUTH,
+            Method::UserPass => USER_PASS
+        }
+    }
+
+    // The method for a code that the server can run (and none for the rest, like NO ACCEPTABLE METHODS).
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            NO_AUTH => Some(Method::NoAuth),
+            USER_PASS => Some(Method::UserPass),
+            _ => None
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Method::NoAuth => "no_auth",
+            Method::UserPass => "user_pass"
+        }
+    }
+}
+
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl std::str::FromStr for Method {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "no_auth" => Ok(Method::NoAuth),
+            "user_pass" => Ok(Method::UserPass),
+            _ => format!("Unknown auth method `{}` (expected `no_auth` or `user_pass`).", s).into_error()
+        }
+    }
+}
+
+// The steps of the method negotiation (RFC 1928, section 3).
+#[derive(Debug, PartialEq)]
+pub enum Step {
+    // The selected method's sub-negotiation comes next.
+    SubNegotiate(Method),
+    // The client is authenticated (as the user, if the method has one).
+    Done(Option<String>),
+    // The client gave a username that did not authenticate (and the connection should close).
+    Failed(String),
+    // The client offered no acceptable method, or sent a malformed sub-negotiation (and the connection should close).
+    Refused(&'static str)
+}
+
+// The longest username that is logged (a client may send up to 255 bytes of anything).
+const MAX_LOGGED_USERNAME: usize = 32;
+
+// The method a client negotiated, and whether it authenticated, for the connection's summary (and audit trail).
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuthRecord {
+    // The selected method (none, when the client offered no acceptable method).
+    pub method: Option<Method>,
+    // The username the client gave (for methods that have one).
+    pub username: Option<String>,
+    pub succeeded: bool
+}
+
+impl AuthRecord {
+    // Records where a negotiation ended (`None` while the sub-negotiation is still running).
+    pub fn of(method: Option<Method>, step: &Step) -> Option<Self> {
+        match step {
+            Step::SubNegotiate(_) => None,
+            Step::Done(user) => Some(AuthRecord { method, username: user.clone(), succeeded: true }),
+            Step::Failed(username) => Some(AuthRecord { method, username: Some(username.clone()), succeeded: false }),
+            Step::Refused(_) => Some(AuthRecord { method, username: None, succeeded: false })
+        }
+    }
+
+    pub fn method_str(&self) -> &'static str {
+        self.method.map_or("none", Method::as_str)
+    }
+
+    pub fn result_str(&self) -> &'static str {
+        if self.succeeded { "succeeded" } else { "failed" }
+    }
+
+    // The username, truncated (and with control characters escaped) so that it is safe to log.
+    pub fn username_for_log(&self) -> String {
+        let username = self.username.as_deref().unwrap_or_default();
+        let truncated = username.chars().take(MAX_LOGGED_USERNAME).flat_map(char::escape_debug).collect::<String>();
+
+        if username.chars().count() > MAX_LOGGED_USERNAME { format!("{}...", truncated) } else { truncated }
+    }
+}
+
+// Negotiates a method with a client: the most preferred method that the client offers (and that the server can run) is
+// selected, and then its sub-negotiation runs until the client is done or refused.
+pub struct Negotiation<'a> {
+    credentials: Option<&'a Credentials>,
+    preference: &'a [Method],
+    require_auth: bool
+}
+
+impl<'a> Negotiation<'a> {
+    pub fn new(credentials: Option<&'a Credentials>, preference: &'a [Method], require_auth: bool) -> Self {
+        Negotiation { credentials, preference, require_auth }
+    }
+
+    // Returns the method to reply with, and the next step.
+    pub fn select(&self, offered: &[u8]) -> (u8, Step) {
+        match self.preference.iter().copied().find(|m| offered.contains(&m.code()) && self.can_run(*m)) {
+            Some(Method::NoAuth) => (NO_AUTH, Step::Done(None)),
+            Some(method) => (method.code(), Step::SubNegotiate(method)),
+            None if offered.contains(&GSSAPI) => (NO_ACCEPTABLE_METHODS, Step::Refused("The client offered GSSAPI, which is not supported, and no other acceptable method.")),
+            None => (NO_ACCEPTABLE_METHODS, Step::Refused("The client did not offer an acceptable method."))
+        }
+    }
+
+    // Runs the method's sub-negotiation on the client's message, and returns the reply and the next step.
+    pub async fn sub_negotiate(&self, method: Method, data: &[u8]) -> (&'static [u8], Step) {
+        match (method, self.credentials) {
+            (Method::UserPass, Some(credentials)) => {
+                // A malformed request is simply a failure (and its error is not held across the verification).
+                let parsed = parse_user_pass(data).ok().map(|(u, p)| (String::from_utf8_lossy(u).into_owned(), p.to_vec()));
+
+                match parsed {
+                    Some((username, password)) if credentials.verify(username.as_bytes(), &password).await => (&USER_PASS_SUCCESS, Step::Done(Some(username))),
+                    Some((username, _)) => (&USER_PASS_FAILURE, Step::Failed(username)),
+                    None => (&USER_PASS_FAILURE, Step::Refused("The client sent a malformed username/password request."))
+                }
+            },
+            // Only methods with a sub-negotiation are ever selected into one.
+            _ => (&[], Step::Refused("The method has no sub-negotiation."))
+        }
+    }
+
+    // NO AUTH cannot run when authentication is required, and USERNAME/PASSWORD needs credentials.
+    fn can_run(&self, method: Method) -> bool {
+        match method {
+            Method::NoAuth => !self.require_auth,
+            Method::UserPass => self.credentials.is_some()
+        }
+    }
+}
+
+// Parses the username/password request: `VER ULEN UNAME PLEN PASSWD`.
+pub fn parse_user_pass(data: &[u8]) -> Res<(&[u8], &[u8])> {
+    if data.first() != Some(&USER_PASS_VERSION) {
+        return "The username/password request has a bad version.".into_error();
+    }
+
+    let username_length = usize::from(*data.get(1).unwrap_or(&0));
+    let username = data.get(2..2 + username_length);
+    let password_length = data.get(2 + username_length).map(|l| usize::from(*l));
+
+    match (username, password_length) {
+        (Some(username), Some(password_length)) => match data.get(3 + username_length..3 + username_length + password_length) {
+            Some(password) => Ok((username, password)),
+            None => "The username/password request is shorter than its password.".into_error()
+        },
+        _ => "The username/password request is shorter than its username.".into_error()
+    }
+}
+
+fn constant_time_eq(expected: &[u8], actual: &[u8]) -> bool {
+    if expected.len() != actual.len() {
+        return false;
+    }
+
+    expected.iter().zip(actual).fold(0u8, |acc, (e, a)| acc | (e ^ a)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn credentials() -> Credentials {
+        let mut credentials = Credentials::default();
+        credentials.add("user".to_owned(), "pass".to_owned(), None).unwrap();
+
+        credentials
+    }
+
+    fn select(offered: &[u8], credentials: Option<&Credentials>, preference: &[Method], require_auth: bool) -> (u8, Step) {
+        Negotiation::new(credentials, preference, require_auth).select(offered)
+    }
+
+    #[test]
+    fn select_prefers_user_pass_when_configured() {
+        assert_eq!((USER_PASS, Step::SubNegotiate(Method::UserPass)), select(&[NO_AUTH, USER_PASS], Some(&credentials()), &DEFAULT_METHODS, false));
+        assert_eq!((NO_AUTH, Step::Done(None)), select(&[NO_AUTH, USER_PASS], None, &DEFAULT_METHODS, false));
+        assert_eq!((NO_AUTH, Step::Done(None)), select(&[NO_AUTH], Some(&credentials()), &DEFAULT_METHODS, false));
+    }
+
+    #[test]
+    fn select_follows_the_preference_order() {
+        let preference = [Method::NoAuth, Method::UserPass];
+
+        assert_eq!((NO_AUTH, Step::Done(None)), select(&[USER_PASS, NO_AUTH], Some(&credentials()), &preference, false));
+        assert_eq!((USER_PASS, Step::SubNegotiate(Method::UserPass)), select(&[USER_PASS], Some(&credentials()), &preference, false));
+        assert_eq!(NO_ACCEPTABLE_METHODS, select(&[NO_AUTH], Some(&credentials()), &[Method::UserPass], false).0);
+    }
+
+    #[test]
+    fn select_never_selects_no_auth_when_required() {
+        assert_eq!(NO_ACCEPTABLE_METHODS, select(&[NO_AUTH], Some(&credentials()), &DEFAULT_METHODS, true).0);
+        assert_eq!(USER_PASS, select(&[NO_AUTH, USER_PASS], Some(&credentials()), &DEFAULT_METHODS, true).0);
+    }
+
+    #[test]
+    fn select_refuses_methods_the_client_did_not_offer() {
+        assert!(matches!(select(&[], None, &DEFAULT_METHODS, false), (NO_ACCEPTABLE_METHODS, Step::Refused(_))));
+        assert!(matches!(select(&[USER_PASS], None, &DEFAULT_METHODS, false), (NO_ACCEPTABLE_METHODS, Step::Refused(_))));
+    }
+
+    #[test]
+    fn select_treats_gssapi_as_unsupported() {
+        assert!(matches!(select(&[GSSAPI], Some(&credentials()), &DEFAULT_METHODS, false), (NO_ACCEPTABLE_METHODS, Step::Refused(_))));
+        assert_eq!(NO_AUTH, select(&[GSSAPI, NO_AUTH], None, &DEFAULT_METHODS, false).0);
+    }
+
+    #[tokio::test]
+    async fn sub_negotiate_authenticates_user_pass() {
+        let credentials = credentials();
+        let negotiation = Negotiation::new(Some(&credentials), &DEFAULT_METHODS, false);
+
+        assert_eq!((&USER_PASS_SUCCESS[..], Step::Done(Some("user".to_owned()))), negotiation.sub_negotiate(Method::UserPass, b"\x01\x04user\x04pass").await);
+        assert!(matches!(negotiation.sub_negotiate(Method::UserPass, b"\x01\x04user\x04nope").await, (reply, Step::Failed(username)) if reply == USER_PASS_FAILURE && username == "user"));
+        assert!(matches!(negotiation.sub_negotiate(Method::UserPass, b"\x01\x04us").await, (reply, Step::Refused(_)) if reply == USER_PASS_FAILURE));
+    }
+
+    #[test]
+    fn auth_record_of_records_the_end_of_the_negotiation() {
+        assert_eq!(None, AuthRecord::of(Some(Method::UserPass), &Step::SubNegotiate(Method::UserPass)));
+
+        let record = AuthRecord::of(Some(Method::UserPass), &Step::Failed("user".to_owned())).unwrap();
+        assert_eq!(("user_pass", "failed", Some("user")), (record.method_str(), record.result_str(), record.username.as_deref()));
+
+        let record = AuthRecord::of(Method::from_code(NO_ACCEPTABLE_METHODS), &Step::Refused("")).unwrap();
+        assert_eq!(("none", "failed", None), (record.method_str(), record.result_str(), record.username.as_deref()));
+
+        let record = AuthRecord::of(Method::from_code(NO_AUTH), &Step::Done(None)).unwrap();
+        assert_eq!(("no_auth", "succeeded"), (record.method_str(), record.result_str()));
+    }
+
+    #[test]
+    fn username_for_log_truncates_and_escapes() {
+        let record = |username: &str| AuthRecord { method: Some(Method::UserPass), username: Some(username.to_owned()), succeeded: false };
+
+        assert_eq!("user", record("user").username_for_log());
+        assert_eq!("bad\\nname", record("bad\nname").username_for_log());
+        assert_eq!(format!("{}...", "a".repeat(32)), record(&"a".repeat(40)).username_for_log());
+    }
+
+    #[test]
+    fn parse_user_pass_reads_both_fields() {
+        assert_eq!((&b"user"[..], &b"pass"[..]), parse_user_pass(b"\x01\x04user\x04pass").unwrap());
+
+        assert!(parse_user_pass(b"\x05\x04user\x04pass").is_err());
+        assert!(parse_user_pass(b"\x01\x04us").is_err());
+        assert!(parse_user_pass(b"\x01\x04user\x04pa").is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_requires_both_fields() {
+        assert!(credentials().verify(b"user", b"pass").await);
+        assert!(!credentials().verify(b"user", b"pasS").await);
+        assert!(!credentials().verify(b"use", b"pass").await);
+    }
+
+    #[tokio::test]
+    async fn load_reads_plain_and_bcrypt_passwords() {
+        let path = std::env::temp_dir().join(format!("rusty_socks_credentials_{}", std::process::id()));
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        std::fs::write(&path, format!("# Users.\nalice:wonderland\n\nbob:{}  10.0.0.0/8\n", hash)).unwrap();
+
+        let credentials = Credentials::load(path.to_str().unwrap()).await.unwrap();
+        std::fs::remove_file(&path).unwrap_or_default();
+
+        assert!(credentials.verify(b"alice", b"wonderland").await);
+        assert!(credentials.verify(b"bob", b"secret").await);
+        assert!(!credentials.verify(b"bob", hash.as_bytes()).await);
+        assert!(!credentials.is_allowed("bob", &Destination::Domain("example.com".to_owned())));
+
+        // An unknown user is checked against the bcrypt secret (to take as long), but never passes.
+        assert!(!credentials.verify(b"carol", b"secret").await);
+    }
+
+    #[test]
+    fn is_allowed_applies_the_user_list() {
+        let mut credentials = credentials();
+        credentials.add("scoped".to_owned(), "pass".to_owned(), Some("10.0.0.0/8, *.example.com")).unwrap();
+
+        assert!(credentials.is_allowed("user", &Destination::Domain("anywhere.net".to_owned())));
+        assert!(credentials.is_allowed("scoped", &Destination::Ipv4Addr("10.1.2.3".parse().unwrap())));
+        assert!(credentials.is_allowed("scoped", &Destination::Domain("api.example.com".to_owned())));
+        assert!(!credentials.is_allowed("scoped", &Destination::Ipv4Addr("192.168.0.1".parse().unwrap())));
+        assert!(!credentials.is_allowed("scoped", &Destination::Ipv6Addr("::1".parse().unwrap())));
+        assert!(!credentials.is_allowed("scoped", &Destination::Domain("example.net".to_owned())));
+
+        assert!(credentials.add("bad".to_owned(), "pass".to_owned(), Some("10.0.0.0/33")).is_err());
+    }
+
+    #[tokio::test]
+    async fn load_rejects_malformed_lines() {
+        let path = std::env::temp_dir().join(format!("rusty_socks_credentials_bad_{}", std::process::id()));
+        std::fs::write(&path, "alice:wonderland\nbob\n").unwrap();
+
+        let error = Credentials::load(path.to_str().unwrap()).await.err().unwrap();
+        std::fs::remove_file(&path).unwrap_or_default();
+
+        assert!(error.to_string().starts_with("Line 2 of the credentials file"));
+    }
+}
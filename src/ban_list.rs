@@ -0,0 +1,129 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use log::warn;
+
+// Past this many tracked sources, stale failure records are pruned (so that a scan from many IPs cannot grow the map forever).
+const MAX_TRACKED: usize = 10_000;
+
+// Temporarily bans sources that fail too many handshakes within a window.
+pub struct BanList {
+    threshold: u32,
+    window: Duration,
+    duration: Duration,
+    state: Mutex<State>
+}
+
+#[derive(Default)]
+struct State {
+    failures: HashMap<IpAddr, Failures>,
+    bans: HashMap<IpAddr, Instant>
+}
+
+// The failures counted since the start of the current window.
+struct Failures {
+    since: Instant,
+    count: u32
+}
+
+impl BanList {
+    // A threshold of zero disables banning.
+    pub fn new(threshold: u32, window: u64, duration: u64) -> Self {
+        BanList { threshold, window: Duration::from_millis(window), duration: Duration::from_millis(duration), state: Mutex::new(State::default()) }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        if self.threshold == 0 {
+            return false;
+        }
+
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        match state.bans.get(ip) {
+            Some(expiry) if Instant::now() < *expiry => true,
+            Some(_) => {
+                state.bans.remove(ip);
+                false
+            },
+            None => false
+        }
+    }
+
+    pub fn record_failure(&self, ip: IpAddr) {
+        if self.threshold == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        if state.failures.len() >= MAX_TRACKED {
+            let window = self.window;
+            state.failures.retain(|_, f| now.duration_since(f.since) < window);
+        }
+
+        let failures = state.failures.entry(ip).or_insert(Failures { since: now, count: 0 });
+
+        if now.duration_since(failures.since) >= self.window {
+            *failures = Failures { since: now, count: 0 };
+        }
+
+        failures.count += 1;
+
+        if failures.count >= self.threshold {
+            state.failures.remove(&ip);
+            state.bans.insert(ip, now + self.duration);
+
+            warn!("Banning {} for {} ms after {} failed handshakes.", ip, self.duration.as_millis(), self.threshold);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: IpAddr = IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1));
+
+    #[test]
+    fn record_failure_bans_at_the_threshold() {
+        let bans = BanList::new(3, 60_000, 60_000);
+
+        bans.record_failure(IP);
+        bans.record_failure(IP);
+        assert!(!bans.is_banned(&IP));
+
+        bans.record_failure(IP);
+        assert!(bans.is_banned(&IP));
+        assert!(!bans.is_banned(&IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2))));
+    }
+
+    #[test]
+    fn record_failure_restarts_the_count_after_the_window() {
+        let bans = BanList::new(2, 0, 60_000);
+
+        bans.record_failure(IP);
+        bans.record_failure(IP);
+
+        assert!(!bans.is_banned(&IP));
+    }
+
+    #[test]
+    fn is_banned_expires() {
+        let bans = BanList::new(1, 60_000, 0);
+
+        bans.record_failure(IP);
+
+        assert!(!bans.is_banned(&IP));
+    }
+
+    #[test]
+    fn zero_threshold_never_bans() {
+        let bans = BanList::new(0, 60_000, 60_000);
+
+        bans.record_failure(IP);
+
+        assert!(!bans.is_banned(&IP));
+    }
+}
@@ -1,65 +1,205 @@
 use std::sync::Arc;
-use tokio::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use log::info;
 
+// The indices of unleased buffers, shared with each `Buffer` so that it can return itself on drop.
+#[derive(Default)]
+struct FreeList {
+    indices: std::sync::Mutex<Vec<usize>>,
+    returned: Notify,
+    // The most buffers ever leased at once.
+    peak_leased: AtomicUsize,
+    // The number of buffers, so that the stats can be read without the pool.
+    total: AtomicUsize,
+    // Whether a returned buffer is zeroed, so that a connection's data does not linger in it for the next lease.
+    zero_on_return: bool
+}
+
+// A snapshot of the pool's utilization.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PoolStats {
+    pub leased: usize,
+    pub total: usize,
+    pub bytes_allocated: usize,
+    pub peak_leased: usize
+}
+
+// Leases are taken through `&self`, so the pool can be shared (e.g., behind an `Arc`) by several accept loops.
 pub struct BufferPool {
     buffer_size: usize,
-    buffers: Vec<Arc<Mutex<Vec<u8>>>>
+    max_buffers: usize,
+    buffers: std::sync::Mutex<Vec<Arc<Mutex<Vec<u8>>>>>,
+    free: Arc<FreeList>
 }
 
 impl BufferPool {
-    pub fn new(buffer_size: usize) -> Self {
-        BufferPool { buffer_size, buffers: Vec::<Arc<Mutex<Vec<u8>>>>::new() }
+    // A `max_buffers` of zero allows the pool to grow without limit.  With `zero_on_return`, each buffer is zeroed as it is
+    // returned (which costs a write of the whole buffer per connection).
+    pub fn new(buffer_size: usize, max_buffers: usize, zero_on_return: bool) -> Self {
+        BufferPool { buffer_size, max_buffers, buffers: std::sync::Mutex::new(Vec::new()), free: Arc::new(FreeList { zero_on_return, ..FreeList::default() }) }
     }
 
-    pub fn lease(&mut self) -> Buffer {
-        let mut free_buffer_index: Option<usize> = None;
+    pub async fn lease(&self) -> Buffer {
+        loop {
+            // Register for a returned buffer before looking, so that a return in between is not missed.
+            let returned = self.free.returned.notified();
+            tokio::pin!(returned);
+            returned.as_mut().enable();
 
-        // Find an unleased buffer.
-        for k in 0..self.buffers.len() {
-            let ref_count = Arc::strong_count(&self.buffers[k]);
-            if ref_count < 2 {
-                free_buffer_index = Some(k);
-                break;
-            } 
-        }
+            if let Some(buffer) = self.try_lease() {
+                return buffer;
+            }
 
-        // Or, create a new one.
-        if free_buffer_index.is_none() {
-            free_buffer_index = Some(self.add_buffer());
+            // Otherwise, wait for a buffer to be returned.
+            returned.await;
         }
+    }
+
+    // Takes an unleased buffer, or creates a new one (if the cap allows).
+    fn try_lease(&self) -> Option<Buffer> {
+        let free_buffer_index = self.free.indices.lock().unwrap().pop();
+        let mut buffers = self.buffers.lock().unwrap();
+
+        let index = match free_buffer_index {
+            Some(i) => i,
+            // The cap is checked under the lock, so that concurrent leases cannot overshoot it.
+            None if self.max_buffers == 0 || buffers.len() < self.max_buffers => {
+                buffers.push(Arc::new(Mutex::new(vec![0; self.buffer_size])));
+                self.free.total.store(buffers.len(), Ordering::Relaxed);
 
-        assert_ne!(None, free_buffer_index);
+                buffers.len() - 1
+            },
+            None => return None
+        };
 
-        let index = free_buffer_index.unwrap();
+        self.free.peak_leased.fetch_max(self.free.leased(), Ordering::Relaxed);
 
-        Buffer::new(self.buffers[index].clone())
+        Some(Buffer::new(index, buffers[index].clone(), self.free.clone()))
     }
 
-    pub fn leased_count(&self) -> usize {
-        self.buffers.iter().filter(|b| Arc::strong_count(b) >= 2).count()
+    pub fn stats(&self) -> PoolStats {
+        self.free.stats(self.buffer_size)
     }
 
-    pub fn total_count(&self) -> usize {
-        self.buffers.len()
+    // Logs the pool's utilization on an interval (for as long as the runtime lives), to help with sizing `buffer_size` and `max_buffers`.
+    pub fn spawn_stats_logger(&self, interval: Duration) {
+        let free = self.free.clone();
+        let buffer_size = self.buffer_size;
+
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+
+            // The first tick completes immediately, and there is nothing to report yet.
+            ticks.tick().await;
+
+            loop {
+                ticks.tick().await;
+
+                let stats = free.stats(buffer_size);
+                info!(
+                    event = "buffer_pool",
+                    leased = stats.leased,
+                    total = stats.total,
+                    bytes_allocated = stats.bytes_allocated,
+                    peak_leased = stats.peak_leased;
+                    "Buffer pool: {} leased / {} total ({} bytes allocated).  Peak: {} leased.", stats.leased, stats.total, stats.bytes_allocated, stats.peak_leased
+                );
+            }
+        });
+    }
+}
+
+impl FreeList {
+    fn leased(&self) -> usize {
+        self.total.load(Ordering::Relaxed).saturating_sub(self.indices.lock().unwrap().len())
     }
 
-    fn add_buffer(&mut self) -> usize {
-        self.buffers.push(Arc::new(Mutex::new(vec![0; self.buffer_size])));
+    fn stats(&self, buffer_size: usize) -> PoolStats {
+        let total = self.total.load(Ordering::Relaxed);
 
-        self.buffers.len() - 1
+        PoolStats { leased: self.leased(), total, bytes_allocated: total * buffer_size, peak_leased: self.peak_leased.load(Ordering::Relaxed) }
     }
 }
 
 pub struct Buffer {
-    buffer: Arc<Mutex<Vec<u8>>>
+    index: usize,
+    buffer: Arc<Mutex<Vec<u8>>>,
+    free: Arc<FreeList>
 }
 
 impl Buffer {
-    fn new(buffer: Arc<Mutex<Vec<u8>>>) -> Buffer {
-        Buffer { buffer }
+    fn new(index: usize, buffer: Arc<Mutex<Vec<u8>>>, free: Arc<FreeList>) -> Buffer {
+        Buffer { index, buffer, free }
     }
 
     pub async fn get(&mut self) -> MutexGuard<'_, Vec<u8>> {
         self.buffer.lock().await
     }
-}
\ No newline at end of file
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        // The lease's guard (which borrows this) is gone, and the pool never locks its buffers, so the lock is free.
+        if self.free.zero_on_return {
+            self.buffer.try_lock().expect("A returned buffer is still locked.").fill(0);
+        }
+
+        // Return this buffer to the pool, and wake a waiting lease.
+        self.free.indices.lock().unwrap().push(self.index);
+        self.free.returned.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stats_track_the_peak() {
+        let pool = BufferPool::new(16, 0, false);
+
+        let first = pool.lease().await;
+        let second = pool.lease().await;
+        assert_eq!(PoolStats { leased: 2, total: 2, bytes_allocated: 32, peak_leased: 2 }, pool.stats());
+
+        drop(first);
+        drop(second);
+        let _third = pool.lease().await;
+        assert_eq!(PoolStats { leased: 1, total: 2, bytes_allocated: 32, peak_leased: 2 }, pool.stats());
+    }
+
+    #[tokio::test]
+    async fn zero_on_return_clears_the_buffer() {
+        for (zero_on_return, expected) in [(false, 7), (true, 0)] {
+            let pool = BufferPool::new(16, 1, zero_on_return);
+
+            pool.lease().await.get().await[15] = 7;
+            assert_eq!(expected, pool.lease().await.get().await[15]);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn lease_is_shared_across_tasks_within_the_cap() {
+        let pool = Arc::new(BufferPool::new(16, 2, false));
+
+        let tasks = (0..8).map(|_| {
+            let pool = pool.clone();
+
+            tokio::spawn(async move {
+                for _ in 0..50 {
+                    let mut buffer = pool.lease().await;
+                    buffer.get().await[0] = 1;
+                    tokio::task::yield_now().await;
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(PoolStats { leased: 0, total: 2, bytes_allocated: 32, peak_leased: 2 }, pool.stats());
+    }
+}
@@ -1,81 +1,1127 @@
-use std::{str::FromStr, ffi::OsStr};
-use serde::Deserialize;
+use std::{str::FromStr, net::{IpAddr, SocketAddr}};
+use clap::Parser;
+use log::LevelFilter;
+use serde::{Deserialize, Deserializer};
 use toml::from_str;
 
-use crate::helpers::{Res, Helpers};
+use crate::helpers::{Res, Void, Helpers, IntoError, Family, PortRange};
+use crate::logger::LogFormat;
+use crate::custom_pump::PumpMode;
+use crate::request::{MAX_REQUEST_SIZE, MAX_DOMAIN_LENGTH};
+use crate::upstream::UpstreamProxy;
+use crate::resolver::Resolver;
+use crate::server::LimitBehavior;
+use crate::auth::{Credentials, Method, DEFAULT_METHODS};
+use crate::proxy_protocol::Version;
 
-#[derive(Deserialize)]
-struct OptionalConfig {
+// Every field is optional, so that each source (CLI, file, env) can leave any of them unset.
+#[derive(Clone, Default, Deserialize, clap::Args)]
+pub struct OptionalConfig {
+    /// The interface to listen on [env: RS_LISTEN_INTERFACE] [default: all interfaces]
+    #[arg(long)]
     listen_interface: Option<String>,
+    /// The interface to connect to endpoints from [env: RS_ENDPOINT_INTERFACE] [default: any interface]
+    #[arg(long)]
     endpoint_interface: Option<String>,
-    port: Option<u16>,
+    /// A comma-separated list of IPs to connect to endpoints from, like one IPv4 and one IPv6 (instead of an endpoint interface) [env: RS_ENDPOINT_IP] [default: any IP]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    endpoint_ip: Option<Vec<IpAddr>>,
+    /// A comma-separated list of ports to listen on (where 0 picks a free port) [env: RS_PORT] [default: 1080]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    port: Option<Vec<u16>>,
+    /// The buffer size, in bytes, for each direction of a connection (at least 262, which fits the largest SOCKS request); larger buffers (e.g., 262144 for 10GbE) move more per read, but each connection holds `2 * buffer_size`, so buffers take up to `2 * buffer_size * max_connections` bytes [env: RS_BUFFER_SIZE] [default: 2048]
+    #[arg(long)]
     buffer_size: Option<usize>,
-    read_timeout: Option<u64>,
-    accept_cidr: Option<String>
+    /// How long, in milliseconds, a data connection may sit idle [env: RS_IDLE_TIMEOUT] [default: 60000]
+    #[arg(long, alias = "read-timeout")]
+    #[serde(alias = "read_timeout")]
+    idle_timeout: Option<u64>,
+    /// How long, in milliseconds, to wait for each handshake or request read [env: RS_HANDSHAKE_TIMEOUT] [default: 10000]
+    #[arg(long)]
+    handshake_timeout: Option<u64>,
+    /// The CIDR that clients must match, or a comma-separated list of CIDRs that clients must match one of [env: RS_ACCEPT_CIDR] [default: 0.0.0.0/0]
+    #[arg(long)]
+    accept_cidr: Option<String>,
+    /// The maximum number of concurrent connections, or 0 for no limit [env: RS_MAX_CONNECTIONS] [default: 0]
+    #[arg(long)]
+    max_connections: Option<usize>,
+    /// The maximum number of pooled buffers, or 0 for no limit [env: RS_MAX_BUFFERS] [default: 0]
+    #[arg(long)]
+    max_buffers: Option<usize>,
+    /// Whether to set TCP_NODELAY on client and endpoint sockets [env: RS_NO_DELAY] [default: true]
+    #[arg(long)]
+    no_delay: Option<bool>,
+    /// A comma-separated list of CIDRs to refuse, even if they match the accept CIDR [env: RS_DENY_CIDRS]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    deny_cidrs: Option<Vec<String>>,
+    /// The log format, `text` or `json` [env: RS_LOG_FORMAT] [default: text]
+    #[arg(long)]
+    log_format: Option<String>,
+    /// How long, in milliseconds, to wait for connections to finish on shutdown [env: RS_SHUTDOWN_GRACE] [default: 30000]
+    #[arg(long)]
+    shutdown_grace: Option<u64>,
+    /// How long, in milliseconds, to wait for each outbound connection attempt [env: RS_CONNECT_TIMEOUT] [default: 10000]
+    #[arg(long)]
+    connect_timeout: Option<u64>,
+    /// The maximum throughput, in bytes per second, for each direction of a connection [env: RS_RATE_LIMIT_BYTES_PER_SEC] [default: unlimited]
+    #[arg(long)]
+    rate_limit_bytes_per_sec: Option<u64>,
+    /// An upstream SOCKS5 proxy (`host:port`) to forward CONNECTs through (domains are resolved locally first, unless `resolve_domains` is false) [env: RS_UPSTREAM_PROXY]
+    #[arg(long)]
+    upstream_proxy: Option<String>,
+    /// The username for the upstream proxy [env: RS_UPSTREAM_USERNAME]
+    #[arg(long)]
+    upstream_username: Option<String>,
+    /// The password for the upstream proxy [env: RS_UPSTREAM_PASSWORD]
+    #[arg(long)]
+    upstream_password: Option<String>,
+    /// A comma-separated list of the only destination ports that may be reached (empty means any) [env: RS_ALLOWED_PORTS]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    allowed_ports: Option<Vec<u16>>,
+    /// A comma-separated list of destination ports that may not be reached [env: RS_DENIED_PORTS]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    denied_ports: Option<Vec<u16>>,
+    /// A comma-separated list of the only destinations that may be reached, like `example.com` or `*.example.com` (empty means any) [env: RS_ALLOWED_DOMAINS]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    allowed_domains: Option<Vec<String>>,
+    /// A comma-separated list of destinations that may not be reached, which takes precedence over the allowed domains [env: RS_DENIED_DOMAINS]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    denied_domains: Option<Vec<String>>,
+    /// The port on which to serve Prometheus metrics at `/metrics` (unset means no metrics server) [env: RS_METRICS_PORT]
+    #[arg(long)]
+    metrics_port: Option<u16>,
+    /// The data pump mode, `copy` (times out when the whole connection is idle) or `custom` (times out when either direction goes idle) [env: RS_PUMP_MODE] [default: copy]
+    #[arg(long)]
+    pump_mode: Option<String>,
+    /// Whether to set `SO_REUSEPORT` on the listeners, so that several instances can share a port (Unix only) [env: RS_REUSE_PORT] [default: false]
+    #[arg(long)]
+    reuse_port: Option<bool>,
+    /// How long, in milliseconds, to wait for a connection attempt before racing the next address (zero starts every attempt at once) [env: RS_HAPPY_EYEBALLS_DELAY] [default: 250]
+    #[arg(long)]
+    happy_eyeballs_delay: Option<u64>,
+    /// The number of characters in each connection id [env: RS_ID_LENGTH] [default: 8]
+    #[arg(long)]
+    id_length: Option<usize>,
+    /// The path of a Unix socket to listen on, in addition to the ports (Unix only) [env: RS_LISTEN_UNIX]
+    #[arg(long)]
+    listen_unix: Option<String>,
+    /// The path of a file to append one line to per completed connection [env: RS_ACCESS_LOG]
+    #[arg(long)]
+    access_log: Option<String>,
+    /// The longest domain name accepted in a request (longer names are rejected with a general failure reply) [env: RS_MAX_DOMAIN_LENGTH] [default: 255]
+    #[arg(long)]
+    max_domain_length: Option<usize>,
+    /// The number of resolved domain names to cache (zero disables the cache) [env: RS_DNS_CACHE_SIZE] [default: 0]
+    #[arg(long)]
+    dns_cache_size: Option<usize>,
+    /// How long, in milliseconds, a cached domain name stays valid [env: RS_DNS_CACHE_TTL] [default: 60000]
+    #[arg(long)]
+    dns_cache_ttl: Option<u64>,
+    /// A DNS server (`ip` or `ip:port`) to resolve domain names with, instead of the system resolver [env: RS_DNS_SERVER]
+    #[arg(long)]
+    dns_server: Option<String>,
+    /// Whether to resolve domain names locally; when false, domains are passed to the upstream proxy unresolved (direct connections always resolve locally) [env: RS_RESOLVE_DOMAINS] [default: true]
+    #[arg(long)]
+    resolve_domains: Option<bool>,
+    /// The number of failed handshakes from one IP (within the ban window) that bans it (zero disables banning) [env: RS_BAN_THRESHOLD] [default: 0]
+    #[arg(long)]
+    ban_threshold: Option<u32>,
+    /// How long, in milliseconds, failed handshakes count towards the ban threshold [env: RS_BAN_WINDOW] [default: 60000]
+    #[arg(long)]
+    ban_window: Option<u64>,
+    /// How long, in milliseconds, a banned IP has its connections dropped [env: RS_BAN_DURATION] [default: 600000]
+    #[arg(long)]
+    ban_duration: Option<u64>,
+    /// The path of a PEM certificate chain; with `tls_key`, clients must connect over TLS [env: RS_TLS_CERT]
+    #[arg(long)]
+    tls_cert: Option<String>,
+    /// The path of the PEM private key for `tls_cert` [env: RS_TLS_KEY]
+    #[arg(long)]
+    tls_key: Option<String>,
+    /// How long, in seconds, a connection may sit idle before TCP keepalive probes are sent on the client and endpoint sockets [env: RS_TCP_KEEPALIVE] [default: off]
+    #[arg(long)]
+    tcp_keepalive: Option<u64>,
+    /// The port to serve a health check on (`200 OK` with the active connections and uptime, on any path) [env: RS_HEALTH_PORT]
+    #[arg(long)]
+    health_port: Option<u16>,
+    /// What to do with a new connection when `max_connections` is reached, `queue` (wait for a slot) or `reject` (close it) [env: RS_LIMIT_BEHAVIOR] [default: queue]
+    #[arg(long)]
+    limit_behavior: Option<String>,
+    /// Whether to also serve HTTP CONNECT requests on the SOCKS ports (and Unix socket) [env: RS_ENABLE_HTTP_CONNECT] [default: false]
+    #[arg(long)]
+    enable_http_connect: Option<bool>,
+    /// The local port range that outbound sockets bind to, as `start-end` (once every port in it is in use, connections fail with a general failure) [env: RS_SOURCE_PORT_RANGE] [default: any ephemeral port]
+    #[arg(long)]
+    source_port_range: Option<String>,
+    /// A destination hostname that, instead of being connected to, answers with the client address the proxy observed (and its methods) [env: RS_DIAGNOSTIC_HOSTNAME] [default: none, which disables the diagnostic]
+    #[arg(long)]
+    diagnostic_hostname: Option<String>,
+    /// How often to log the buffer pool utilization (and its high-water mark), in milliseconds [env: RS_POOL_STATS_INTERVAL] [default: none, which disables the logging]
+    #[arg(long)]
+    pool_stats_interval: Option<u64>,
+    /// How many times to retry a connection to the endpoint that fails transiently (refused or timed out) [env: RS_CONNECT_RETRIES] [default: 0]
+    #[arg(long)]
+    connect_retries: Option<u32>,
+    /// How long, in milliseconds, to wait before the first connect retry (doubling for each one after) [env: RS_CONNECT_RETRY_DELAY] [default: 100]
+    #[arg(long)]
+    connect_retry_delay: Option<u64>,
+    /// The username that clients may authenticate with (requires `auth_password`) [env: RS_AUTH_USERNAME]
+    #[arg(long)]
+    auth_username: Option<String>,
+    /// The password that clients may authenticate with (requires `auth_username`) [env: RS_AUTH_PASSWORD]
+    #[arg(long)]
+    auth_password: Option<String>,
+    /// A file of `username:password` lines (where the password may be a bcrypt hash), each optionally followed by the CIDRs and hosts that the user is limited to, which is re-read on SIGHUP [env: RS_CREDENTIALS_FILE]
+    #[arg(long)]
+    credentials_file: Option<String>,
+    // The users that clients may authenticate as, given as `[[users]]` tables in the file (there is no CLI or env form).
+    #[arg(skip)]
+    users: Option<Vec<UserConfig>>,
+    /// Whether to refuse clients that do not authenticate with a username and password (rather than letting them fall back to NO AUTH) [env: RS_REQUIRE_AUTH] [default: false]
+    #[arg(long)]
+    require_auth: Option<bool>,
+    /// Whether to log a hexdump of the handshake and request bytes at trace level (which may include sensitive data) [env: RS_TRACE_BYTES] [default: false]
+    #[arg(long)]
+    trace_bytes: Option<bool>,
+    /// The log level, `off`, `error`, `warn`, `info`, `debug`, or `trace` (overridden by `RUST_LOG`, when it is set to one of these) [env: RS_LOG_LEVEL] [default: info]
+    #[arg(long)]
+    log_level: Option<String>,
+    /// Listen on IPv6 (`::`, or the IPv6 address of the listen interface) rather than IPv4 [env: RS_LISTEN_IPV6] [default: false]
+    #[arg(long)]
+    listen_ipv6: Option<bool>,
+    /// Whether to accept both IPv4 and IPv6 clients on one listener (which binds `::` with `IPV6_V6ONLY` disabled) [env: RS_DUAL_STACK] [default: false]
+    #[arg(long)]
+    dual_stack: Option<bool>,
+    /// The write timeout (in ms) of the custom pump, after which a peer that stops reading is disconnected [env: RS_WRITE_TIMEOUT] [default: 60000]
+    #[arg(long)]
+    write_timeout: Option<u64>,
+    /// A comma-separated list of the auth methods to select, most preferred first, from `user_pass` and `no_auth` [env: RS_AUTH_METHODS] [default: user_pass,no_auth]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    auth_methods: Option<Vec<String>>,
+    /// The device (e.g., `eth1`) to bind endpoint sockets to with `SO_BINDTODEVICE`, so that connections egress it whatever the routing table says (Linux only) [env: RS_ENDPOINT_DEVICE]
+    #[arg(long)]
+    endpoint_device: Option<String>,
+    /// The percentage of each connection's buffer that carries uploads (client to endpoint), with the rest carrying downloads (endpoint to client), from 1 to 99 [env: RS_BUFFER_UP_PERCENT] [default: 50]
+    #[arg(long)]
+    buffer_up_percent: Option<u8>,
+    /// The PROXY protocol version, `v1` (text) or `v2` (binary), of a header sent to each endpoint before any data, so that it sees the original client address [env: RS_SEND_PROXY_PROTOCOL] [default: off]
+    #[arg(long)]
+    send_proxy_protocol: Option<String>,
+    /// Whether each client connection starts with a PROXY protocol (v1 or v2) header, like from a load balancer, whose client address is used for the CIDR checks, bans, and logs (rather than the load balancer's), when it comes from one of the trusted proxies [env: RS_ACCEPT_PROXY_PROTOCOL] [default: false]
+    #[arg(long)]
+    accept_proxy_protocol: Option<bool>,
+    /// Whether to zero each buffer when its connection returns it to the pool, so that no data outlives its connection (at some throughput cost) [env: RS_ZERO_BUFFERS_ON_RETURN] [default: false]
+    #[arg(long)]
+    zero_buffers_on_return: Option<bool>,
+    /// Log the data path and summary of 1 in this many connections at info level (and the rest at debug level), though failed connections are always logged at info level [env: RS_LOG_SAMPLING] [default: 1]
+    #[arg(long)]
+    log_sampling: Option<u32>,
+    /// A comma-separated list of CIDRs of the proxies whose PROXY protocol headers are trusted (clients outside of it are treated as direct clients) [env: RS_TRUSTED_PROXIES]
+    #[arg(long, value_delimiter = ',')]
+    #[serde(default, deserialize_with = "one_or_many")]
+    trusted_proxies: Option<Vec<String>>
+}
+
+// A user in the file, as a table (e.g., `[[users]]` with `username`, `password`, and optionally `allowed`).
+#[derive(Clone, Deserialize)]
+struct UserConfig {
+    username: String,
+    password: String,
+    // The destinations the user is limited to (as in the credentials file).
+    #[serde(default, deserialize_with = "one_or_many")]
+    allowed: Option<Vec<String>>
+}
+
+#[derive(Clone, Parser)]
+#[command(name = "rusty_socks", version, about = "A super basic SOCKS5 proxy.")]
+pub struct Args {
+    /// The path to a TOML config file
+    #[arg(long = "config", value_name = "FILE")]
+    config: Option<String>,
+    /// The path to a TOML config file (the same as `--config`)
+    #[arg(value_name = "FILE", conflicts_with = "config")]
+    config_positional: Option<String>,
+    /// Load and validate the config, print it, and exit (without starting the server)
+    #[arg(long)]
+    check: bool,
+    #[command(flatten)]
+    overrides: OptionalConfig
+}
+
+impl Args {
+    pub fn config_file(&self) -> Option<&str> {
+        self.config.as_deref().or(self.config_positional.as_deref())
+    }
+
+    pub fn is_check(&self) -> bool {
+        self.check
+    }
+}
+
+impl OptionalConfig {
+    // Reads each option from its environment variable, which is `RS_` and the upper-cased name (e.g., `RS_BUFFER_SIZE`).
+    // Lists are comma-separated.
+    fn from_env() -> Res<OptionalConfig> {
+        OptionalConfig::from_lookup(|name| std::env::var(name).ok())
+    }
+
+    // Reads each option's variable through `lookup` (so that tests need not change the process environment).
+    fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Res<OptionalConfig> {
+        let mut c = OptionalConfig::default();
+
+        macro_rules! from_env {
+            ($read:ident: $($field:ident),*) => {
+                $({
+                    let name = format!("RS_{}", stringify!($field).to_uppercase());
+                    c.$field = $read(&name, lookup(&name))?;
+                })*
+            };
+        }
+
+        from_env!(env_value: listen_interface, endpoint_interface, buffer_size, idle_timeout, handshake_timeout, accept_cidr, max_connections, max_buffers, no_delay, log_format, shutdown_grace, connect_timeout, rate_limit_bytes_per_sec, upstream_proxy, upstream_username, upstream_password, metrics_port, pump_mode, reuse_port, happy_eyeballs_delay, id_length, listen_unix, access_log, max_domain_length, dns_cache_size, dns_cache_ttl, dns_server, resolve_domains, ban_threshold, ban_window, ban_duration, tls_cert, tls_key, tcp_keepalive, health_port, limit_behavior, enable_http_connect, source_port_range, diagnostic_hostname, pool_stats_interval, connect_retries, connect_retry_delay, auth_username, auth_password, credentials_file, require_auth, trace_bytes, log_level, listen_ipv6, dual_stack, write_timeout, endpoint_device, buffer_up_percent, send_proxy_protocol, accept_proxy_protocol, zero_buffers_on_return, log_sampling);
+        from_env!(env_list: endpoint_ip, port, deny_cidrs, allowed_ports, denied_ports, allowed_domains, denied_domains, auth_methods, trusted_proxies);
+
+        // `RS_READ_TIMEOUT` is the old name of `RS_IDLE_TIMEOUT`.
+        if c.idle_timeout.is_none() {
+            c.idle_timeout = env_value("RS_READ_TIMEOUT", lookup("RS_READ_TIMEOUT"))?;
+        }
+
+        Ok(c)
+    }
+
+    // Fills any unset values in `self` from `other`.
+    fn or(self, other: OptionalConfig) -> OptionalConfig {
+        OptionalConfig {
+            listen_interface: self.listen_interface.or(other.listen_interface),
+            endpoint_interface: self.endpoint_interface.or(other.endpoint_interface),
+            endpoint_ip: self.endpoint_ip.or(other.endpoint_ip),
+            port: self.port.or(other.port),
+            buffer_size: self.buffer_size.or(other.buffer_size),
+            idle_timeout: self.idle_timeout.or(other.idle_timeout),
+            handshake_timeout: self.handshake_timeout.or(other.handshake_timeout),
+            accept_cidr: self.accept_cidr.or(other.accept_cidr),
+            max_connections: self.max_connections.or(other.max_connections),
+            max_buffers: self.max_buffers.or(other.max_buffers),
+            no_delay: self.no_delay.or(other.no_delay),
+            deny_cidrs: self.deny_cidrs.or(other.deny_cidrs),
+            log_format: self.log_format.or(other.log_format),
+            shutdown_grace: self.shutdown_grace.or(other.shutdown_grace),
+            connect_timeout: self.connect_timeout.or(other.connect_timeout),
+            rate_limit_bytes_per_sec: self.rate_limit_bytes_per_sec.or(other.rate_limit_bytes_per_sec),
+            upstream_proxy: self.upstream_proxy.or(other.upstream_proxy),
+            upstream_username: self.upstream_username.or(other.upstream_username),
+            upstream_password: self.upstream_password.or(other.upstream_password),
+            allowed_ports: self.allowed_ports.or(other.allowed_ports),
+            denied_ports: self.denied_ports.or(other.denied_ports),
+            allowed_domains: self.allowed_domains.or(other.allowed_domains),
+            denied_domains: self.denied_domains.or(other.denied_domains),
+            metrics_port: self.metrics_port.or(other.metrics_port),
+            pump_mode: self.pump_mode.or(other.pump_mode),
+            reuse_port: self.reuse_port.or(other.reuse_port),
+            happy_eyeballs_delay: self.happy_eyeballs_delay.or(other.happy_eyeballs_delay),
+            id_length: self.id_length.or(other.id_length),
+            listen_unix: self.listen_unix.or(other.listen_unix),
+            access_log: self.access_log.or(other.access_log),
+            max_domain_length: self.max_domain_length.or(other.max_domain_length),
+            dns_cache_size: self.dns_cache_size.or(other.dns_cache_size),
+            dns_cache_ttl: self.dns_cache_ttl.or(other.dns_cache_ttl),
+            dns_server: self.dns_server.or(other.dns_server),
+            resolve_domains: self.resolve_domains.or(other.resolve_domains),
+            ban_threshold: self.ban_threshold.or(other.ban_threshold),
+            ban_window: self.ban_window.or(other.ban_window),
+            ban_duration: self.ban_duration.or(other.ban_duration),
+            tls_cert: self.tls_cert.or(other.tls_cert),
+            tls_key: self.tls_key.or(other.tls_key),
+            tcp_keepalive: self.tcp_keepalive.or(other.tcp_keepalive),
+            health_port: self.health_port.or(other.health_port),
+            limit_behavior: self.limit_behavior.or(other.limit_behavior),
+            enable_http_connect: self.enable_http_connect.or(other.enable_http_connect),
+            source_port_range: self.source_port_range.or(other.source_port_range),
+            diagnostic_hostname: self.diagnostic_hostname.or(other.diagnostic_hostname),
+            pool_stats_interval: self.pool_stats_interval.or(other.pool_stats_interval),
+            connect_retries: self.connect_retries.or(other.connect_retries),
+            connect_retry_delay: self.connect_retry_delay.or(other.connect_retry_delay),
+            auth_username: self.auth_username.or(other.auth_username),
+            auth_password: self.auth_password.or(other.auth_password),
+            credentials_file: self.credentials_file.or(other.credentials_file),
+            users: self.users.or(other.users),
+            require_auth: self.require_auth.or(other.require_auth),
+            trace_bytes: self.trace_bytes.or(other.trace_bytes),
+            log_level: self.log_level.or(other.log_level),
+            listen_ipv6: self.listen_ipv6.or(other.listen_ipv6),
+            dual_stack: self.dual_stack.or(other.dual_stack),
+            write_timeout: self.write_timeout.or(other.write_timeout),
+            auth_methods: self.auth_methods.or(other.auth_methods),
+            endpoint_device: self.endpoint_device.or(other.endpoint_device),
+            buffer_up_percent: self.buffer_up_percent.or(other.buffer_up_percent),
+            send_proxy_protocol: self.send_proxy_protocol.or(other.send_proxy_protocol),
+            accept_proxy_protocol: self.accept_proxy_protocol.or(other.accept_proxy_protocol),
+            zero_buffers_on_return: self.zero_buffers_on_return.or(other.zero_buffers_on_return),
+            log_sampling: self.log_sampling.or(other.log_sampling),
+            trusted_proxies: self.trusted_proxies.or(other.trusted_proxies)
+        }
+    }
+}
+
+// Applies `$apply` to the settings that only apply at startup.
+macro_rules! startup_settings {
+    ($apply:ident) => {
+        $apply!(
+            listen_ip, ports, listen_unix, reuse_port, buffer_size, max_buffers, max_connections, log_format, metrics_port, health_port,
+            access_log, dns_cache_size, dns_cache_ttl, dns_server, ban_threshold, ban_window, ban_duration, tls_cert, tls_key,
+            pool_stats_interval, zero_buffers_on_return
+        )
+    };
 }
 
 pub struct Config {
     pub listen_ip: String,
-    pub endpoint_ip: String,
-    pub port: u16,
+    pub endpoint_ips: Vec<IpAddr>,
+    pub ports: Vec<u16>,
     pub buffer_size: usize,
-    pub read_timeout: u64,
-    pub accept_cidr: String
+    pub idle_timeout: u64,
+    pub handshake_timeout: u64,
+    pub accept_cidr: String,
+    pub max_connections: usize,
+    pub max_buffers: usize,
+    pub no_delay: bool,
+    pub deny_cidrs: Vec<String>,
+    pub log_format: LogFormat,
+    pub shutdown_grace: u64,
+    pub connect_timeout: u64,
+    pub rate_limit_bytes_per_sec: Option<u64>,
+    pub upstream_proxy: Option<UpstreamProxy>,
+    pub allowed_ports: Vec<u16>,
+    pub denied_ports: Vec<u16>,
+    pub allowed_domains: Vec<String>,
+    pub denied_domains: Vec<String>,
+    pub metrics_port: Option<u16>,
+    pub pump_mode: PumpMode,
+    pub reuse_port: bool,
+    pub happy_eyeballs_delay: u64,
+    pub id_length: usize,
+    pub listen_unix: Option<String>,
+    pub access_log: Option<String>,
+    pub max_domain_length: usize,
+    pub dns_cache_size: usize,
+    pub dns_cache_ttl: u64,
+    pub dns_server: Option<SocketAddr>,
+    pub resolve_domains: bool,
+    pub ban_threshold: u32,
+    pub ban_window: u64,
+    pub ban_duration: u64,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tcp_keepalive: Option<u64>,
+    pub health_port: Option<u16>,
+    pub limit_behavior: LimitBehavior,
+    pub enable_http_connect: bool,
+    pub source_port_range: Option<PortRange>,
+    pub diagnostic_hostname: Option<String>,
+    pub pool_stats_interval: Option<u64>,
+    pub connect_retries: u32,
+    pub connect_retry_delay: u64,
+    pub credentials: Option<Credentials>,
+    pub require_auth: bool,
+    pub trace_bytes: bool,
+    pub log_level: LevelFilter,
+    pub listen_ipv6: bool,
+    pub dual_stack: bool,
+    pub write_timeout: u64,
+    pub auth_methods: Vec<Method>,
+    pub endpoint_device: Option<String>,
+    pub buffer_up_percent: u8,
+    pub send_proxy_protocol: Option<Version>,
+    pub accept_proxy_protocol: bool,
+    pub zero_buffers_on_return: bool,
+    pub log_sampling: u32,
+    pub trusted_proxies: Vec<String>
 }
 
-pub async fn from_file_and_env(file: Option<&str>) -> Res<Config> {
-    let config: Option<OptionalConfig> = if let Some(f) = file {
-        let config_file_data = tokio::fs::read(f).await?;
-        let config_text = std::str::from_utf8(&config_file_data)?;
+pub async fn from_args_file_and_env(args: Args) -> Res<Config> {
+    let file_config = match args.config_file() {
+        Some(f) => {
+            let config_file_data = tokio::fs::read(f).await?;
+            let config_text = std::str::from_utf8(&config_file_data)?;
 
-        Some(from_str::<OptionalConfig>(config_text)?)
-    } else {
-        None
+            from_str::<OptionalConfig>(config_text)?
+        },
+        None => OptionalConfig::default()
     };
 
-    let mut listen_interface: Option<String> = None;
-    let mut endpoint_interface: Option<String> = None;
-    let mut port = 1080u16;
-    let mut buffer_size = 2048usize;
-    let mut read_timeout = 60_000u64;
-    let mut accept_cidr = "0.0.0.0/0".to_owned();
+    // Compute the config values: CLI > env > file > default.
+    let c = args.overrides.or(OptionalConfig::from_env()?).or(file_config);
 
-    // Compute the config values: file > env > default.
-    if let Some(c) = config {
-        listen_interface = c.listen_interface.or_else(|| std::env::var("RS_LISTEN_INTERFACE").ok());
-        endpoint_interface = c.endpoint_interface.or_else(|| std::env::var("RS_ENDPOINT_INTERFACE").ok());
-        port = c.port.unwrap_or_else(|| get_env_or("RS_PORT", port));
-        buffer_size = c.buffer_size.unwrap_or_else(|| get_env_or("RS_BUFFER_SIZE", buffer_size));
-        read_timeout = c.read_timeout.unwrap_or_else(|| get_env_or("RS_READ_TIMEOUT", read_timeout));
-        accept_cidr = c.accept_cidr.unwrap_or_else(|| get_env_or("RS_ACCEPT_CIDR", accept_cidr));
+    let defaults = Config::default();
+
+    let listen_interface = c.listen_interface;
+    let endpoint_interface = c.endpoint_interface;
+    let endpoint_ip = c.endpoint_ip.unwrap_or_default();
+    let ports = c.port.unwrap_or(defaults.ports);
+    let buffer_size = c.buffer_size.unwrap_or(defaults.buffer_size);
+    let idle_timeout = c.idle_timeout.unwrap_or(defaults.idle_timeout);
+    let handshake_timeout = c.handshake_timeout.unwrap_or(defaults.handshake_timeout);
+    let accept_cidr = c.accept_cidr.unwrap_or(defaults.accept_cidr);
+    let max_connections = c.max_connections.unwrap_or(defaults.max_connections);
+    let max_buffers = c.max_buffers.unwrap_or(defaults.max_buffers);
+    let no_delay = c.no_delay.unwrap_or(defaults.no_delay);
+    let deny_cidrs = c.deny_cidrs.unwrap_or(defaults.deny_cidrs);
+    let log_format = match c.log_format {
+        Some(f) => f.parse()?,
+        None => defaults.log_format
+    };
+    let shutdown_grace = c.shutdown_grace.unwrap_or(defaults.shutdown_grace);
+    let connect_timeout = c.connect_timeout.unwrap_or(defaults.connect_timeout);
+    let rate_limit_bytes_per_sec = c.rate_limit_bytes_per_sec;
+    let upstream_username = c.upstream_username;
+    let upstream_password = c.upstream_password;
+    let upstream_proxy = c.upstream_proxy.map(|u| UpstreamProxy::new(u, upstream_username, upstream_password));
+    let allowed_ports = c.allowed_ports.unwrap_or(defaults.allowed_ports);
+    let denied_ports = c.denied_ports.unwrap_or(defaults.denied_ports);
+    let allowed_domains = c.allowed_domains.unwrap_or(defaults.allowed_domains);
+    let denied_domains = c.denied_domains.unwrap_or(defaults.denied_domains);
+    let metrics_port = c.metrics_port;
+    let pump_mode = match c.pump_mode {
+        Some(p) => p.parse()?,
+        None => defaults.pump_mode
+    };
+    let reuse_port = c.reuse_port.unwrap_or(defaults.reuse_port);
+    let happy_eyeballs_delay = c.happy_eyeballs_delay.unwrap_or(defaults.happy_eyeballs_delay);
+    let id_length = c.id_length.unwrap_or(defaults.id_length);
+    let listen_unix = c.listen_unix;
+    let access_log = c.access_log;
+    let max_domain_length = c.max_domain_length.unwrap_or(defaults.max_domain_length);
+    let dns_cache_size = c.dns_cache_size.unwrap_or(defaults.dns_cache_size);
+    let dns_cache_ttl = c.dns_cache_ttl.unwrap_or(defaults.dns_cache_ttl);
+    let dns_server = match c.dns_server {
+        Some(s) => Some(Resolver::parse_server(&s)?),
+        None => None
+    };
+    let resolve_domains = c.resolve_domains.unwrap_or(defaults.resolve_domains);
+    let ban_threshold = c.ban_threshold.unwrap_or(defaults.ban_threshold);
+    let ban_window = c.ban_window.unwrap_or(defaults.ban_window);
+    let ban_duration = c.ban_duration.unwrap_or(defaults.ban_duration);
+    let tls_cert = c.tls_cert;
+    let tls_key = c.tls_key;
+    let tcp_keepalive = c.tcp_keepalive;
+    let health_port = c.health_port;
+    let limit_behavior = match c.limit_behavior {
+        Some(l) => l.parse()?,
+        None => defaults.limit_behavior
+    };
+    let enable_http_connect = c.enable_http_connect.unwrap_or(defaults.enable_http_connect);
+    let source_port_range = match c.source_port_range {
+        Some(r) => Some(r.parse()?),
+        None => None
+    };
+    let diagnostic_hostname = c.diagnostic_hostname;
+    let pool_stats_interval = c.pool_stats_interval;
+    let connect_retries = c.connect_retries.unwrap_or(defaults.connect_retries);
+    let connect_retry_delay = c.connect_retry_delay.unwrap_or(defaults.connect_retry_delay);
+    let credentials_file = c.credentials_file;
+    let mut credentials = match &credentials_file {
+        Some(path) => Credentials::load(path).await?,
+        None => Credentials::default()
+    };
+    for user in c.users.unwrap_or_default() {
+        let allowed = user.allowed.map(|a| a.join(","));
+
+        if let Err(e) = credentials.add(user.username.clone(), user.password, allowed.as_deref()) {
+            return format!("The user `{}` in the config file is invalid.  {}", user.username, e).into_error();
+        }
     }
+    match (c.auth_username, c.auth_password) {
+        (Some(username), Some(password)) => credentials.add(username, password, None)?,
+        (None, None) => {},
+        _ => return "The auth username and password must be set together.".into_error()
+    };
+    let credentials = if credentials.is_empty() { None } else { Some(credentials) };
+    let require_auth = c.require_auth.unwrap_or(defaults.require_auth);
+    let trace_bytes = c.trace_bytes.unwrap_or(defaults.trace_bytes);
+    let log_level = match std::env::var("RUST_LOG").ok().and_then(|l| l.parse().ok()) {
+        Some(l) => l,
+        None => match c.log_level {
+            Some(l) => l.parse().or_else(|_| format!("Unknown log level `{}` (expected `off`, `error`, `warn`, `info`, `debug`, or `trace`).", l).into_error())?,
+            None => defaults.log_level
+        }
+    };
+    let listen_ipv6 = c.listen_ipv6.unwrap_or(defaults.listen_ipv6);
+    let dual_stack = c.dual_stack.unwrap_or(defaults.dual_stack);
+    let write_timeout = c.write_timeout.unwrap_or(defaults.write_timeout);
+    let auth_methods = match c.auth_methods {
+        Some(m) => m.iter().map(|m| m.parse()).collect::<Res<Vec<Method>>>()?,
+        None => defaults.auth_methods
+    };
+    let endpoint_device = c.endpoint_device;
+    let buffer_up_percent = c.buffer_up_percent.unwrap_or(defaults.buffer_up_percent);
+    let send_proxy_protocol = match c.send_proxy_protocol {
+        Some(v) => Some(v.parse()?),
+        None => None
+    };
+    let accept_proxy_protocol = c.accept_proxy_protocol.unwrap_or(defaults.accept_proxy_protocol);
+    let zero_buffers_on_return = c.zero_buffers_on_return.unwrap_or(defaults.zero_buffers_on_return);
+    let log_sampling = c.log_sampling.unwrap_or(defaults.log_sampling);
+    let trusted_proxies = c.trusted_proxies.unwrap_or(defaults.trusted_proxies);
 
-    let listen_ip = match &listen_interface {
-        Some(i) => Helpers::get_interface_ip(i)?.to_string(),
-        None => "0.0.0.0".to_owned()
+    // A dual-stack listener is an IPv6 listener that also accepts IPv4 clients.
+    let listen_ip = match (&listen_interface, listen_ipv6 || dual_stack) {
+        (Some(i), false) => Helpers::get_interface_ip(i, None)?.to_string(),
+        (Some(i), true) => Helpers::get_interface_ip(i, Some(Family::V6))?.to_string(),
+        (None, false) => "0.0.0.0".to_owned(),
+        (None, true) => "::".to_owned()
     };
 
-    let endpoint_ip = match &endpoint_interface {
-        Some(i) => Helpers::get_interface_ip(i)?.to_string(),
-        None => "0.0.0.0".to_owned()
+    // An endpoint interface contributes its best IP of each family (the right family is picked for each endpoint).
+    let endpoint_ips = match (&endpoint_interface, endpoint_ip.is_empty()) {
+        (Some(_), false) => return "Only one of the endpoint interface and the endpoint IPs may be specified.".into_error(),
+        (Some(i), true) => {
+            let ips = Helpers::get_interface_ips(i)?;
+
+            [Family::V4, Family::V6].iter().filter_map(|f| ips.iter().copied().find(|ip| f.matches(ip))).collect()
+        },
+        (None, _) => endpoint_ip
     };
 
-    Ok(Config { 
+    let config = Config { 
         listen_ip,
-        endpoint_ip,
-        port,
+        endpoint_ips,
+        ports,
         buffer_size,
-        read_timeout,
-        accept_cidr
-    })
+        idle_timeout,
+        handshake_timeout,
+        accept_cidr,
+        max_connections,
+        max_buffers,
+        no_delay,
+        deny_cidrs,
+        log_format,
+        shutdown_grace,
+        connect_timeout,
+        rate_limit_bytes_per_sec,
+        upstream_proxy,
+        allowed_ports,
+        denied_ports,
+        allowed_domains,
+        denied_domains,
+        metrics_port,
+        pump_mode,
+        reuse_port,
+        happy_eyeballs_delay,
+        id_length,
+        listen_unix,
+        access_log,
+        max_domain_length,
+        dns_cache_size,
+        dns_cache_ttl,
+        dns_server,
+        resolve_domains,
+        ban_threshold,
+        ban_window,
+        ban_duration,
+        tls_cert,
+        tls_key,
+        tcp_keepalive,
+        health_port,
+        limit_behavior,
+        enable_http_connect,
+        source_port_range,
+        diagnostic_hostname,
+        pool_stats_interval,
+        connect_retries,
+        connect_retry_delay,
+        credentials,
+        require_auth,
+        trace_bytes,
+        log_level,
+        listen_ipv6,
+        dual_stack,
+        write_timeout,
+        auth_methods,
+        endpoint_device,
+        buffer_up_percent,
+        send_proxy_protocol,
+        accept_proxy_protocol,
+        zero_buffers_on_return,
+        log_sampling,
+        trusted_proxies
+    };
+
+    config.validate()?;
+
+    Ok(config)
+}
+
+// The defaults, which apply to whatever the CLI, file, and env (or a `ConfigBuilder`) leave unset.
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen_ip: "0.0.0.0".to_owned(),
+            endpoint_ips: Vec::new(),
+            ports: vec![1080],
+            buffer_size: 2048,
+            idle_timeout: 60_000,
+            handshake_timeout: 10_000,
+            accept_cidr: "0.0.0.0/0".to_owned(),
+            max_connections: 0,
+            max_buffers: 0,
+            no_delay: true,
+            deny_cidrs: Vec::new(),
+            log_format: LogFormat::Text,
+            shutdown_grace: 30_000,
+            connect_timeout: 10_000,
+            rate_limit_bytes_per_sec: None,
+            upstream_proxy: None,
+            allowed_ports: Vec::new(),
+            denied_ports: Vec::new(),
+            allowed_domains: Vec::new(),
+            denied_domains: Vec::new(),
+            metrics_port: None,
+            pump_mode: PumpMode::Copy,
+            reuse_port: false,
+            happy_eyeballs_delay: 250,
+            id_length: 8,
+            listen_unix: None,
+            access_log: None,
+            max_domain_length: MAX_DOMAIN_LENGTH,
+            dns_cache_size: 0,
+            dns_cache_ttl: 60_000,
+            dns_server: None,
+            resolve_domains: true,
+            ban_threshold: 0,
+            ban_window: 60_000,
+            ban_duration: 600_000,
+            tls_cert: None,
+            tls_key: None,
+            tcp_keepalive: None,
+            health_port: None,
+            limit_behavior: LimitBehavior::Queue,
+            enable_http_connect: false,
+            source_port_range: None,
+            diagnostic_hostname: None,
+            pool_stats_interval: None,
+            connect_retries: 0,
+            connect_retry_delay: 100,
+            credentials: None,
+            require_auth: false,
+            trace_bytes: false,
+            log_level: LevelFilter::Info,
+            listen_ipv6: false,
+            dual_stack: false,
+            write_timeout: 60_000,
+            auth_methods: DEFAULT_METHODS.to_vec(),
+            endpoint_device: None,
+            buffer_up_percent: 50,
+            send_proxy_protocol: None,
+            accept_proxy_protocol: false,
+            zero_buffers_on_return: false,
+            log_sampling: 1,
+            trusted_proxies: Vec::new()
+        }
+    }
+}
+
+// Builds a `Config` in code (e.g., when embedding the server), without reading the CLI, a file, or the env.
+#[derive(Default)]
+pub struct ConfigBuilder {
+    config: Config
+}
+
+// Generates a fluent setter for each field.
+macro_rules! setters {
+    ($($field:ident: $type:ty),*) => {
+        $(
+            pub fn $field(mut self, $field: $type) -> Self {
+                self.config.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+impl ConfigBuilder {
+    pub fn new() -> Self {
+        ConfigBuilder::default()
+    }
+
+    setters!(
+        listen_ip: String,
+        endpoint_ips: Vec<IpAddr>,
+        ports: Vec<u16>,
+        buffer_size: usize,
+        idle_timeout: u64,
+        handshake_timeout: u64,
+        accept_cidr: String,
+        max_connections: usize,
+        max_buffers: usize,
+        no_delay: bool,
+        deny_cidrs: Vec<String>,
+        log_format: LogFormat,
+        shutdown_grace: u64,
+        connect_timeout: u64,
+        rate_limit_bytes_per_sec: Option<u64>,
+        upstream_proxy: Option<UpstreamProxy>,
+        allowed_ports: Vec<u16>,
+        denied_ports: Vec<u16>,
+        allowed_domains: Vec<String>,
+        denied_domains: Vec<String>,
+        metrics_port: Option<u16>,
+        pump_mode: PumpMode,
+        reuse_port: bool,
+        happy_eyeballs_delay: u64,
+        id_length: usize,
+        listen_unix: Option<String>,
+        access_log: Option<String>,
+        max_domain_length: usize,
+        dns_cache_size: usize,
+        dns_cache_ttl: u64,
+        dns_server: Option<SocketAddr>,
+        resolve_domains: bool,
+        ban_threshold: u32,
+        ban_window: u64,
+        ban_duration: u64,
+        tls_cert: Option<String>,
+        tls_key: Option<String>,
+        tcp_keepalive: Option<u64>,
+        health_port: Option<u16>,
+        limit_behavior: LimitBehavior,
+        enable_http_connect: bool,
+        source_port_range: Option<PortRange>,
+        diagnostic_hostname: Option<String>,
+        pool_stats_interval: Option<u64>,
+        connect_retries: u32,
+        connect_retry_delay: u64,
+        credentials: Option<Credentials>,
+        require_auth: bool,
+        trace_bytes: bool,
+        log_level: LevelFilter,
+        listen_ipv6: bool,
+        dual_stack: bool,
+        write_timeout: u64,
+        auth_methods: Vec<Method>,
+        endpoint_device: Option<String>,
+        buffer_up_percent: u8,
+        send_proxy_protocol: Option<Version>,
+        accept_proxy_protocol: bool,
+        zero_buffers_on_return: bool,
+        log_sampling: u32,
+        trusted_proxies: Vec<String>
+    );
+
+    // Runs the same validation as a config loaded from the CLI, file, and env.
+    pub fn build(self) -> Res<Config> {
+        self.config.validate()?;
+
+        Ok(self.config)
+    }
+}
+
+impl Config {
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::new()
+    }
+
+    // Returns the settings that differ from `other`, but only apply at startup (so they need a restart to change).
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+
+        macro_rules! compare {
+            ($($field:ident),*) => {
+                $(if self.$field != other.$field { changed.push(stringify!($field)); })*
+            };
+        }
+
+        startup_settings!(compare);
+
+        changed
+    }
+
+    // Returns this (reloaded) config, but with the startup settings of `current`, which stay in effect until a restart.
+    pub fn with_startup_settings_of(self, current: &Config) -> Config {
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                Config { $($field: current.$field.clone(),)* ..self }
+            };
+        }
+
+        startup_settings!(keep)
+    }
+
+    // The bytes of each connection's buffer (which is leased at twice the buffer size) that go to uploads and downloads.
+    pub fn buffer_split(&self) -> (usize, usize) {
+        let total = 2 * self.buffer_size;
+        let up = total * self.buffer_up_percent as usize / 100;
+
+        (up, total - up)
+    }
+
+    // The most memory that the buffers can take (each connection leases `2 * buffer_size`), or none when neither the
+    // buffers nor the connections are limited.
+    pub fn max_buffer_memory(&self) -> Option<usize> {
+        let buffers = match (self.max_buffers, self.max_connections) {
+            (0, 0) => return None,
+            (0, n) | (n, 0) => n,
+            (b, c) => b.min(c)
+        };
+
+        Some(buffers.saturating_mul(2 * self.buffer_size))
+    }
+
+    // Checks the values up front, so that a bad config fails at startup rather than on the first connection.
+    pub fn validate(&self) -> Void {
+        if self.ports.is_empty() {
+            return "At least one (1) port must be specified.".into_error();
+        }
+
+        if self.buffer_size < MAX_REQUEST_SIZE {
+            return format!("The buffer size must be at least {} bytes (the largest SOCKS request), but was {}.", MAX_REQUEST_SIZE, self.buffer_size).into_error();
+        }
+
+        if self.log_sampling == 0 {
+            return "The log sampling must be at least 1 (which logs every connection at info level).".into_error();
+        }
+
+        if self.buffer_up_percent == 0 || self.buffer_up_percent > 99 {
+            return format!("The buffer up percent must be between 1 and 99, but was {}.", self.buffer_up_percent).into_error();
+        }
+
+        if let (0, _) | (_, 0) = self.buffer_split() {
+            return format!("The buffer split must leave each direction at least one byte, but was {} up / {} down.", self.buffer_split().0, self.buffer_split().1).into_error();
+        }
+
+        if self.max_domain_length == 0 || self.max_domain_length > MAX_DOMAIN_LENGTH {
+            return format!("The max domain length must be between 1 and {}, but was {}.", MAX_DOMAIN_LENGTH, self.max_domain_length).into_error();
+        }
+
+        if self.tls_cert.is_some() != self.tls_key.is_some() {
+            return "The TLS certificate and key must be set together.".into_error();
+        }
+
+        if self.require_auth && self.credentials.is_none() {
+            return "Requiring auth needs an auth username and password (or a credentials file with at least one user).".into_error();
+        }
+
+        let can_run = |m: &Method| match m {
+            Method::NoAuth => !self.require_auth,
+            Method::UserPass => self.credentials.is_some()
+        };
+
+        if !self.auth_methods.iter().any(can_run) {
+            return "None of the auth methods can be selected (`no_auth` cannot be while requiring auth, and `user_pass` needs credentials).".into_error();
+        }
+
+        if self.require_auth && self.enable_http_connect {
+            return "HTTP CONNECT clients cannot authenticate, so it cannot be enabled while requiring auth.".into_error();
+        }
+
+        if self.id_length == 0 {
+            return "The id length must be non-zero.".into_error();
+        }
+
+        if self.idle_timeout == 0 {
+            return "The idle timeout must be non-zero.".into_error();
+        }
+
+        if self.write_timeout == 0 {
+            return "The write timeout must be non-zero.".into_error();
+        }
+
+        if self.handshake_timeout == 0 {
+            return "The handshake timeout must be non-zero.".into_error();
+        }
+
+        if self.rate_limit_bytes_per_sec == Some(0) {
+            return "The rate limit must be non-zero (or unset for no limit).".into_error();
+        }
+
+        if self.tcp_keepalive == Some(0) {
+            return "The TCP keepalive must be non-zero (or unset for no keepalive).".into_error();
+        }
+
+        if self.pool_stats_interval == Some(0) {
+            return "The pool stats interval must be non-zero (or unset for no pool stats).".into_error();
+        }
+
+        if self.connect_timeout == 0 {
+            return "The connect timeout must be non-zero.".into_error();
+        }
+
+        if self.listen_unix.is_some() && cfg!(not(unix)) {
+            return "Listening on a Unix socket is only supported on Unix.".into_error();
+        }
+
+        if self.reuse_port && cfg!(not(unix)) {
+            return "Reusing ports is only supported on Unix.".into_error();
+        }
+
+        if self.endpoint_device.is_some() && cfg!(not(target_os = "linux")) {
+            return "Binding endpoint sockets to a device is only supported on Linux.".into_error();
+        }
+
+        if self.endpoint_device.as_deref() == Some("") {
+            return "The endpoint device must be non-empty (or unset for any device).".into_error();
+        }
+
+        if self.metrics_port == Some(0) {
+            return "The metrics port must be non-zero (or unset for no metrics server).".into_error();
+        }
+
+        if self.metrics_port.is_some_and(|p| self.ports.contains(&p)) {
+            return "The metrics port must differ from the proxy ports.".into_error();
+        }
+
+        if self.health_port == Some(0) {
+            return "The health port must be non-zero (or unset for no health check).".into_error();
+        }
+
+        if self.health_port.is_some_and(|p| self.ports.contains(&p) || self.metrics_port == Some(p)) {
+            return "The health port must differ from the proxy and metrics ports.".into_error();
+        }
+
+        if self.dual_stack && self.listen_ip != "::" {
+            return "The dual-stack listener binds `::`, so it cannot be combined with a listen interface.".into_error();
+        }
+
+        if let Err(e) = Helpers::parse_cidr_list(&self.accept_cidr) {
+            return format!("The accept CIDR `{}` is invalid: {}", self.accept_cidr, e).into_error();
+        }
+
+        for cidr in &self.deny_cidrs {
+            if let Err(e) = Helpers::parse_cidr(cidr) {
+                return format!("The deny CIDR `{}` is invalid: {}", cidr, e).into_error();
+            }
+        }
+
+        for cidr in &self.trusted_proxies {
+            if let Err(e) = Helpers::parse_cidr(cidr) {
+                return format!("The trusted proxy CIDR `{}` is invalid: {}", cidr, e).into_error();
+            }
+        }
+
+        // Otherwise, any client could name itself as whichever client it liked.
+        if self.accept_proxy_protocol && self.trusted_proxies.is_empty() {
+            return "Accepting PROXY protocol headers requires the trusted proxies that may send them.".into_error();
+        }
+
+        Ok(())
+    }
 }
 
-fn get_env_or<S: AsRef<OsStr>, T: FromStr>(s: S, d: T) -> T {
-    match std::env::var(s) {
-        Ok(s) => match s.parse() {
-            Ok(v) => v,
-            _ => d
+// Parses the `value` of the environment variable `name` (if it is set).
+fn env_value<T: FromStr>(name: &str, value: Option<String>) -> Res<Option<T>> {
+    match value {
+        Some(value) => match value.parse() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => format!("The environment variable `{}` has an invalid value `{}`.", name, value).into_error()
         },
-        _ => d
+        None => Ok(None)
+    }
+}
+
+fn env_list<T: FromStr>(name: &str, value: Option<String>) -> Res<Option<Vec<T>>> {
+    match value {
+        Some(value) => match value.split(',').map(str::trim).filter(|i| !i.is_empty()).map(str::parse).collect::<Result<Vec<T>, _>>() {
+            Ok(v) => Ok(Some(v)),
+            Err(_) => format!("The environment variable `{}` has an invalid list `{}`.", name, value).into_error()
+        },
+        None => Ok(None)
+    }
+}
+
+// Allows a list value in the file to be given as a single item.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum OneOrMany<T> {
+    One(T),
+    Many(Vec<T>)
+}
+
+fn one_or_many<'de, D, T>(deserializer: D) -> Result<Option<Vec<T>>, D::Error>
+    where D: Deserializer<'de>, T: Deserialize<'de>
+{
+    let value = Option::<OneOrMany<T>>::deserialize(deserializer)?;
+
+    Ok(value.map(|v| match v {
+        OneOrMany::One(t) => vec![t],
+        OneOrMany::Many(v) => v
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_value_rejects_invalid_values() {
+        assert_eq!(Some(12u64), env_value("RS_TEST", Some("12".to_owned())).unwrap());
+        assert!(env_value::<u64>("RS_TEST", Some("twelve".to_owned())).is_err());
+        assert_eq!(None, env_value::<u64>("RS_TEST", None).unwrap());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn env_list_splits_on_commas() {
+        assert_eq!(Some(vec![80u16, 443]), env_list("RS_TEST", Some("80, 443,".to_owned())).unwrap());
+        assert!(env_list::<u16>("RS_TEST", Some("80,https".to_owned())).is_err());
+    }
+
+    #[test]
+    fn from_lookup_reads_the_prefixed_names() {
+        let c = OptionalConfig::from_lookup(|name| match name {
+            "RS_PORT" => Some("80,443".to_owned()),
+            "RS_READ_TIMEOUT" => Some("5".to_owned()),
+            _ => None
+        }).unwrap();
+
+        assert_eq!(Some(vec![80, 443]), c.port);
+        assert_eq!(Some(5), c.idle_timeout);
+        assert!(OptionalConfig::from_lookup(|name| (name == "RS_BUFFER_SIZE").then(|| "big".to_owned())).is_err());
+    }
+
+    #[test]
+    fn buffer_split_applies_the_up_percent() {
+        let config = Config { buffer_size: 1000, ..Config::default() };
+        assert_eq!((1000, 1000), config.buffer_split());
+
+        let config = Config { buffer_size: 1000, buffer_up_percent: 20, ..Config::default() };
+        assert_eq!((400, 1600), config.buffer_split());
+        assert!(config.validate().is_ok());
+
+        assert!(Config { buffer_up_percent: 0, ..Config::default() }.validate().is_err());
+        assert!(Config { buffer_up_percent: 100, ..Config::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn restart_required_names_the_startup_settings() {
+        let current = Config::default();
+        let reloaded = Config { buffer_size: 4096, ports: vec![2080], idle_timeout: 1, ..Config::default() };
+        assert_eq!(vec!["ports", "buffer_size"], current.restart_required(&reloaded));
+
+        // The startup settings stay as they are, while the rest reload.
+        let reloaded = reloaded.with_startup_settings_of(&current);
+        assert!(current.restart_required(&reloaded).is_empty());
+        assert_eq!(1, reloaded.idle_timeout);
+    }
+
+    #[test]
+    fn accept_proxy_protocol_requires_trusted_proxies() {
+        assert!(Config { accept_proxy_protocol: true, ..Config::default() }.validate().is_err());
+        assert!(Config { accept_proxy_protocol: true, trusted_proxies: vec!["10.0.0.0/8".to_owned()], ..Config::default() }.validate().is_ok());
+        assert!(Config { trusted_proxies: vec!["10.0.0.0/33".to_owned()], ..Config::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn max_buffer_memory_uses_the_tighter_limit() {
+        let config = |max_buffers, max_connections| Config { buffer_size: 1000, max_buffers, max_connections, ..Config::default() };
+
+        assert_eq!(None, config(0, 0).max_buffer_memory());
+        assert_eq!(Some(20_000), config(0, 10).max_buffer_memory());
+        assert_eq!(Some(8_000), config(4, 10).max_buffer_memory());
+    }
+}
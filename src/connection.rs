@@ -1,206 +1,799 @@
-use tokio::{io::AsyncReadExt, task::JoinHandle};
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite}, task::JoinHandle};
 use tokio::net::{TcpStream};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::time::sleep;
 use tokio::io::AsyncWriteExt;
 
-use std::iter::IntoIterator;
-use std::str::FromStr;
-use std::net::{SocketAddr, IpAddr};
-use log::{error, info, debug, warn};
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use std::error::Error;
+use std::net::{SocketAddr, IpAddr, Ipv4Addr};
+use log::{error, info, debug, warn, trace, log, Level};
 use phf::{Map, phf_map};
+use futures::stream::{FuturesUnordered, StreamExt};
+use futures::future::BoxFuture;
 
 use crate::handshake::Handshake;
-use crate::helpers::{Helpers, Res, Void, IntoError};
+use crate::helpers::{Cidr, Helpers, Res, Void, IntoError, TimeoutError, PortRange};
+use crate::activity::Transfer;
 use crate::request::{Request, Destination};
-//use crate::custom_pump::CustomPump;
+use crate::custom_pump::{CustomPump, PumpMode};
 use crate::copy_pump::CopyPump;
 use crate::buffer_pool::Buffer;
+use crate::config::Config;
+use crate::upstream::UpstreamProxy;
+use crate::stream::{ClientStream, ProxiedStream};
+use crate::metrics::{METRICS, ActiveConnection};
+use crate::access_log::{AccessLog, Entry};
+use crate::resolver::Resolver;
+use crate::ban_list::BanList;
+use crate::tls::TlsAcceptor;
+use crate::http_connect;
+use crate::proxy_protocol;
+use crate::auth::{self, AuthRecord};
+
+// What is known about a connection when it ends (filled in as the connection progresses).
+#[derive(Default)]
+struct Summary {
+    client: String,
+    destination: Option<String>,
+    port: Option<u16>,
+    reply: Option<u8>,
+    auth: Option<AuthRecord>,
+    transfer: Transfer,
+    outcome: Outcome
+}
+
+// The outcome of negotiating with the client.
+struct Negotiated {
+    request: Request,
+    // The methods the client offered (HTTP clients offer none).
+    methods: Vec<u8>,
+    user: Option<String>
+}
+
+// The protocol that a client asked for its connection with.
+#[derive(Clone, Copy)]
+enum Protocol {
+    Socks5,
+    HttpConnect
+}
+
+impl Protocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Socks5 => "SOCKS5",
+            Protocol::HttpConnect => "HTTP CONNECT"
+        }
+    }
+}
+
+// How a connection ended.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Outcome {
+    #[default]
+    Normal,
+    Timeout,
+    Error
+}
+
+impl Outcome {
+    fn of(error: &(dyn Error + 'static)) -> Self {
+        if error.is::<TimeoutError>() { Outcome::Timeout } else { Outcome::Error }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Outcome::Normal => "normal",
+            Outcome::Timeout => "timeout",
+            Outcome::Error => "error"
+        }
+    }
+}
+
+// What a completed connection reports (to the `OnComplete` callback).
+#[derive(Clone, Debug)]
+pub struct ConnectionSummary {
+    pub id: String,
+    pub client: String,
+    // The destination and port are only known once the client has made a request.
+    pub destination: Option<String>,
+    pub port: Option<u16>,
+    // The reply code sent to the client (if one was sent).
+    pub reply_code: Option<u8>,
+    // How the client authenticated (only SOCKS clients that get through method selection have a record).
+    pub auth: Option<AuthRecord>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    pub duration: Duration,
+    pub outcome: Outcome,
+    // Whether the connection was picked (per the log sampling) to have its routine lines logged at info level.
+    pub sampled: bool
+}
+
+impl ConnectionSummary {
+    // Failures are logged at info level whether or not the connection was sampled.
+    pub fn log_level(&self) -> Level {
+        let failed = self.outcome != Outcome::Normal || self.reply_code.is_some_and(|r| r != 0x00);
+
+        if self.sampled || failed { Level::Info } else { Level::Debug }
+    }
+}
+
+// An async callback, invoked once per completed connection.
+pub type OnComplete = Arc<dyn Fn(ConnectionSummary) -> BoxFuture<'static, ()> + Send + Sync>;
+
+// The state that the server shares with every connection.
+pub struct Context {
+    pub access_log: Option<AccessLog>,
+    pub resolver: Resolver,
+    pub ban_list: BanList,
+    pub tls: Option<TlsAcceptor>,
+    pub on_complete: OnComplete,
+    // Swapped out when the config reloads.
+    cidrs: RwLock<Arc<Cidrs>>
+}
+
+impl Context {
+    pub fn new(access_log: Option<AccessLog>, resolver: Resolver, ban_list: BanList, tls: Option<TlsAcceptor>, on_complete: OnComplete, cidrs: Cidrs) -> Self {
+        Context { access_log, resolver, ban_list, tls, on_complete, cidrs: RwLock::new(Arc::new(cidrs)) }
+    }
+
+    pub fn cidrs(&self) -> Arc<Cidrs> {
+        self.cidrs.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn set_cidrs(&self, cidrs: Cidrs) {
+        *self.cidrs.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(cidrs);
+    }
+}
+
+// The config's CIDRs, parsed once (rather than for each connection).
+pub struct Cidrs {
+    accept: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    trusted_proxies: Vec<Cidr>,
+    // The accept CIDRs as configured (for the logs).
+    accept_cidr: String
+}
+
+impl Cidrs {
+    pub fn parse(config: &Config) -> Res<Cidrs> {
+        Ok(Cidrs {
+            accept: Helpers::parse_cidr_list(&config.accept_cidr)?,
+            deny: config.deny_cidrs.iter().map(|c| Helpers::parse_cidr(c)).collect::<Res<Vec<_>>>()?,
+            trusted_proxies: config.trusted_proxies.iter().map(|c| Helpers::parse_cidr(c)).collect::<Res<Vec<_>>>()?,
+            accept_cidr: config.accept_cidr.clone()
+        })
+    }
+
+    // Whether a peer may send a PROXY protocol header.
+    pub fn is_trusted_proxy(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|c| c.contains(ip))
+    }
+}
 
 pub struct Connection {
     id: String,
-    client_socket: TcpStream,
-    endpoint_interface: String,
+    client_socket: Box<dyn ClientStream>,
+    // The IP of a TCP client (or of the client in a PROXY protocol header), taken up front since a reset client has no peer name.
+    client_ip: Option<IpAddr>,
+    config: Arc<Config>,
     buffer: Buffer, 
-    read_timeout: u64
+    context: Arc<Context>,
+    // The user the client authenticated as (if it did).
+    user: Option<String>,
+    // Whether the data path and summary are logged at info level (rather than debug level).
+    sampled: bool,
+    _permit: OwnedSemaphorePermit,
+    _active: ActiveConnection
 }
 
 impl Connection {
-    pub fn from(client_socket: TcpStream, endpoint_interface: String, buffer: Buffer, read_timeout: u64) -> Self {
-        Connection { id: Helpers::get_id(), client_socket, endpoint_interface, buffer, read_timeout }
+    pub fn from(client_socket: Box<dyn ClientStream>, client_ip: Option<IpAddr>, config: Arc<Config>, buffer: Buffer, context: Arc<Context>, permit: OwnedSemaphorePermit) -> Self {
+        let sampled = Connection::is_sampled(config.log_sampling, CONNECTIONS_SEEN.fetch_add(1, Ordering::Relaxed));
+
+        Connection { id: Helpers::get_id(config.id_length), client_socket, client_ip, config, buffer, context, user: None, sampled, _permit: permit, _active: ActiveConnection::new() }
+    }
+
+    // Picks 1 in `every` connections (by the order they arrive).
+    fn is_sampled(every: u32, count: u64) -> bool {
+        count.is_multiple_of(u64::from(every.max(1)))
+    }
+
+    pub fn active_count() -> u64 {
+        METRICS.connections_active()
     }
 
     // `self` Connection is moved when the handle method is called, and ownership is given
     // fully to the thread, so `this` Connection will drop when the spawned thread ends.
-    pub fn handle(self) -> JoinHandle<()> {
-        debug!("[{}] Start.", self.id);
+    pub fn handle(self) -> JoinHandle<ConnectionSummary> {
+        debug!(id = self.id.as_str(), event = "start"; "Start.");
 
         // Move self into the spawned thread, as well.
         tokio::spawn(async move {
-            match self.handle_task().await {
+            let id = self.id.clone();
+            let start = Instant::now();
+            let context = self.context.clone();
+            let sampled = self.sampled;
+            let mut summary = Summary { client: self.client_socket.peer_name().unwrap_or_else(|_| "unknown".to_owned()), ..Summary::default() };
+
+            match self.handle_task(&mut summary).await {
                 Ok(_) => {},
                 Err(e) => {
-                    error!("{}", e);
+                    summary.outcome = Outcome::of(e.as_ref());
+                    error!(id = id.as_str(), event = "error"; "{}", e);
                 }
             }
+
+            let summary = ConnectionSummary {
+                id,
+                client: summary.client,
+                destination: summary.destination,
+                port: summary.port,
+                reply_code: summary.reply,
+                auth: summary.auth,
+                bytes_up: summary.transfer.bytes_up,
+                bytes_down: summary.transfer.bytes_down,
+                duration: start.elapsed(),
+                outcome: summary.outcome,
+                sampled
+            };
+
+            if let Some(access_log) = &context.access_log {
+                // The access log records the client IP (a TCP peer name also includes the port).
+                let client_ip = summary.client.parse::<SocketAddr>().map_or(summary.client.clone(), |a| a.ip().to_string());
+
+                access_log.write(&Entry {
+                    client: &client_ip,
+                    destination: summary.destination.as_deref(),
+                    port: summary.port,
+                    reply: summary.reply_code,
+                    bytes_up: summary.bytes_up,
+                    bytes_down: summary.bytes_down,
+                    duration_ms: summary.duration.as_millis() as u64
+                }).await;
+            }
+
+            (context.on_complete)(summary.clone()).await;
+
+            summary
         })
     }
 
-    async fn handle_task(mut self) -> Void {
+    // The default `OnComplete` callback, which logs the summary.
+    pub fn log_summary(summary: &ConnectionSummary) {
+        let destination = match (&summary.destination, summary.port) {
+            (Some(d), Some(p)) => Helpers::host_port(d, p),
+            _ => "none".to_owned()
+        };
+        let duration_ms = summary.duration.as_millis() as u64;
+        let outcome = summary.outcome.as_str();
+        let (auth_method, auth_result) = summary.auth.as_ref().map_or(("none", "none"), |a| (a.method_str(), a.result_str()));
+
+        log!(
+            summary.log_level(),
+            id = summary.id.as_str(),
+            event = "summary",
+            client = summary.client.as_str(),
+            destination = destination.as_str(),
+            duration_ms = duration_ms,
+            bytes_up = summary.bytes_up,
+            bytes_down = summary.bytes_down,
+            outcome = outcome,
+            auth_method = auth_method,
+            auth_result = auth_result;
+            "{} => {} ended ({}) after {} ms: {} bytes up, {} bytes down.  Auth: {} ({}).", summary.client, destination, outcome, duration_ms, summary.bytes_up, summary.bytes_down, auth_method, auth_result
+        );
+    }
+
+    async fn handle_task(mut self, summary: &mut Summary) -> Void {
+        // Behind a proxy, read the client from its header first (it comes before everything else, even TLS).  Only a trusted
+        // proxy's header is read (and the peer of a Unix socket, which is local, is trusted).
+        if self.config.accept_proxy_protocol && self.client_ip.is_none_or(|ip| self.context.cidrs().is_trusted_proxy(&ip)) {
+            let source = self.read_proxy_header(summary).await?;
+
+            if let Some(source) = source {
+                self.client_ip = Some(source.ip().to_canonical());
+            }
+            self.client_socket = Box::new(ProxiedStream::new(self.client_socket, source));
+        }
+
+        // Terminate TLS first, if it is configured (the rest of the connection runs over the TLS stream).
+        if let Some(acceptor) = &self.context.tls {
+            let accept = acceptor.accept(self.client_socket);
+
+            self.client_socket = match tokio::time::timeout(Duration::from_millis(self.config.handshake_timeout), accept).await {
+                Ok(Ok(stream)) => Box::new(stream),
+                Ok(Err(e)) => {
+                    Connection::handshake_failed(self.client_ip, &self.context.ban_list);
+                    return Err(e.into());
+                },
+                Err(_) => {
+                    Connection::handshake_failed(self.client_ip, &self.context.ban_list);
+                    return Err(TimeoutError::boxed(format!("Timed out after {} ms during TLS handshake.", self.config.handshake_timeout)));
+                }
+            };
+        }
+
         // Get a &mut slice from the leased buffer.
         let buffer = &mut self.buffer.get().await[..];
 
-        // Complete handshake.
+        Connection::set_no_delay(&self.id, &*self.client_socket, self.config.no_delay);
+        Connection::set_keepalive(&self.id, &*self.client_socket, self.config.tcp_keepalive);
 
-        let handshake = Connection::perform_handshake(&mut self.client_socket, buffer).await?;
-        let methods_string = handshake.methods.into_iter().map(|m| m.to_string()).collect::<Vec<String>>().join(",");
+        // Read the client's first message (a SOCKS handshake, or an HTTP CONNECT request, when enabled).
 
-        debug!("[{}]   Handshake:", self.id);
-        debug!("[{}]     Version: {}", self.id, handshake.version);
-        debug!("[{}]     Num Methods: {}", self.id, handshake.num_methods);
-        debug!("[{}]     Methods: {}", self.id, methods_string);
+        let read = match Connection::read_with_timeout(&mut self.client_socket, buffer, self.config.handshake_timeout, "handshake").await {
+            Ok(r) => r,
+            Err(e) => {
+                Connection::handshake_failed(self.client_ip, &self.context.ban_list);
+                return Err(e);
+            }
+        };
 
-        // Get request from client.
+        Connection::trace_bytes(self.config.trace_bytes.then_some(self.id.as_str()), "handshake", &buffer[..read]);
+
+        let protocol = if self.config.enable_http_connect && http_connect::is_connect(&buffer[..read]) { Protocol::HttpConnect } else { Protocol::Socks5 };
+
+        let Negotiated { request, methods, user } = match Connection::negotiate(&self.id, &mut self.client_socket, buffer, read, &self.config, protocol, &mut summary.auth).await {
+            Ok(n) => n,
+            Err(e) => {
+                if let Some(record) = summary.auth.as_ref().filter(|r| !r.succeeded) {
+                    Connection::auth_failed(&self.id, &*self.client_socket, record);
+                }
+
+                Connection::handshake_failed(self.client_ip, &self.context.ban_list);
+                return Err(e);
+            }
+        };
+
+        self.user = user;
 
-        let request = Connection::perform_request_negotiation(&mut self.client_socket, buffer).await?;
         let destination = match &request.destination {
             Destination::Ipv4Addr(ipv4) => ipv4.to_string(),
             Destination::Ipv6Addr(ipv6) => ipv6.to_string(),
             Destination::Domain(s) => s.to_owned()
         };
 
-        debug!("[{}]   Request:", self.id);
-        debug!("[{}]     Version: {}", self.id, request.version);
-        debug!("[{}]     Command: {}", self.id, COMMANDS[&request.command]);
-        debug!("[{}]     Address Type: {}", self.id, ADDRESS_TYPES[&request.address_type]);
-        debug!("[{}]     Destination: {}", self.id, destination);
-        debug!("[{}]     Port: {}", self.id, request.port);
+        debug!(id = self.id.as_str(), event = "request"; "  Request ({}):", protocol.as_str());
+        debug!(id = self.id.as_str(), event = "request"; "    Version: {}", request.version);
+        debug!(id = self.id.as_str(), event = "request"; "    Command: {}", COMMANDS.get(&request.command).unwrap_or(&"Unknown"));
+        debug!(id = self.id.as_str(), event = "request"; "    Address Type: {}", ADDRESS_TYPES.get(&request.address_type).unwrap_or(&"Unknown"));
+        debug!(id = self.id.as_str(), event = "request"; "    Destination: {}", destination);
+        debug!(id = self.id.as_str(), event = "request"; "    Port: {}", request.port);
+
+        summary.destination = Some(destination);
+        summary.port = Some(request.port);
+
+        // Answer diagnostic requests (without connecting anywhere).
+
+        if request.command == 0x01 && Connection::is_diagnostic(&self.config, &request.destination) {
+            let client = self.client_socket.peer_name()?;
+
+            summary.reply = Some(0x00);
+            Connection::send_response(&mut self.client_socket, buffer, protocol, 0x00, UNSPECIFIED_ADDR).await?;
+
+            self.client_socket.write_all(Connection::diagnostic_report(&client, protocol, &methods).as_bytes()).await?;
+            self.client_socket.flush().await?;
+
+            info!(id = self.id.as_str(), event = "diagnostic"; "Answered a diagnostic request from {}.", client);
+
+            return Ok(());
+        }
+
+        // Enforce the destination rules.
+
+        if !Connection::is_destination_allowed(&self.config, &request.destination) {
+            summary.reply = Some(0x02);
+            Connection::send_response(&mut self.client_socket, buffer, protocol, 0x02, UNSPECIFIED_ADDR).await?;
+
+            return format!("The destination `{}` is not allowed by the ruleset.", request.destination).into_error();
+        }
+
+        if !Connection::is_port_allowed(&self.config, request.port) {
+            summary.reply = Some(0x02);
+            Connection::send_response(&mut self.client_socket, buffer, protocol, 0x02, UNSPECIFIED_ADDR).await?;
+
+            return format!("The destination port {} is not allowed by the ruleset.", request.port).into_error();
+        }
+
+        if !Connection::is_user_allowed(&self.config, self.user.as_deref(), &request.destination) {
+            summary.reply = Some(0x02);
+            Connection::send_response(&mut self.client_socket, buffer, protocol, 0x02, UNSPECIFIED_ADDR).await?;
+
+            return format!("The destination `{}` is not allowed for the user `{}`.", request.destination, self.user.as_deref().unwrap_or_default()).into_error();
+        }
 
         // Perform requested action.
 
-        let endpoint_socket = match request.command {
-            0x01 /* CONNECT */ => Connection::establish_connect_request(&mut self.client_socket, &self.endpoint_interface, &request, buffer).await?,
-            0x02 /* BIND */ => return "BIND requests not supported.".into_error(),
-            0x03 /* UDP ASSOCIATE */ => return "UDP ASSOCIATE requests not supported.".into_error(),
-            _ => return "Unknown command type.".into_error()
+        let mut endpoint_socket = match request.command {
+            0x01 /* CONNECT */ => Connection::establish_connect_request(&mut self.client_socket, &self.config, &self.context.resolver, &request, buffer, protocol, &mut summary.reply).await?,
+            // BIND, UDP ASSOCIATE, and unknown commands are not supported, so tell the client so.
+            command => {
+                summary.reply = Some(0x07);
+                Connection::send_response(&mut self.client_socket, buffer, protocol, 0x07, UNSPECIFIED_ADDR).await?;
+
+                return format!("The command `{}` is not supported ({}).", COMMANDS.get(&command).unwrap_or(&"Unknown"), ERRORS[&0x07]).into_error();
+            }
         };
 
+        Connection::set_no_delay(&self.id, &endpoint_socket, self.config.no_delay);
+        Connection::set_keepalive(&self.id, &endpoint_socket, self.config.tcp_keepalive);
+
         // Print the data path.
 
-        let client_peer_addr = self.client_socket.peer_addr()?;
-        let client_local_addr = self.client_socket.local_addr()?;
+        let client_peer_addr = self.client_socket.peer_name()?;
+        let client_local_addr = self.client_socket.local_name()?;
         let endpoint_local_addr = endpoint_socket.local_addr()?;
         let endpoint_peer_addr = endpoint_socket.peer_addr()?;
 
-        info!("[{}] {} => {} => {} => {}", self.id, client_peer_addr, client_local_addr, endpoint_local_addr, endpoint_peer_addr);
+        log!(
+            if self.sampled { Level::Info } else { Level::Debug },
+            id = self.id.as_str(),
+            event = "data_path",
+            client:% = client_peer_addr,
+            proxy_in:% = client_local_addr,
+            proxy_out:% = endpoint_local_addr,
+            endpoint:% = endpoint_peer_addr;
+            "{} => {} => {} => {}", client_peer_addr, client_local_addr, endpoint_local_addr, endpoint_peer_addr
+        );
+
+        // Tell the endpoint who the client is, ahead of any of the client's data.
+        if let Some(version) = self.config.send_proxy_protocol {
+            let header = proxy_protocol::header(version, client_peer_addr.parse().ok(), client_local_addr.parse().ok());
+
+            endpoint_socket.write_all(&header).await?;
+        }
 
         // Run the pump (all errors in pumps are emitted as log messages and should not disrupt the execution flow).
 
-        //CustomPump::from(&self.id, self.client_socket, endpoint_socket, buffer, self.read_timeout).start().await;
-        match CopyPump::from(self.client_socket, endpoint_socket, self.read_timeout).start().await {
-            Ok(_) => {},
-            Err(e) => {
-                warn!("[{}] The pump ended with an error.  {}", self.id, e);
-            }
+        let (transfer, result) = match self.config.pump_mode {
+            PumpMode::Copy => CopyPump::from(self.client_socket, endpoint_socket, buffer, self.config.buffer_up_percent, self.config.idle_timeout, self.config.rate_limit_bytes_per_sec).start().await,
+            PumpMode::Custom => CustomPump::from(self.client_socket, endpoint_socket, buffer, self.config.buffer_up_percent, self.config.idle_timeout, self.config.write_timeout, self.config.rate_limit_bytes_per_sec).start().await
+        };
+
+        if let Err(e) = result {
+            summary.outcome = Outcome::of(e.as_ref());
+            warn!(id = self.id.as_str(), event = "pump_error", reason = summary.outcome.as_str(); "The pump ended with an error ({}).  {}", summary.outcome.as_str(), e);
         }
 
-        debug!("[{}] End.", self.id);
+        METRICS.transferred(transfer.bytes_up, transfer.bytes_down);
+        summary.transfer = transfer;
+
+        debug!(id = self.id.as_str(), event = "end"; "End.");
 
         Ok(())
     }
 
-    async fn perform_handshake(client_socket: &mut TcpStream, buffer: &mut [u8]) -> Res<Handshake> {
-        let read = client_socket.read(buffer).await?;
+    // Returns the client that the PROXY protocol header names, after checking it like the server checks the clients it accepts.
+    async fn read_proxy_header(&mut self, summary: &mut Summary) -> Res<Option<SocketAddr>> {
+        let source = match tokio::time::timeout(Duration::from_millis(self.config.handshake_timeout), proxy_protocol::read_header(&mut self.client_socket)).await {
+            Ok(source) => source?,
+            Err(_) => return Err(TimeoutError::boxed(format!("Timed out after {} ms reading the PROXY protocol header.", self.config.handshake_timeout)))
+        };
+
+        // A header without a client (like `PROXY UNKNOWN`, or a load balancer's health check) leaves the proxy as the peer,
+        // which is then checked like any other client.
+        let source = match source {
+            Some(s) => s,
+            None => {
+                if let Some(ip) = self.client_ip.filter(|ip| !Connection::is_admitted(ip, &self.context.ban_list, &self.context.cidrs())) {
+                    return format!("The proxy {} is not admitted.", ip).into_error();
+                }
+
+                return Ok(None);
+            }
+        };
+
+        debug!(id = self.id.as_str(), event = "proxy_protocol"; "The PROXY protocol header names the client {} (via {}).", source, summary.client);
+        summary.client = source.to_string();
+
+        if !Connection::is_admitted(&source.ip().to_canonical(), &self.context.ban_list, &self.context.cidrs()) {
+            return format!("The client {} is not admitted.", source).into_error();
+        }
+
+        Ok(Some(source))
+    }
+
+    // Whether a client IP may connect: banned IPs are dropped quietly (since this is what scanners look like), a deny CIDR
+    // takes precedence over the accept CIDRs, and a client must match one of the accept CIDRs (where a client of the other
+    // family matches none).
+    pub fn is_admitted(ip: &IpAddr, ban_list: &BanList, cidrs: &Cidrs) -> bool {
+        if ban_list.is_banned(ip) {
+            debug!("Request from {} is banned: dropping connection.", ip);
+            return false;
+        }
+
+        if cidrs.deny.iter().any(|c| c.contains(ip)) {
+            warn!("Request from {} matches a deny CIDR: dropping connection.", ip);
+            return false;
+        }
+
+        if !cidrs.accept.iter().any(|c| c.is_trivial() || c.contains(ip)) {
+            warn!("Request from {} does not match {}: dropping connection.", ip, cidrs.accept_cidr);
+            return false;
+        }
+
+        true
+    }
+
+    fn handshake_failed(client_ip: Option<IpAddr>, ban_list: &BanList) {
+        METRICS.handshake_failed();
+
+        // Only TCP clients have an IP to ban.
+        if let Some(ip) = client_ip {
+            ban_list.record_failure(ip);
+        }
+    }
+
+    // Failed authentications are logged with the client's IP and the username it gave (truncated, and never the password).
+    fn auth_failed(id: &str, client_socket: &dyn ClientStream, record: &AuthRecord) {
+        let client = client_socket.peer_name().unwrap_or_default();
+        let client_ip = client.parse::<SocketAddr>().map_or(client.clone(), |a| a.ip().to_string());
+        let username = record.username_for_log();
+
+        warn!(
+            id = id,
+            event = "auth_failed",
+            client_ip = client_ip.as_str(),
+            method = record.method_str(),
+            username = username.as_str();
+            "{} failed `{}` authentication (username `{}`).", client_ip, record.method_str(), username
+        );
+    }
+
+    // Reads the request in the client's protocol (recording how a SOCKS client authenticated in `auth`).
+    async fn negotiate<S>(id: &str, client_socket: &mut S, buffer: &mut [u8], read: usize, config: &Config, protocol: Protocol, auth: &mut Option<AuthRecord>) -> Res<Negotiated>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        match protocol {
+            Protocol::Socks5 => Connection::perform_socks_negotiation(id, client_socket, buffer, read, config, auth).await,
+            Protocol::HttpConnect => {
+                let request = Connection::perform_http_negotiation(client_socket, buffer, read, config.handshake_timeout).await?;
+
+                Ok(Negotiated { request, methods: Vec::new(), user: None })
+            }
+        }
+    }
+
+    // Completes the SOCKS handshake, and reads the request.
+    async fn perform_socks_negotiation<S>(id: &str, client_socket: &mut S, buffer: &mut [u8], read: usize, config: &Config, auth: &mut Option<AuthRecord>) -> Res<Negotiated>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let negotiation = auth::Negotiation::new(config.credentials.as_ref(), &config.auth_methods, config.require_auth);
+        let handshake = Connection::perform_handshake(client_socket, buffer, read, config.handshake_timeout, &negotiation, auth).await?;
+        let user = auth.as_ref().and_then(|a| a.username.clone());
+        let methods_string = Connection::methods_string(&handshake.methods);
+
+        debug!(id = id, event = "handshake"; "  Handshake:");
+        debug!(id = id, event = "handshake"; "    Version: {}", handshake.version);
+        debug!(id = id, event = "handshake"; "    Num Methods: {}", handshake.num_methods);
+        debug!(id = id, event = "handshake"; "    Methods: {}", methods_string);
+
+        let request = Connection::perform_request_negotiation(client_socket, buffer, config.handshake_timeout, config.max_domain_length, config.trace_bytes.then_some(id)).await?;
+
+        Ok(Negotiated { request, methods: handshake.methods, user })
+    }
+
+    // Unknown methods are rendered as hex.
+    fn methods_string(methods: &[u8]) -> String {
+        methods.iter().map(|m| METHODS.get(m).map_or_else(|| format!("0x{:02x}", m), |n| n.to_string())).collect::<Vec<String>>().join(",")
+    }
+
+    // Reads the rest of an HTTP CONNECT request (which must fit in the buffer, and in `MAX_REQUEST_SIZE`).  Clients wait
+    // for the response before sending any data, so everything read is the request line and headers.  The whole request
+    // must arrive within the handshake timeout, so that a client dribbling bytes cannot hold the connection open.
+    async fn perform_http_negotiation<S>(client_socket: &mut S, buffer: &mut [u8], mut read: usize, handshake_timeout: u64) -> Res<Request>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let deadline = tokio::time::Instant::now() + Duration::from_millis(handshake_timeout);
+        let limit = buffer.len().min(http_connect::MAX_REQUEST_SIZE);
 
+        while http_connect::header_length(&buffer[..read]).is_none() {
+            if read >= limit {
+                client_socket.write_all(http_connect::BAD_REQUEST.as_bytes()).await?;
+                return format!("The HTTP CONNECT request exceeds {} bytes.", limit).into_error();
+            }
+
+            match Connection::read_before(client_socket, &mut buffer[read..limit], deadline, handshake_timeout, "HTTP CONNECT request").await? {
+                0 => return "Read 0 bytes during the HTTP CONNECT request.".into_error(),
+                more => read += more
+            }
+        }
+
+        // Render the error first, since it cannot be held across the response.
+        let message = match http_connect::parse_request(&buffer[..read]) {
+            Ok(request) => return Ok(request),
+            Err(e) => e.to_string()
+        };
+
+        client_socket.write_all(http_connect::BAD_REQUEST.as_bytes()).await?;
+
+        message.into_error()
+    }
+
+    // Returns the handshake, and records how the client authenticated in `auth` (whether or not it succeeded).
+    async fn perform_handshake<S>(client_socket: &mut S, buffer: &mut [u8], read: usize, handshake_timeout: u64, negotiation: &auth::Negotiation<'_>, auth: &mut Option<AuthRecord>) -> Res<Handshake>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
         if read == 0 {
             return "Read 0 bytes during handshake.".into_error();
         }
 
-        let handshake = Handshake::from_data(buffer);
+        // Point out clients that are not speaking SOCKS5 at all (like a browser configured to use an HTTP proxy).
+        if let Some(protocol) = Handshake::identify_other_protocol(&buffer[..read]) {
+            return format!("The client looks like {}, not SOCKS5 (check its proxy settings).", protocol).into_error();
+        }
+
+        let handshake = Handshake::from_data(&buffer[..read])?;
 
         if handshake.version != 5 {
             return "Bad SOCKS version.".into_error();
         }
 
+        let (method, mut step) = negotiation.select(&handshake.methods);
+        let selected = auth::Method::from_code(method);
+
         // Reuse the buffer since we are borrowing it anyway.
 
         buffer[0] = 0x05; // VERSION.
-        buffer[1] = 0x00; // NO AUTH.
+        buffer[1] = method;
 
         client_socket.write_all(&buffer[..2]).await?;
         client_socket.flush().await?;
 
-        Ok(handshake)
+        // Run the selected method's sub-negotiation (if it has one) until the client is done or refused.
+        loop {
+            *auth = AuthRecord::of(selected, &step);
+
+            step = match step {
+                auth::Step::SubNegotiate(method) => {
+                    let read = Connection::read_with_timeout(client_socket, buffer, handshake_timeout, "authentication").await?;
+                    let (reply, next) = negotiation.sub_negotiate(method, &buffer[..read]).await;
+
+                    client_socket.write_all(reply).await?;
+                    client_socket.flush().await?;
+
+                    next
+                },
+                auth::Step::Done(_) => return Ok(handshake),
+                auth::Step::Failed(_) => return format!("The client failed `{}` authentication.", selected.map_or("none", auth::Method::as_str)).into_error(),
+                auth::Step::Refused(reason) => return reason.into_error()
+            };
+        }
     }
 
-    async fn perform_request_negotiation(client_socket: &mut TcpStream, buffer: &mut [u8]) -> Res<Request> {
-        let read = client_socket.read(buffer).await?;
+    // The bytes read are traced under `trace_id`, if it is given.
+    async fn perform_request_negotiation<S>(client_socket: &mut S, buffer: &mut [u8], handshake_timeout: u64, max_domain_length: usize, trace_id: Option<&str>) -> Res<Request>
+        where S: AsyncRead + AsyncWrite + Unpin
+    {
+        let read = Connection::read_with_timeout(client_socket, buffer, handshake_timeout, "connection negotiation").await?;
+
+        Connection::trace_bytes(trace_id, "request", &buffer[..read]);
 
         if read == 0 {
             return "Read 0 bytes during connection negotiation.".into_error();
         }
 
-        let request = Request::from_data(buffer)?;
+        // Reject oversized domain names before trying to read them.
+        if let Some(length) = Request::domain_length(&buffer[..read]).filter(|l| *l > max_domain_length) {
+            Connection::send_reply(client_socket, buffer, 0x01, UNSPECIFIED_ADDR).await?;
+
+            return format!("The domain name length {} exceeds the maximum of {}.", length, max_domain_length).into_error();
+        }
+
+        // Tell the client why the request was rejected, rather than just closing the connection (the error is
+        // rendered first, since it cannot be held across the reply).
+        let message = match Request::from_data(&buffer[..read]) {
+            Ok(request) => return Ok(request),
+            Err(e) => e.to_string()
+        };
+
+        let reply = match buffer[..read].get(3) {
+            Some(address_type) if !ADDRESS_TYPES.contains_key(address_type) => 0x08, // Address type not supported.
+            _ => 0x01 // General SOCKS server failure.
+        };
+
+        Connection::send_reply(client_socket, buffer, reply, UNSPECIFIED_ADDR).await?;
 
-        Ok(request)
+        message.into_error()
     }
 
-    async fn establish_connect_request(client_socket: &mut TcpStream, endpoint_interface: &str, request: &Request, buffer: &mut [u8]) -> Res<TcpStream> {
-        let mut reply = 0u8;
+    fn trace_bytes(trace_id: Option<&str>, stage: &str, data: &[u8]) {
+        if let Some(id) = trace_id {
+            trace!(id = id, event = "bytes"; "  Received {} bytes during {}: {}", data.len(), stage, Helpers::hexdump(data, TRACE_BYTES_LIMIT));
+        }
+    }
 
-        // Get requested local interface.
-        let local_addr = SocketAddr::from_str(&format!("{}:{}", endpoint_interface, 0))?;
-        
-        // Get endpoint address.
-        let string_to_connect = format!("{}:{}", request.destination, request.port);
-        let endpoint_addr_iterator = tokio::net::lookup_host(&string_to_connect).await;
-
-        // Compute valid endpoint addresses, and connect to endpoint.
-        let endpoint_socket = match endpoint_addr_iterator {
-            Ok(endpoint_addresses) => {
-                // Try to create a local socket that can connect to the endpoint.
-                match Helpers::create_local_socket(local_addr, endpoint_addresses) {
-                    Some(ep) => {
-                        let socket = ep.socket;
-                        let endpoint_addr = ep.address;
-
-                        // Connect to endpoint.
-                        match socket.connect(endpoint_addr).await {
-                            Ok(s) => Some(s),
-                            Err(e) => {
-                                warn!("Could not connect to `{}` (`{}`).", string_to_connect, endpoint_addr);
-                                
-                                reply = match e.raw_os_error() {
-                                    Some(i) => Helpers::get_socks_reply(i),
-                                    _ => 5u8 // Connection refused?.
-                                };
-
-                                None
-                            }
-                        }
-                    },
-                    None => {
-                        warn!("Could not create local socket (`{}`) to `{}`. This likely means that we could not find a suitable address type for the endpoint that matches the endpoint interface type (i.e., IPv6/IPv4 mismatch).", local_addr, string_to_connect);
-                        
-                        reply = 5u8; // Connection refused?.
-
-                        None
-                    }
-                }
-            },
-            Err(e) => {
-                warn!("Could not compute an endpoint address for `{}`.", string_to_connect);
-                
-                reply = match e.raw_os_error() {
-                    Some(i) => Helpers::get_socks_reply(i),
-                    _ => 8u8 // Address type not supported.
-                };
+    async fn read_with_timeout<S>(client_socket: &mut S, buffer: &mut [u8], timeout: u64, stage: &str) -> Res<usize>
+        where S: AsyncRead + Unpin
+    {
+        Connection::read_before(client_socket, buffer, tokio::time::Instant::now() + Duration::from_millis(timeout), timeout, stage).await
+    }
+
+    // Reads what is available before the deadline (which is `timeout` ms after the stage started).
+    async fn read_before<S>(client_socket: &mut S, buffer: &mut [u8], deadline: tokio::time::Instant, timeout: u64, stage: &str) -> Res<usize>
+        where S: AsyncRead + Unpin
+    {
+        match tokio::time::timeout_at(deadline, client_socket.read(buffer)).await {
+            Ok(read) => Ok(read?),
+            Err(_) => Err(TimeoutError::boxed(format!("Timed out after {} ms during {}.", timeout, stage)))
+        }
+    }
+
+    // The reply sent to the client is recorded in `sent_reply` (even when the connection fails).
+    #[allow(clippy::too_many_arguments)]
+    async fn establish_connect_request<S>(client_socket: &mut S, config: &Config, resolver: &Resolver, request: &Request, buffer: &mut [u8], protocol: Protocol, sent_reply: &mut Option<u8>) -> Res<TcpStream>
+        where S: AsyncWrite + Unpin
+    {
+        let string_to_connect = Helpers::host_port(&request.destination.to_string(), request.port);
+
+        // Connect to the endpoint (either directly, or through the upstream proxy), retrying transient failures.
+        let mut retries = 0;
+        let result = loop {
+            let result = match &config.upstream_proxy {
+                Some(upstream) => Connection::connect_via_upstream(config, resolver, upstream, request).await,
+                None => Connection::connect_direct(config, resolver, &string_to_connect).await
+            };
 
-                None
+            match result {
+                Err(reply) if retries < config.connect_retries && Connection::is_transient(reply) => {
+                    let delay = config.connect_retry_delay.saturating_mul(1 << retries.min(16));
+                    retries += 1;
+
+                    warn!("Retrying the connection to `{}` in {} ms (retry {} of {}) after `{}`.", string_to_connect, delay, retries, config.connect_retries, ERRORS.get(&reply).unwrap_or(&"Unknown"));
+                    sleep(Duration::from_millis(delay)).await;
+                },
+                result => break result
             }
         };
+
+        let (endpoint_socket, reply, bound_addr) = match result {
+            Ok(s) => {
+                let bound_addr = s.local_addr().unwrap_or(UNSPECIFIED_ADDR);
+                (Some(s), 0u8, bound_addr)
+            },
+            Err(r) => (None, r, UNSPECIFIED_ADDR)
+        };
+        
+        // Send a response to the client, even if there is a failure.
+
+        *sent_reply = Some(reply);
+        METRICS.connect_replied(reply);
+        Connection::send_response(client_socket, buffer, protocol, reply, bound_addr).await?;
+
+        // In a failure scenario, ensure the SOCKS process does not continue.
         
+        if reply != 0 {
+            return format!("The connection to `{}` failed gracefully with `{}`.", string_to_connect, ERRORS.get(&reply).unwrap_or(&"Unknown")).into_error();
+        }
+        
+        // This should only be `None` if there is an error, which aborts above.
+        Ok(endpoint_socket.unwrap())
+    }
+
+    // Sends the reply in the client's protocol (HTTP has no bound address).
+    async fn send_response<S>(client_socket: &mut S, buffer: &mut [u8], protocol: Protocol, reply: u8, bound_addr: SocketAddr) -> Void
+        where S: AsyncWrite + Unpin
+    {
+        match protocol {
+            Protocol::Socks5 => Connection::send_reply(client_socket, buffer, reply, bound_addr).await,
+            Protocol::HttpConnect => {
+                client_socket.write_all(http_connect::response(reply).as_bytes()).await?;
+                client_socket.flush().await?;
+
+                Ok(())
+            }
+        }
+    }
+
+    async fn send_reply<S>(client_socket: &mut S, buffer: &mut [u8], reply: u8, bound_addr: SocketAddr) -> Void
+        where S: AsyncWrite + Unpin
+    {
         // Get the local IP and port.
-        let local_ip = local_addr.ip();
-        let (port_high, port_low) = Helpers::port_to_bytes(local_addr.port());
+        let (port_high, port_low) = Helpers::port_to_bytes(bound_addr.port());
 
         // Prepare reply.
 
@@ -208,13 +801,10 @@ impl Connection {
         buffer[1] = reply;
         buffer[2] = 0x0; // RESERVED.
 
-        let reply_length = match local_ip {
+        let reply_length = match bound_addr.ip() {
             IpAddr::V4(ipv4) => {
-                let octets = ipv4.octets();
-
                 buffer[3] = 0x01; // ADDRESS TYPE (IPv4).
-                buffer[4] = octets[0]; buffer[5] = octets[1]; buffer[6] = octets[2]; buffer[7] = octets[3];
-                Helpers::write_octets(&mut buffer[4..8], &octets);
+                Helpers::write_octets(&mut buffer[4..8], &ipv4.octets());
 
                 buffer[8] = port_high;
                 buffer[9] = port_low;
@@ -222,10 +812,8 @@ impl Connection {
                 10
             },
             IpAddr::V6(ipv6) => {
-                let octets = ipv6.octets();
-
                 buffer[3] = 0x04; // ADDRESS TYPE (IPv6).
-                Helpers::write_octets(&mut buffer[4..20], &octets);
+                Helpers::write_octets(&mut buffer[4..20], &ipv6.octets());
 
                 buffer[20] = port_high;
                 buffer[21] = port_low;
@@ -234,40 +822,450 @@ impl Connection {
             }
         };
 
-        // Send a response to the client, even if there is a failure.
-
         client_socket.write_all(&buffer[0..reply_length]).await?;
         client_socket.flush().await?;
 
-        // In a failure scenario, ensure the SOCKS process does not continue.
-        
-        if reply != 0 {
-            return format!("The connection to `{}` failed gracefully with `{}`.", string_to_connect, ERRORS[&reply]).into_error();
+        Ok(())
+    }
+
+    fn is_port_allowed(config: &Config, port: u16) -> bool {
+        // Empty lists mean no restriction.
+        (config.allowed_ports.is_empty() || config.allowed_ports.contains(&port)) && !config.denied_ports.contains(&port)
+    }
+
+    // Deny takes precedence over allow.  IP destinations only match entries that are the same IP literal (there is no reverse lookup).
+    fn is_destination_allowed(config: &Config, destination: &Destination) -> bool {
+        let host = destination.to_string();
+        let is_match = |pattern: &String| Helpers::is_host_match(pattern, &host);
+
+        (config.allowed_domains.is_empty() || config.allowed_domains.iter().any(is_match)) && !config.denied_domains.iter().any(is_match)
+    }
+
+    // Refusals and timeouts may succeed on a retry; anything else (like an unsupported address) will not.
+    fn is_transient(reply: u8) -> bool {
+        matches!(reply, 0x05 | 0x06)
+    }
+
+    // Users may be limited to certain destinations (and clients that did not authenticate have no such limits).
+    fn is_user_allowed(config: &Config, user: Option<&str>, destination: &Destination) -> bool {
+        match (&config.credentials, user) {
+            (Some(credentials), Some(user)) => credentials.is_allowed(user, destination),
+            _ => true
+        }
+    }
+
+    fn is_diagnostic(config: &Config, destination: &Destination) -> bool {
+        match (&config.diagnostic_hostname, destination) {
+            (Some(hostname), Destination::Domain(domain)) => hostname.eq_ignore_ascii_case(domain),
+            _ => false
+        }
+    }
+
+    // One `key: value` line per fact, so that it reads well from `nc` or `curl`.
+    fn diagnostic_report(client: &str, protocol: Protocol, methods: &[u8]) -> String {
+        let methods = if methods.is_empty() { "none".to_owned() } else { Connection::methods_string(methods) };
+
+        format!("client: {}\nprotocol: {}\nmethods: {}\n", client, protocol.as_str(), methods)
+    }
+
+    fn set_keepalive(id: &str, socket: &dyn ClientStream, keepalive: Option<u64>) {
+        if let Some(idle) = keepalive {
+            if let Err(e) = socket.set_keepalive(Duration::from_secs(idle)) {
+                warn!(id = id; "Could not enable TCP keepalive after {} s.  {}", idle, e);
+            }
+        }
+    }
+
+    fn set_no_delay(id: &str, socket: &dyn ClientStream, no_delay: bool) {
+        if let Err(e) = socket.set_no_delay(no_delay) {
+            warn!(id = id; "Could not set TCP_NODELAY to {}.  {}", no_delay, e);
+        }
+    }
+
+    // Returns the connected socket, or the SOCKS reply for the failure.
+    async fn connect_direct(config: &Config, resolver: &Resolver, string_to_connect: &str) -> Result<TcpStream, u8> {
+        // Get endpoint address.
+        let endpoint_addresses = Connection::resolve(resolver, string_to_connect).await?;
+
+        // Compute valid endpoint addresses (alternating the families), and connect to endpoint.
+        let compatible_addresses = Helpers::interleave_families(Helpers::get_compatible_addresses(&config.endpoint_ips, endpoint_addresses.iter().copied()));
+
+        // Without an endpoint IP of an address's family, there is no source address to connect to it from.
+        if compatible_addresses.is_empty() {
+            warn!(
+                event = "address_family_mismatch",
+                destination = string_to_connect;
+                "Could not connect to `{}`, which resolved to {} addresses, since the endpoint IPs (`{}`) are {}.",
+                string_to_connect,
+                Helpers::families(endpoint_addresses.iter().map(SocketAddr::ip)),
+                Helpers::join(&config.endpoint_ips),
+                Helpers::families(config.endpoint_ips.iter().copied())
+            );
+
+            return Err(8u8); // Address type not supported.
+        }
+
+        // Race the addresses, and keep the first that connects.
+        Connection::connect_to_any(&config.endpoint_ips, config.source_port_range, config.endpoint_device.as_deref(), &compatible_addresses, string_to_connect, config.connect_timeout, config.happy_eyeballs_delay).await
+    }
+
+    // Returns the endpoint addresses, or the SOCKS reply for the failure.
+    async fn resolve(resolver: &Resolver, string_to_connect: &str) -> Result<Vec<SocketAddr>, u8> {
+        resolver.resolve(string_to_connect).await.map_err(|e| {
+            warn!("Could not compute an endpoint address for `{}`.", string_to_connect);
+
+            match e.raw_os_error() {
+                Some(i) => Helpers::get_socks_reply(i),
+                _ => 8u8 // Address type not supported.
+            }
+        })
+    }
+
+    // Returns a socket connected to the endpoint through the upstream proxy, or the SOCKS reply for the failure.
+    async fn connect_via_upstream(config: &Config, resolver: &Resolver, upstream: &UpstreamProxy, request: &Request) -> Result<TcpStream, u8> {
+        // Domains are resolved here (and the upstream is given an IP), unless they should be passed along as-is.
+        let resolved = match &request.destination {
+            Destination::Domain(domain) if config.resolve_domains => {
+                let addresses = Connection::resolve(resolver, &Helpers::host_port(domain, request.port)).await?;
+
+                match addresses.first().map(|a| a.ip()) {
+                    Some(IpAddr::V4(ipv4)) => Some(Destination::Ipv4Addr(ipv4)),
+                    Some(IpAddr::V6(ipv6)) => Some(Destination::Ipv6Addr(ipv6)),
+                    None => return Err(4u8) // Host unreachable.
+                }
+            },
+            _ => None
+        };
+        let destination = resolved.as_ref().unwrap_or(&request.destination);
+
+        let mut upstream_socket = Connection::connect_direct(config, resolver, &upstream.address).await?;
+
+        let negotiation = upstream.negotiate(&mut upstream_socket, destination, request.port);
+
+        match tokio::time::timeout(Duration::from_millis(config.connect_timeout), negotiation).await {
+            Ok(Ok(())) => Ok(upstream_socket),
+            Ok(Err(reply)) => {
+                warn!("The upstream proxy `{}` could not connect to `{}:{}` (`{}`).", upstream.address, request.destination, request.port, ERRORS.get(&reply).unwrap_or(&"Unknown"));
+
+                Err(reply)
+            },
+            Err(_) => {
+                warn!("Timed out after {} ms negotiating with the upstream proxy `{}`.", config.connect_timeout, upstream.address);
+
+                Err(6u8) // TTL expired (connection timed out).
+            }
+        }
+    }
+
+    // Races the addresses (RFC 8305 "Happy Eyeballs"): a new attempt starts whenever the previous one fails, or has not
+    // connected within the delay.  Returns the first socket to connect (cancelling the rest), or the SOCKS reply for the
+    // last failure if no address connects.
+    async fn connect_to_any(local_ips: &[IpAddr], source_ports: Option<PortRange>, device: Option<&str>, endpoint_addresses: &[SocketAddr], string_to_connect: &str, connect_timeout: u64, attempt_delay: u64) -> Result<TcpStream, u8> {
+        let mut reply = 1u8; // General SOCKS server failure.
+        let mut pending = endpoint_addresses.iter();
+        let mut attempts = FuturesUnordered::new();
+
+        loop {
+            if let Some(endpoint_addr) = pending.next() {
+                attempts.push(Connection::connect_to(local_ips, source_ports, device, *endpoint_addr, string_to_connect, connect_timeout));
+            }
+
+            if attempts.is_empty() {
+                return Err(reply);
+            }
+
+            tokio::select! {
+                Some(result) = attempts.next() => match result {
+                    Ok(s) => return Ok(s),
+                    Err(r) => reply = r
+                },
+                _ = sleep(Duration::from_millis(attempt_delay)), if pending.len() > 0 => {}
+            }
+        }
+    }
+
+    // Returns the connected socket, or the SOCKS reply for the failure.
+    async fn connect_to(local_ips: &[IpAddr], source_ports: Option<PortRange>, device: Option<&str>, endpoint_addr: SocketAddr, string_to_connect: &str, connect_timeout: u64) -> Result<TcpStream, u8> {
+        let socket = match Helpers::create_local_socket(local_ips, &endpoint_addr, source_ports, device) {
+            Ok(s) => s,
+            Err(e) => {
+                warn!("Could not create local socket (`{}`) to `{}` (`{}`).  {}", Helpers::get_local_addr_for(local_ips, &endpoint_addr), string_to_connect, endpoint_addr, e);
+
+                return Err(1u8); // General SOCKS server failure.
+            }
+        };
+
+        match tokio::time::timeout(Duration::from_millis(connect_timeout), socket.connect(endpoint_addr)).await {
+            Ok(Ok(s)) => Ok(s),
+            Err(_) => {
+                warn!("Timed out after {} ms connecting to `{}` (`{}`).", connect_timeout, string_to_connect, endpoint_addr);
+
+                Err(6u8) // TTL expired (connection timed out).
+            },
+            Ok(Err(e)) => {
+                warn!("Could not connect to `{}` (`{}`).", string_to_connect, endpoint_addr);
+
+                Err(match e.raw_os_error() {
+                    Some(i) => Helpers::get_socks_reply(i),
+                    _ => 5u8 // Connection refused?.
+                })
+            }
         }
-        
-        // This should only be `None` if there is an error, which aborts above.
-        Ok(endpoint_socket.unwrap())
     }
 }
 
+// The most bytes hexdumped for each read (enough for the largest SOCKS request).
+const TRACE_BYTES_LIMIT: usize = 512;
+
+// The bound address sent in replies that have no endpoint socket.
+const UNSPECIFIED_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+// The connections seen so far (for the log sampling).
+static CONNECTIONS_SEEN: AtomicU64 = AtomicU64::new(0);
+
 static COMMANDS: Map<u8, &'static str> = phf_map! {
     1u8 => "Connect",
     2u8 => "Bind",
     3u8 => "UDP Associate",
 };
 
+static METHODS: Map<u8, &'static str> = phf_map! {
+    0u8 => "NoAuth",
+    1u8 => "GSSAPI",
+    2u8 => "UserPass",
+};
+
 static ADDRESS_TYPES: Map<u8, &'static str> = phf_map! {
     1u8 => "Ipv4",
     3u8 => "Domain",
     4u8 => "Ipv6",
 };
 
-static ERRORS: Map<u8, &'static str> = phf_map! {
+pub(crate) static ERRORS: Map<u8, &'static str> = phf_map! {
     0u8 => "Succeeded",
     1u8 => "General SOCKS Server Failure",
+    2u8 => "Connection Not Allowed By Ruleset",
     3u8 => "Network Unreachable",
     4u8 => "Host Unreachable",
     5u8 => "Connection Refused",
     6u8 => "TTL Expired",
-    8u8 => "Address type not supported"
-};
\ No newline at end of file
+    7u8 => "Command Not Supported",
+    8u8 => "Address Type Not Supported"
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_test::io::Builder;
+    use crate::request::MAX_REQUEST_SIZE;
+    use crate::auth::Credentials;
+
+    #[test]
+    fn diagnostic_report_lists_the_client_and_methods() {
+        assert_eq!("client: 10.0.0.1:5000\nprotocol: SOCKS5\nmethods: NoAuth,UserPass\n", Connection::diagnostic_report("10.0.0.1:5000", Protocol::Socks5, &[0, 2]));
+        assert_eq!("client: 10.0.0.1:5000\nprotocol: HTTP CONNECT\nmethods: none\n", Connection::diagnostic_report("10.0.0.1:5000", Protocol::HttpConnect, &[]));
+    }
+
+    #[test]
+    fn methods_string_names_known_methods() {
+        assert_eq!("NoAuth,GSSAPI,UserPass,0x80", Connection::methods_string(&[0x00, 0x01, 0x02, 0x80]));
+    }
+
+    #[test]
+    fn is_sampled_picks_one_in_every() {
+        assert_eq!(vec![0, 3, 6, 9], (0..10).filter(|c| Connection::is_sampled(3, *c)).collect::<Vec<_>>());
+        assert!((0..10).all(|c| Connection::is_sampled(1, c)));
+    }
+
+    #[test]
+    fn log_level_keeps_failures_at_info() {
+        let summary = ConnectionSummary {
+            id: "id".to_owned(),
+            client: "10.0.0.1:5000".to_owned(),
+            destination: None,
+            port: None,
+            reply_code: Some(0x00),
+            auth: None,
+            bytes_up: 0,
+            bytes_down: 0,
+            duration: Duration::ZERO,
+            outcome: Outcome::Normal,
+            sampled: false
+        };
+
+        assert_eq!(Level::Debug, summary.log_level());
+        assert_eq!(Level::Info, ConnectionSummary { sampled: true, ..summary.clone() }.log_level());
+        assert_eq!(Level::Info, ConnectionSummary { reply_code: Some(0x05), ..summary.clone() }.log_level());
+        assert_eq!(Level::Info, ConnectionSummary { outcome: Outcome::Timeout, ..summary }.log_level());
+    }
+
+    // Fills the start of a buffer, as if the data had just been read.
+    fn read_into(data: &[u8]) -> ([u8; MAX_REQUEST_SIZE], usize) {
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+        buffer[..data.len()].copy_from_slice(data);
+
+        (buffer, data.len())
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_replies_no_auth() {
+        let mut socket = Builder::new().write(&[0x05, 0x00]).build();
+        let (mut buffer, read) = read_into(&[0x05, 0x01, 0x00]);
+
+        let mut record = None;
+        let handshake = Connection::perform_handshake(&mut socket, &mut buffer, read, 1000, &auth::Negotiation::new(None, &auth::DEFAULT_METHODS, false), &mut record).await.unwrap();
+
+        assert_eq!(vec![0x00], handshake.methods);
+        assert_eq!(Some(AuthRecord { method: Some(auth::Method::NoAuth), username: None, succeeded: true }), record);
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_rejects_bad_version() {
+        let mut socket = Builder::new().build();
+        let (mut buffer, read) = read_into(&[0x03, 0x01, 0x00]);
+
+        assert!(Connection::perform_handshake(&mut socket, &mut buffer, read, 1000, &auth::Negotiation::new(None, &auth::DEFAULT_METHODS, false), &mut None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_authenticates_user_pass() {
+        let mut credentials = Credentials::default();
+        credentials.add("user".to_owned(), "pass".to_owned(), None).unwrap();
+
+        let mut socket = Builder::new().write(&[0x05, 0x02]).read(b"\x01\x04user\x04pass").write(&[0x01, 0x00]).build();
+        let (mut buffer, read) = read_into(&[0x05, 0x02, 0x00, 0x02]);
+        let mut record = None;
+        Connection::perform_handshake(&mut socket, &mut buffer, read, 1000, &auth::Negotiation::new(Some(&credentials), &auth::DEFAULT_METHODS, false), &mut record).await.unwrap();
+        assert_eq!(Some(AuthRecord { method: Some(auth::Method::UserPass), username: Some("user".to_owned()), succeeded: true }), record);
+
+        let mut socket = Builder::new().write(&[0x05, 0x02]).read(b"\x01\x04user\x04nope").write(&[0x01, 0x01]).build();
+        let (mut buffer, read) = read_into(&[0x05, 0x02, 0x00, 0x02]);
+        let mut record = None;
+        assert!(Connection::perform_handshake(&mut socket, &mut buffer, read, 1000, &auth::Negotiation::new(Some(&credentials), &auth::DEFAULT_METHODS, false), &mut record).await.is_err());
+        assert_eq!(Some(AuthRecord { method: Some(auth::Method::UserPass), username: Some("user".to_owned()), succeeded: false }), record);
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_refuses_no_auth_when_auth_is_required() {
+        let mut credentials = Credentials::default();
+        credentials.add("user".to_owned(), "pass".to_owned(), None).unwrap();
+
+        let mut socket = Builder::new().write(&[0x05, 0xFF]).build();
+        let (mut buffer, read) = read_into(&[0x05, 0x01, 0x00]);
+
+        assert!(Connection::perform_handshake(&mut socket, &mut buffer, read, 1000, &auth::Negotiation::new(Some(&credentials), &auth::DEFAULT_METHODS, true), &mut None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn perform_handshake_identifies_http() {
+        let mut socket = Builder::new().build();
+        let (mut buffer, read) = read_into(b"GET / HTTP/1.1\r\n\r\n");
+
+        let error = Connection::perform_handshake(&mut socket, &mut buffer, read, 1000, &auth::Negotiation::new(None, &auth::DEFAULT_METHODS, false), &mut None).await.err().unwrap();
+
+        assert!(error.to_string().contains("HTTP, not SOCKS5"));
+    }
+
+    #[tokio::test]
+    async fn perform_http_negotiation_reads_split_requests() {
+        let mut socket = Builder::new().read(b"Host: example.com:443\r\n\r\n").build();
+        let (mut buffer, read) = read_into(b"CONNECT example.com:443 HTTP/1.1\r\n");
+
+        let request = Connection::perform_http_negotiation(&mut socket, &mut buffer, read, 1000).await.unwrap();
+
+        assert_eq!("example.com", request.destination.to_string());
+        assert_eq!(443, request.port);
+    }
+
+    #[tokio::test]
+    async fn perform_http_negotiation_times_out_a_slow_client() {
+        let (mut socket, mut client) = tokio::io::duplex(64);
+        let (mut buffer, read) = read_into(b"CONNECT example.com:443 HTTP/1.1\r\n");
+
+        // Each byte arrives well within the timeout, but the request as a whole does not.
+        tokio::spawn(async move {
+            for byte in b"Host: example.com:443\r\n\r\n" {
+                sleep(Duration::from_millis(20)).await;
+                if client.write_all(&[*byte]).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        let error = Connection::perform_http_negotiation(&mut socket, &mut buffer, read, 100).await.err().unwrap();
+
+        assert!(error.is::<TimeoutError>());
+    }
+
+    #[tokio::test]
+    async fn perform_http_negotiation_caps_the_request_size() {
+        let mut socket = Builder::new().read(&[b'a'; 8 * 1024 - 9]).write(http_connect::BAD_REQUEST.as_bytes()).build();
+        let mut buffer = vec![0u8; 16 * 1024];
+        buffer[..9].copy_from_slice(b"CONNECT a");
+
+        let error = Connection::perform_http_negotiation(&mut socket, &mut buffer, 9, 1000).await.err().unwrap();
+
+        assert!(error.to_string().contains("exceeds"));
+    }
+
+    #[tokio::test]
+    async fn perform_request_negotiation_parses_domain() {
+        let mut socket = Builder::new().read(&[0x05, 0x01, 0x00, 0x03, 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x01, 0xbb]).build();
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+
+        let request = Connection::perform_request_negotiation(&mut socket, &mut buffer, 1000, 255, None).await.unwrap();
+
+        assert_eq!("example", request.destination.to_string());
+        assert_eq!(443, request.port);
+    }
+
+    #[tokio::test]
+    async fn perform_request_negotiation_rejects_long_domain() {
+        let mut socket = Builder::new()
+            .read(&[0x05, 0x01, 0x00, 0x03, 0x07, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0x01, 0xbb])
+            .write(&[0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .build();
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+
+        assert!(Connection::perform_request_negotiation(&mut socket, &mut buffer, 1000, 6, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn perform_request_negotiation_replies_to_unknown_address_type() {
+        let mut socket = Builder::new()
+            .read(&[0x05, 0x01, 0x00, 0x02, 0x00, 0x50])
+            .write(&[0x05, 0x08, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .build();
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+
+        assert!(Connection::perform_request_negotiation(&mut socket, &mut buffer, 1000, 255, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn perform_request_negotiation_replies_to_truncated_request() {
+        let mut socket = Builder::new()
+            .read(&[0x05, 0x01, 0x00, 0x01, 127, 0])
+            .write(&[0x05, 0x01, 0x00, 0x01, 0, 0, 0, 0, 0, 0])
+            .build();
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+
+        assert!(Connection::perform_request_negotiation(&mut socket, &mut buffer, 1000, 255, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn send_reply_writes_ipv4_reply() {
+        let mut socket = Builder::new().write(&[0x05, 0x02, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).build();
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+
+        Connection::send_reply(&mut socket, &mut buffer, 0x02, UNSPECIFIED_ADDR).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn send_reply_writes_ipv6_reply() {
+        let bound_addr = SocketAddr::new(IpAddr::V6(std::net::Ipv6Addr::LOCALHOST), 0x1f90);
+        let mut socket = Builder::new().write(&[0x05, 0x00, 0x00, 0x04, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1f, 0x90]).build();
+        let mut buffer = [0u8; MAX_REQUEST_SIZE];
+
+        Connection::send_reply(&mut socket, &mut buffer, 0x00, bound_addr).await.unwrap();
+    }
+}
@@ -1,48 +1,204 @@
 use std::time::Duration;
 
 use futures::{pin_mut, future::Either};
-use tokio::net::TcpStream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-use crate::helpers::{IntoError, Res};
+use crate::activity::{Activity, ActivityReader, Transfer};
+use crate::helpers::{Res, TimeoutError};
+use crate::rate_limit::throttled_copy;
 
-pub struct CopyPump {
-    client_socket: TcpStream,
-    endpoint_socket: TcpStream,
-    read_timeout: u64
+pub struct CopyPump<'a, C, E> {
+    client_socket: C,
+    endpoint_socket: E,
+    buffer: &'a mut [u8],
+    // The percentage of the buffer for uploads (the rest is for downloads).
+    up_percent: u8,
+    idle_timeout: u64,
+    rate_limit: Option<u64>
 }
 
-impl CopyPump {
-    pub fn from(client_socket: TcpStream, endpoint_socket: TcpStream, read_timeout: u64) -> Self {
-        CopyPump { client_socket, endpoint_socket, read_timeout }
+impl<'a, C, E> CopyPump<'a, C, E>
+    where C: AsyncRead + AsyncWrite + Unpin, E: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn from(client_socket: C, endpoint_socket: E, buffer: &'a mut [u8], up_percent: u8, idle_timeout: u64, rate_limit: Option<u64>) -> Self {
+        CopyPump { client_socket, endpoint_socket, buffer, up_percent, idle_timeout, rate_limit }
     }
 
-    pub async fn start(self) -> Res<()> {
+    // The transfer counts are reported even when the pump ends with an error.
+    pub async fn start(self) -> (Transfer, Res<()>) {
         self.run_pumps_as_copy().await
     }
 
-    async fn run_pumps_as_copy(self) -> Res<()> {
-        let (mut client_socket_read, mut client_socket_write) = self.client_socket.into_split();
-        let (mut endpoint_socket_read, mut endpoint_socket_write) = self.endpoint_socket.into_split();
+    async fn run_pumps_as_copy(self) -> (Transfer, Res<()>) {
+        // Split the buffer between the directions per the config (by its own length, since the buffer size may have been reloaded).
+        let up_size = self.buffer.len() * self.up_percent as usize / 100;
+        let (buffer_up, buffer_down) = self.buffer.split_at_mut(up_size);
 
-        let pump_up = tokio::io::copy(&mut client_socket_read, &mut endpoint_socket_write);
-        let pump_down = tokio::io::copy(&mut endpoint_socket_read, &mut client_socket_write);
+        let (client_socket_read, mut client_socket_write) = tokio::io::split(self.client_socket);
+        let (endpoint_socket_read, mut endpoint_socket_write) = tokio::io::split(self.endpoint_socket);
 
-        pin_mut!(pump_up);
-        pin_mut!(pump_down);
-        
+        // Both directions report to the same activity tracker, so the timeout only fires when the whole connection is idle.
+        let activity = Activity::new();
+        let mut client_socket_read = ActivityReader::new(client_socket_read, &activity);
+        let mut endpoint_socket_read = ActivityReader::new(endpoint_socket_read, &activity);
 
-        let pumps = futures::future::select(pump_up, pump_down);
+        let result = {
+            let pump_up = Self::pump(&mut client_socket_read, &mut endpoint_socket_write, buffer_up, self.rate_limit);
+            let pump_down = Self::pump(&mut endpoint_socket_read, &mut client_socket_write, buffer_down, self.rate_limit);
 
-        let timeout = tokio::time::sleep(Duration::from_millis(self.read_timeout));
-        pin_mut!(timeout);
+            // Each direction runs until its own EOF, so a half-closed connection keeps flowing the other way.
+            let pumps = futures::future::try_join(pump_up, pump_down);
+            pin_mut!(pumps);
 
-        match futures::future::select(pumps, timeout).await {
-            Either::Left(_) => {},
-            Either::Right((_, _)) => {
-                return "Timed out.".into_error()
+            let timeout = activity.idle(Duration::from_millis(self.idle_timeout));
+            pin_mut!(timeout);
+
+            match futures::future::select(pumps, timeout).await {
+                Either::Left((Ok(_), _)) => Ok(()),
+                Either::Left((Err(e), _)) => Err(e.into()),
+                Either::Right((_, _)) => Err(TimeoutError::boxed("Timed out."))
+            }
+        };
+
+        let transfer = Transfer {
+            bytes_up: client_socket_read.bytes(),
+            bytes_down: endpoint_socket_read.bytes()
+        };
+
+        (transfer, result)
+    }
+
+    async fn pump<R, W>(from: &mut R, to: &mut W, buffer: &mut [u8], rate_limit: Option<u64>) -> std::io::Result<u64>
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+    {
+        let bytes = match rate_limit {
+            Some(r) => throttled_copy(from, to, buffer, r).await?,
+            None => Self::copy(from, to, buffer).await?
+        };
+
+        // Pass the EOF along to the other side (which may already be gone).
+        to.shutdown().await.unwrap_or_default();
+
+        Ok(bytes)
+    }
+
+    // Like `tokio::io::copy`, but through the leased buffer rather than one allocated per call.
+    async fn copy<R, W>(from: &mut R, to: &mut W, buffer: &mut [u8]) -> std::io::Result<u64>
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+    {
+        let mut total = 0u64;
+
+        loop {
+            let read = from.read(buffer).await?;
+
+            if read == 0 {
+                return Ok(total);
             }
+
+            to.write_all(&buffer[..read]).await?;
+            total += read as u64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+    use tokio::io::{duplex, AsyncReadExt, DuplexStream, ReadBuf};
+    use crate::config::Config;
+
+    #[tokio::test]
+    async fn start_pumps_both_ways_and_counts() {
+        let (client, mut client_peer) = duplex(64);
+        let (endpoint, mut endpoint_peer) = duplex(64);
+        let mut buffer = [0u8; 32];
+
+        let pump = tokio::spawn(async move {
+            CopyPump::from(client, endpoint, &mut buffer, 25, 1000, None).start().await.0
+        });
+
+        client_peer.write_all(b"up").await.unwrap();
+        let mut up = [0u8; 2];
+        endpoint_peer.read_exact(&mut up).await.unwrap();
+
+        endpoint_peer.write_all(b"down").await.unwrap();
+        let mut down = [0u8; 4];
+        client_peer.read_exact(&mut down).await.unwrap();
+
+        drop(client_peer);
+        drop(endpoint_peer);
+        let transfer = pump.await.unwrap();
+
+        assert_eq!(b"up", &up);
+        assert_eq!(b"down", &down);
+        assert_eq!((2, 4), (transfer.bytes_up, transfer.bytes_down));
+    }
+
+    // Records the size of every write, so tests can see how the pump chunks its copies.
+    struct WriteRecorder {
+        inner: DuplexStream,
+        writes: Arc<Mutex<Vec<usize>>>
+    }
+
+    impl AsyncRead for WriteRecorder {
+        fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for WriteRecorder {
+        fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+            self.writes.lock().unwrap().push(buf.len());
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
         }
+    }
+
+    #[tokio::test]
+    async fn start_splits_the_buffer_by_its_own_length() {
+        // After a reload raises the buffer size, connections still pump through the buffers they leased before.
+        let config = Config { buffer_size: 4096, buffer_up_percent: 20, ..Config::default() };
+        let (client, mut client_peer) = duplex(64);
+        let (endpoint, mut endpoint_peer) = duplex(64);
+        let writes = Arc::new(Mutex::new(Vec::new()));
+        let endpoint = WriteRecorder { inner: endpoint, writes: writes.clone() };
+        let mut buffer = [0u8; 32];
+
+        // Queue everything up front, so each read fills the whole upload half (20% of 32 bytes).
+        client_peer.write_all(&[7u8; 16]).await.unwrap();
+        drop(client_peer);
+
+        let pump = tokio::spawn(async move {
+            CopyPump::from(client, endpoint, &mut buffer, config.buffer_up_percent, 1000, None).start().await.0
+        });
+
+        let mut up = Vec::new();
+        endpoint_peer.read_to_end(&mut up).await.unwrap();
+        drop(endpoint_peer);
 
-        Ok(())
+        assert_eq!(vec![7u8; 16], up);
+        assert_eq!(16, pump.await.unwrap().bytes_up);
+        assert_eq!(vec![6, 6, 4], *writes.lock().unwrap());
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn start_times_out_when_idle() {
+        let (client, _client_peer) = duplex(64);
+        let (endpoint, _endpoint_peer) = duplex(64);
+        let mut buffer = [0u8; 32];
+
+        let (_, result) = CopyPump::from(client, endpoint, &mut buffer, 50, 50, None).start().await;
+
+        assert!(result.unwrap_err().is::<TimeoutError>());
+    }
+}
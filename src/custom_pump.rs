@@ -1,106 +1,143 @@
-use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpStream, tcp::{OwnedReadHalf, OwnedWriteHalf}}, time::sleep};
-use tokio::net::tcp::{ReadHalf, WriteHalf};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, Duration, Instant};
+
+use crate::activity::Transfer;
+use crate::helpers::{IntoError, Res, Void, TimeoutError};
+use crate::rate_limit::TokenBucket;
+
+// Selects which pump moves the data once a connection is established.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PumpMode {
+    // Copies in each direction, timing out when the whole connection is idle.
+    Copy,
+    // Copies in each direction, timing out when either direction goes a full timeout without a read.
+    Custom
+}
 
-use tokio::time::{Duration};
-use tokio::sync::oneshot::{channel, Sender, Receiver};
-use futures::{future::Either, pin_mut};
+impl std::fmt::Display for PumpMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PumpMode::Copy => write!(f, "copy"),
+            PumpMode::Custom => write!(f, "custom")
+        }
+    }
+}
 
-use log::trace;
-use log::error;
+impl std::str::FromStr for PumpMode {
+    type Err = Box<dyn std::error::Error>;
 
-pub struct CustomPump<'a> {
-    id: &'a str,
-    client_socket: TcpStream,
-    endpoint_socket: TcpStream,
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "copy" => Ok(PumpMode::Copy),
+            "custom" => Ok(PumpMode::Custom),
+            _ => format!("Unknown pump mode `{}` (expected `copy` or `custom`).", s).into_error()
+        }
+    }
+}
+
+pub struct CustomPump<'a, C, E> {
+    client_socket: C,
+    endpoint_socket: E,
     buffer: &'a mut [u8],
-    read_timeout: u64
+    // The percentage of the buffer for uploads (the rest is for downloads).
+    up_percent: u8,
+    read_timeout: u64,
+    write_timeout: u64,
+    rate_limit: Option<u64>
 }
 
-impl<'a> CustomPump<'a> {
-    pub fn from(id: &'a str, client_socket: TcpStream, endpoint_socket: TcpStream, buffer: &'a mut [u8], read_timeout: u64) -> Self {
-        CustomPump { id, client_socket, endpoint_socket, buffer, read_timeout }
+impl<'a, C, E> CustomPump<'a, C, E>
+    where C: AsyncRead + AsyncWrite + Unpin, E: AsyncRead + AsyncWrite + Unpin
+{
+    pub fn from(client_socket: C, endpoint_socket: E, buffer: &'a mut [u8], up_percent: u8, read_timeout: u64, write_timeout: u64, rate_limit: Option<u64>) -> Self {
+        CustomPump { client_socket, endpoint_socket, buffer, up_percent, read_timeout, write_timeout, rate_limit }
     }
 
-    pub async fn start(mut self) {
-        self.run_pumps_custom().await;
+    // The transfer counts are reported even when the pump ends with an error.
+    pub async fn start(self) -> (Transfer, Res<()>) {
+        self.run_pumps_custom().await
     }
 
-    async fn run_pumps_custom(mut self) {
-        // Split the buffer.
-        let buffer_size = self.buffer.len();
-        let (buffer_up, buffer_down) = self.buffer.split_at_mut(buffer_size / 2);
+    async fn run_pumps_custom(self) -> (Transfer, Res<()>) {
+        // Split the buffer between the directions per the config (by its own length, since the buffer size may have been reloaded).
+        let up_size = self.buffer.len() * self.up_percent as usize / 100;
+        let (buffer_up, buffer_down) = self.buffer.split_at_mut(up_size);
 
         // Split the sockets.
-        let (client_socket_read, client_socket_write) = self.client_socket.into_split();
-        let (endpoint_socket_read, endpoint_socket_write) = self.endpoint_socket.into_split();
+        let (mut client_socket_read, mut client_socket_write) = tokio::io::split(self.client_socket);
+        let (mut endpoint_socket_read, mut endpoint_socket_write) = tokio::io::split(self.endpoint_socket);
 
-        // Create cancellation channels.
-        let (client_cancellation_sender, client_cancellation_receiver) = channel::<bool>();
-        let (endpoint_cancellation_sender, endpoint_cancellation_receiver) = channel::<bool>();
+        let mut transfer = Transfer::default();
 
-        // FYI: Cancellation senders are moved because this is a one-shot channel.  The sender can only send
-        // once, and the object is moved when calling the send method.
+        let result = {
+            let pump_up = Self::pump(&mut client_socket_read, &mut endpoint_socket_write, buffer_up, &mut transfer.bytes_up, self.read_timeout, self.write_timeout, self.rate_limit);
+            let pump_down = Self::pump(&mut endpoint_socket_read, &mut client_socket_write, buffer_down, &mut transfer.bytes_down, self.read_timeout, self.write_timeout, self.rate_limit);
 
-        // Run the pumps.
-        let pump_up = CustomPump::run_pump(&self.id, "up", client_socket_read, endpoint_socket_write, client_cancellation_sender, endpoint_cancellation_receiver, buffer_up, self.read_timeout);
-        let pump_down = CustomPump::run_pump(&self.id, "down", endpoint_socket_read, client_socket_write, endpoint_cancellation_sender, client_cancellation_receiver, buffer_down, self.read_timeout);
+            // Each direction runs until its own EOF, so a half-closed connection keeps flowing the other way.
+            futures::future::try_join(pump_up, pump_down).await.map(|_| ())
+        };
 
-        futures::future::join(pump_up, pump_down).await;
+        (transfer, result)
     }
 
-    async fn run_pump(
-        id: &str,
-        direction: &str,
-        mut from: OwnedReadHalf, 
-        mut to: OwnedWriteHalf, 
-        cancel_sender: Sender<bool>, 
-        mut cancel_receiver: Receiver<bool>, 
-        mut buffer: &mut [u8], 
-        read_timeout: u64
-    ) {
+    async fn pump<R, W>(from: &mut R, to: &mut W, buffer: &mut [u8], total: &mut u64, read_timeout: u64, write_timeout: u64, rate_limit: Option<u64>) -> Void
+        where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+    {
+        let mut bucket = rate_limit.map(TokenBucket::new);
+        let (read_timeout, write_timeout) = (Duration::from_millis(read_timeout), Duration::from_millis(write_timeout));
+
+        // The timers are reset for each read and write (rather than created anew), so that a large buffer's reads are
+        // all copying.
+        let read_deadline = sleep(read_timeout);
+        let write_deadline = sleep(write_timeout);
+        tokio::pin!(read_deadline, write_deadline);
+
         loop {
-            let mut read_fut = from.read(buffer);
-            let mut timeout_fut = sleep(Duration::from_millis(read_timeout));
-
-            pin_mut!(read_fut);
-            pin_mut!(timeout_fut);
-
-            // Read or timeout.
-            let select_future = futures::future::select(
-                read_fut,
-                timeout_fut
-            ).await;
-            
-            // If we read successfully, write.
-            if let Either::Left((Ok(read), _)) = select_future {
-                // Reading 0 bytes is a close, and a write error is a receiver close.  Notify and return.
-                if read == 0 {
-                    trace!("[{}] Read {} bytes while pumping {}, closing.", id, read, direction);
-
-                    cancel_sender.send(true).unwrap_or_default();
-                    return;
-                }
-                
-                if let Err(err) = to.write_all(&buffer[..read]).await {
-                    error!("[{}] Failed to write {} {} bytes of data, closing: {}", id, direction, read, err);
-                    return;
-                }
-
-                if let Err(err) = to.flush().await {
-                    error!("[{}] Failed to flush {} {} bytes of data, closing: {}", id, direction, read, err);
-                    return;
-                }
-
-                trace!("[{}] Pumped {} {} bytes of data: {:x?}.", id, direction, read, &buffer[0..10]);
-            } else if let Either::Left((Err(err), _)) = select_future {
-                error!("[{}] Failed to read {}: {}.", id, direction, err);
-                return;
+            read_deadline.as_mut().reset(Instant::now() + read_timeout);
+
+            let read = tokio::select! {
+                read = from.read(buffer) => read?,
+                _ = &mut read_deadline => return Err(TimeoutError::boxed("Timed out."))
+            };
+
+            // Reading 0 bytes is a close, so pass the EOF along to the other side (which may already be gone).
+            if read == 0 {
+                to.shutdown().await.unwrap_or_default();
+                return Ok(());
             }
 
-            //Return if other thread has cancelled.
-            if cancel_receiver.try_recv().unwrap_or(false) {
-                return;
+            if let Some(bucket) = &mut bucket {
+                bucket.take(read as u64).await;
             }
+
+            // A peer that stops reading would otherwise block the write (and hold the connection) forever.
+            write_deadline.as_mut().reset(Instant::now() + write_timeout);
+
+            tokio::select! {
+                written = to.write_all(&buffer[..read]) => written?,
+                _ = &mut write_deadline => return Err(TimeoutError::boxed("Timed out writing."))
+            };
+            *total += read as u64;
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn pump_times_out_a_peer_that_stops_reading() {
+        let (client, mut client_peer) = tokio::io::duplex(1024);
+        // The endpoint's peer is kept open, but never reads (so its small buffer fills up).
+        let (endpoint, _endpoint_peer) = tokio::io::duplex(16);
+        let mut buffer = vec![0u8; 1024];
+
+        client_peer.write_all(&[1u8; 512]).await.unwrap();
+
+        let (transfer, result) = CustomPump::from(client, endpoint, &mut buffer, 50, 10_000, 50, None).start().await;
+
+        assert!(result.err().unwrap().is::<TimeoutError>());
+        assert_eq!(0, transfer.bytes_up);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::helpers::{Res, IntoError};
 
 pub struct Handshake {
     pub version: u8,
@@ -6,11 +7,67 @@ pub struct Handshake {
 }
 
 impl Handshake {
-    pub fn from_data(data: &[u8]) -> Handshake {
+    pub fn from_data(data: &[u8]) -> Res<Handshake> {
+        if data.len() < 2 {
+            return "The handshake is too short.".into_error();
+        }
+
         let version = data[0];
         let num_methods = data[1];
-        let methods = data[2..(2 + usize::from(num_methods))].to_vec();
+        let methods = match data.get(2..(2 + usize::from(num_methods))) {
+            Some(m) => m.to_vec(),
+            None => return "The handshake is shorter than its number of methods.".into_error()
+        };
+
+        Ok(Handshake { version, num_methods, methods })
+    }
+
+    // Names the protocol that a (misconfigured) client is speaking instead of SOCKS5, if it is a common one.
+    pub fn identify_other_protocol(data: &[u8]) -> Option<&'static str> {
+        const HTTP_METHODS: [&[u8]; 8] = [b"GET ", b"POST ", b"HEAD ", b"PUT ", b"DELETE ", b"OPTIONS ", b"PATCH ", b"CONNECT "];
+
+        match data {
+            _ if HTTP_METHODS.iter().any(|m| data.starts_with(m)) => Some("HTTP"),
+            [0x16, 0x03, ..] => Some("TLS"),
+            [b'S', b'S', b'H', b'-', ..] => Some("SSH"),
+            [0x04, ..] => Some("SOCKS4"),
+            _ => None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
 
-        Handshake { version, num_methods, methods }
+    #[test]
+    fn from_data_parses_methods() {
+        let handshake = Handshake::from_data(&[5, 2, 0, 2]).unwrap();
+
+        assert_eq!(5, handshake.version);
+        assert_eq!(vec![0, 2], handshake.methods);
+    }
+
+    #[test]
+    fn from_data_rejects_truncated() {
+        assert!(Handshake::from_data(&[5]).is_err());
+        assert!(Handshake::from_data(&[5, 3, 0]).is_err());
+    }
+
+    #[test]
+    fn identify_other_protocol_recognizes_common_mistakes() {
+        assert_eq!(Some("HTTP"), Handshake::identify_other_protocol(b"GET / HTTP/1.1\r\n"));
+        assert_eq!(Some("HTTP"), Handshake::identify_other_protocol(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+        assert_eq!(Some("TLS"), Handshake::identify_other_protocol(&[0x16, 0x03, 0x01, 0x02, 0x00]));
+        assert_eq!(Some("SOCKS4"), Handshake::identify_other_protocol(&[0x04, 0x01, 0x00, 0x50]));
+        assert_eq!(None, Handshake::identify_other_protocol(&[0x05, 0x01, 0x00]));
+    }
+
+    proptest! {
+        #[test]
+        fn from_data_never_panics(data in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let _ = Handshake::from_data(&data);
+        }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,53 @@
+use std::time::Instant;
+use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use log::{info, warn};
+
+use crate::helpers::Void;
+use crate::metrics::{self, METRICS};
+
+// Answers every request with `200 OK` until the process exits.  It runs on its own listener (and task), so it keeps
+// answering even if the SOCKS accept loop is stuck; probes that need more can compare the reported counts over time.
+pub async fn serve(listener: TcpListener, started: Instant) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving health checks on http://{}/ ... ", addr);
+    }
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((s, _)) => s,
+            Err(e) => {
+                warn!("Could not accept a health check connection.  {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream, started).await {
+                warn!("Could not serve a health check.  {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, started: Instant) -> Void {
+    // The request does not matter (any path is a health check), but read it so the client sees a clean close.
+    let mut buffer = [0u8; 1024];
+    let _ = stream.read(&mut buffer).await?;
+
+    metrics::write_response(&mut stream, "200 OK", "text/plain", &status(METRICS.connections_active(), started.elapsed().as_secs())).await
+}
+
+fn status(active_connections: u64, uptime_secs: u64) -> String {
+    format!("status: ok\nactive_connections: {}\nuptime_secs: {}\n", active_connections, uptime_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn status_reports_connections_and_uptime() {
+        assert_eq!("status: ok\nactive_connections: 3\nuptime_secs: 42\n", status(3, 42));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{fmt::Formatter, net::SocketAddr};
+use std::{convert::TryInto, fmt::Formatter, net::SocketAddr};
 use std::fmt::Display;
 use std::error::Error;
 use rand::{self, Rng};
@@ -20,69 +20,138 @@ impl Cidr {
             Cidr::V6(_, mask) => *mask == 0
         }
     }
+
+    // Unlike `Helpers::is_ip_in_cidr`, a family mismatch is simply not a match.
+    pub fn contains(&self, ip_addr: &IpAddr) -> bool {
+        match (self, ip_addr) {
+            (Cidr::V4(prefix, mask), IpAddr::V4(ip)) => u32::from(*ip) & mask == *prefix,
+            (Cidr::V6(prefix, mask), IpAddr::V6(ip)) => u128::from(*ip) & mask == *prefix,
+            _ => false
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum Family {
+    V4,
+    V6
+}
+
+impl Family {
+    pub fn matches(self, ip: &IpAddr) -> bool {
+        match self {
+            Family::V4 => ip.is_ipv4(),
+            Family::V6 => ip.is_ipv6()
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Family::V4 => "IPv4",
+            Family::V6 => "IPv6"
+        }
+    }
+}
+
+// An inclusive range of local ports for outbound sockets, given as `start-end` (or a single port).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16
+}
+
+impl PortRange {
+    pub fn port_count(self) -> usize {
+        (self.end - self.start) as usize + 1
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl std::str::FromStr for PortRange {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Res<Self> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+
+        let (start, end) = match (start.trim().parse::<u16>(), end.trim().parse::<u16>()) {
+            (Ok(start), Ok(end)) => (start, end),
+            _ => return format!("The port range `{}` must be `start-end` (or a single port).", s).into_error()
+        };
+
+        if start == 0 || start > end {
+            return format!("The port range `{}` must be non-zero, and start at or below its end.", s).into_error();
+        }
+
+        Ok(PortRange { start, end })
+    }
 }
 
-pub struct EndpointPair {
-    pub socket: TcpSocket,
-    pub address: SocketAddr
+// Renders back to `address/mask-length` form (with the host bits cleared).
+impl Display for Cidr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Cidr::V4(prefix, mask) => write!(f, "{}/{}", Ipv4Addr::from(*prefix), mask.count_ones()),
+            Cidr::V6(prefix, mask) => write!(f, "{}/{}", Ipv6Addr::from(*prefix), mask.count_ones())
+        }
+    }
 }
 
 pub struct Helpers;
 
 impl Helpers {
-    pub fn get_id() -> String {
+    // Ids are random alphanumerics, so eight (8) characters give ~2e14 combinations.
+    pub fn get_id(length: usize) -> String {
         rand::thread_rng()
             .sample_iter(&Alphanumeric)
-            .take(4)
+            .take(length)
             .collect::<String>()
     }
 
     pub fn bytes_to_port(data: &[u8]) -> Res<u16> {
-        if data.len() != 2 {
-            return "There must be exactly two (2) bytes for a conversion to a port.".into_error();
+        match data.try_into() {
+            Ok(bytes) => Ok(u16::from_be_bytes(bytes)),
+            Err(_) => "There must be exactly two (2) bytes for a conversion to a port.".into_error()
         }
-
-        Ok(((data[0] as u16) << 8) + (data[1] as u16))
     }
 
     pub fn port_to_bytes(port: u16) -> (u8, u8) {
-        ((port >> 8) as u8, (port & 0xff) as u8)
+        let [high, low] = port.to_be_bytes();
+
+        (high, low)
     }
 
     pub fn slice_to_u32(data: &[u8]) -> Res<u32> {
-        if data.len() != 4 {
-            return "There must be exactly four (4) bytes for a conversion to an IPv4.".into_error();
+        match data.try_into() {
+            Ok(bytes) => Ok(u32::from_be_bytes(bytes)),
+            Err(_) => "There must be exactly four (4) bytes for a conversion to an IPv4.".into_error()
         }
-
-        Ok(((data[0] as u32) << 24) +
-                  ((data[1] as u32) << 16) +
-                  ((data[2] as u32) <<  8) +
-                   (data[3] as u32))
     }
 
     pub fn slice_to_u128(data: &[u8]) -> Res<u128> {
-        if data.len() != 16 {
-            return "There must be exactly sixteen (16) bytes for a conversion to an IPv6.".into_error();
-        }
-
-        Ok(((data[0] as u128) << 120) +
-                  ((data[1] as u128) << 112) +
-                  ((data[2] as u128) << 104) +
-                  ((data[3] as u128) <<  96) +
-                  ((data[4] as u128) <<  88) +
-                  ((data[5] as u128) <<  80) +
-                  ((data[6] as u128) <<  72) +
-                  ((data[7] as u128) <<  64) +
-                  ((data[8] as u128) <<  56) +
-                  ((data[9] as u128) <<  48) +
-                 ((data[10] as u128) <<  40) +
-                 ((data[11] as u128) <<  32) +
-                 ((data[12] as u128) <<  24) +
-                 ((data[13] as u128) <<  16) +
-                 ((data[14] as u128) <<   8) +
-                  (data[15] as u128))
+        match data.try_into() {
+            Ok(bytes) => Ok(u128::from_be_bytes(bytes)),
+            Err(_) => "There must be exactly sixteen (16) bytes for a conversion to an IPv6.".into_error()
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn get_socks_reply(error: i32) -> u8 {
+        match error {
+            0 =>                                     0x00, // succeeded
+            libc::ENETUNREACH | libc::ENETDOWN =>    0x03, // Network unreachable
+            libc::EHOSTUNREACH | libc::EHOSTDOWN =>  0x04, // Host unreachable
+            libc::ECONNREFUSED =>                    0x05, // Connection refused
+            libc::ETIMEDOUT =>                       0x06, // TTL expired (connection timed out)
+            _ =>                                     0x01  // general SOCKS server failure
+        }
     }
 
+    #[cfg(windows)]
     pub fn get_socks_reply(error: i32) -> u8 {
         match error {
             0 =>                     0x00, // succeeded
@@ -94,54 +163,106 @@ impl Helpers {
         }
     }
 
+    // Matches a host against a pattern, where `*.example.com` matches any subdomain of `example.com` (but not `example.com` itself).
+    pub fn is_host_match(pattern: &str, host: &str) -> bool {
+        let pattern = pattern.trim_end_matches('.').to_ascii_lowercase();
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+
+        match pattern.strip_prefix("*.") {
+            Some(suffix) => host.len() > suffix.len() + 1 && host.ends_with(&suffix) && host[..host.len() - suffix.len()].ends_with('.'),
+            None => host == pattern
+        }
+    }
+
+    pub fn join<T: Display>(items: &[T]) -> String {
+        items.iter().map(|i| i.to_string()).collect::<Vec<String>>().join(", ")
+    }
+
+    // Formats `host:port`, bracketing IPv6 literals (e.g., `[::1]:1080`).
+    pub fn host_port(host: &str, port: u16) -> String {
+        if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) }
+    }
+
     pub fn write_octets(buffer: &mut [u8], octets: &[u8]) {
         buffer[..octets.len()].clone_from_slice(octets);
     }
 
-    pub fn get_interface_ip(name: &str) -> Res<IpAddr> {
-        for iface in datalink::interfaces() {
-            if iface.name == name {
-                if iface.ips.is_empty() {
-                    return format!("Found interface `{}`, but could not find an assigned IP for that interface.", name).into_error();
-                }   
+    // Picks the first usable IP on the interface (of the given family, if any).
+    pub fn get_interface_ip(name: &str, family: Option<Family>) -> Res<IpAddr> {
+        let ips = Helpers::get_interface_ips(name)?;
 
-                return Ok(iface.ips[0].ip());
-            }
+        // There is at least one usable IP, so only a family mismatch can find nothing.
+        match ips.into_iter().find(|i| family.is_none_or(|f| f.matches(i))) {
+            Some(ip) => Ok(ip),
+            None => format!("Found interface `{}`, but could not find a usable {} IP for that interface.", name, family.map_or("", |f| f.name())).into_error()
         }
-
-        format!("Could not lookup IP for interface `{}`.", name).into_error()
     }
 
-    pub fn mask_ipv4(ip: &Ipv4Addr, mask: u32) -> Res<u32> {
-        Ok(Helpers::slice_to_u32(&ip.octets())? & mask) 
+    // Lists the usable IPs on the interface (skipping link-local addresses, and loopback addresses unless the interface is a loopback).
+    pub fn get_interface_ips(name: &str) -> Res<Vec<IpAddr>> {
+        let iface = match datalink::interfaces().into_iter().find(|i| i.name == name) {
+            Some(i) => i,
+            None => return format!("Could not lookup IPs for interface `{}`.", name).into_error()
+        };
+
+        let ips = iface.ips.iter().map(|i| i.ip()).filter(|i| Helpers::is_usable_ip(i, iface.is_loopback())).collect::<Vec<_>>();
+
+        if ips.is_empty() {
+            return format!("Found interface `{}`, but could not find a usable IP (not link-local or loopback) for that interface.", name).into_error();
+        }
+
+        Ok(ips)
     }
 
-    pub fn mask_ipv6(ip: &Ipv6Addr, mask: u128) -> Res<u128> {
-        Ok(Helpers::slice_to_u128(&ip.octets())? & mask) 
+    pub fn is_usable_ip(ip: &IpAddr, allow_loopback: bool) -> bool {
+        let is_link_local = match ip {
+            IpAddr::V4(ipv4) => ipv4.is_link_local(),
+            IpAddr::V6(ipv6) => ipv6.segments()[0] & 0xffc0 == 0xfe80
+        };
+
+        !ip.is_unspecified() && !is_link_local && (allow_loopback || !ip.is_loopback())
     }
 
     pub fn is_ip_in_cidr(ip_addr: &IpAddr, cidr: &Cidr) -> Res<bool> {
-        match cidr {
-            Cidr::V4(prefix, mask) => {
-                match &ip_addr {
-                    IpAddr::V4(ip) => Ok(Helpers::mask_ipv4(ip, *mask)? == *prefix),
-                    _ => Err(Box::new(GenericError::from("Cannot check IPv6 addresses against IPv4 CIDRs.")))
-                }
-            },
-            Cidr::V6(prefix, mask) => {
-                match &ip_addr {
-                    IpAddr::V6(ip) => Ok(Helpers::mask_ipv6(ip, *mask)? == *prefix),
-                    _ => Err(Box::new(GenericError::from("Cannot check IPv4 addresses against IPv6 CIDRs."))),
-                }
-            }
+        match (cidr, ip_addr) {
+            (Cidr::V4(_, _), IpAddr::V6(_)) => "Cannot check IPv6 addresses against IPv4 CIDRs.".into_error(),
+            (Cidr::V6(_, _), IpAddr::V4(_)) => "Cannot check IPv4 addresses against IPv6 CIDRs.".into_error(),
+            _ => Ok(cidr.contains(ip_addr))
         }
     }
 
+    // Parses a comma-separated list of CIDRs (e.g., `10.0.0.0/8,192.168.0.0/16`), where a single CIDR is a list of one.
+    pub fn parse_cidr_list(s: &str) -> Res<Vec<Cidr>> {
+        s.split(',').map(Helpers::parse_cidr).collect()
+    }
+
     pub fn parse_cidr(s: &str) -> Res<Cidr> {
+        let s = s.trim();
+
+        if s.is_empty() {
+            return "A CIDR cannot be empty.".into_error();
+        }
+
         let splits = s.split('/').collect::<Vec<&str>>();
 
-        let ip_addr = splits[0].parse::<IpAddr>()?;
-        let num_mask_bits = splits[1].parse::<u32>()?;
+        if splits.len() > 2 {
+            return format!("The CIDR `{}` must contain at most one (1) slash.", s).into_error();
+        }
+
+        let ip_addr = match splits[0].parse::<IpAddr>() {
+            Ok(ip) => ip,
+            Err(_) => return format!("The CIDR `{}` does not start with a valid IP address.", s).into_error()
+        };
+
+        // A missing mask is a host route.
+        let num_mask_bits = match splits.get(1) {
+            Some(m) => match m.parse::<u32>() {
+                Ok(m) => m,
+                Err(_) => return format!("The CIDR `{}` must have a numeric mask bit length.", s).into_error()
+            },
+            None if ip_addr.is_ipv4() => 32,
+            None => 128
+        };
 
         match ip_addr {
             IpAddr::V4(ip) => {
@@ -156,7 +277,7 @@ impl Helpers {
             },
             IpAddr::V6(ip) => {
                 if num_mask_bits > 128 {
-                    return Err(Box::new(GenericError::from("An IPv4 CIDR prefix must have a mask bit length less than or equal to 128.")));
+                    return Err(Box::new(GenericError::from("An IPv6 CIDR prefix must have a mask bit length less than or equal to 128.")));
                 }
 
                 let mask = !(2u128.overflowing_pow(128 - num_mask_bits).0.overflowing_sub(1).0);
@@ -167,35 +288,131 @@ impl Helpers {
         }
     }
 
-    pub fn create_local_socket(local_addr: SocketAddr, mut endpoint_addresses: impl Iterator<Item = SocketAddr>) -> Option<EndpointPair> {
-        let is_endpoint_interface_ipv6 = local_addr.is_ipv6();
-
-        let endpoint_addr = if is_endpoint_interface_ipv6 {
-            endpoint_addresses
-                .find(|a| a.is_ipv6())
+    pub fn get_compatible_addresses(local_ips: &[IpAddr], endpoint_addresses: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+        // No local IPs means any local IP will do; otherwise, there must be a local IP of the endpoint's family.
+        if local_ips.is_empty() {
+            endpoint_addresses.collect()
         } else {
-            endpoint_addresses
-                .find(|a| a.is_ipv4())
+            endpoint_addresses.filter(|a| local_ips.iter().any(|i| i.is_ipv6() == a.is_ipv6())).collect()
+        }
+    }
+
+    // Names the address families among the IPs (e.g., for a log of why no address was compatible).
+    pub fn families(ips: impl Iterator<Item = IpAddr>) -> &'static str {
+        let (mut ipv4, mut ipv6) = (false, false);
+
+        for ip in ips {
+            if ip.is_ipv6() { ipv6 = true } else { ipv4 = true }
+        }
+
+        match (ipv4, ipv6) {
+            (true, true) => "IPv4 and IPv6",
+            (true, false) => "IPv4",
+            (false, true) => "IPv6",
+            (false, false) => "no"
+        }
+    }
+
+    // Alternates the address families, starting with the family of the first (most preferred) address, as RFC 8305 suggests.
+    pub fn interleave_families(endpoint_addresses: Vec<SocketAddr>) -> Vec<SocketAddr> {
+        let first_is_ipv6 = match endpoint_addresses.first() {
+            Some(a) => a.is_ipv6(),
+            None => return endpoint_addresses
+        };
+
+        let (preferred, other): (Vec<_>, Vec<_>) = endpoint_addresses.into_iter().partition(|a| a.is_ipv6() == first_is_ipv6);
+        let mut interleaved = Vec::with_capacity(preferred.len() + other.len());
+        let mut preferred = preferred.into_iter();
+        let mut other = other.into_iter();
+
+        loop {
+            match (preferred.next(), other.next()) {
+                (None, None) => return interleaved,
+                (a, b) => interleaved.extend(a.into_iter().chain(b))
+            }
+        }
+    }
+
+    // Without a source port range, the system picks an ephemeral port.  With one, the ports are tried in turn (from a random
+    // starting point, so that concurrent connections do not all contend for the first port), skipping any that are in use.
+    // Once every port in the range is in use, this fails with `AddrInUse`.
+    pub fn create_local_socket(local_ips: &[IpAddr], endpoint_addr: &SocketAddr, source_ports: Option<PortRange>, device: Option<&str>) -> std::io::Result<TcpSocket> {
+        let local_addr = Helpers::get_local_addr_for(local_ips, endpoint_addr);
+
+        let new_socket = || {
+            let socket = if endpoint_addr.is_ipv4() {
+                TcpSocket::new_v4()
+            } else {
+                TcpSocket::new_v6()
+            }?;
+
+            if let Some(device) = device {
+                Helpers::bind_device(&socket, device)?;
+            }
+
+            Ok::<_, std::io::Error>(socket)
         };
 
-        let endpoint_addr = match endpoint_addr {
-            Some(addr) => addr,
-            None => return None
+        let range = match source_ports {
+            Some(r) => r,
+            None => {
+                let socket = new_socket()?;
+                socket.bind(local_addr)?;
+
+                return Ok(socket);
+            }
         };
 
-        // Bind to requested local address.
-        let socket = if endpoint_addr.is_ipv4() {
-            TcpSocket::new_v4().ok()?
+        let offset = rand::thread_rng().gen_range(0, range.port_count());
+
+        for i in 0..range.port_count() {
+            let port = range.start + ((offset + i) % range.port_count()) as u16;
+
+            // A failed bind leaves the socket unusable, so each attempt needs a new one.
+            let socket = new_socket()?;
+
+            match socket.bind(SocketAddr::new(local_addr.ip(), port)) {
+                Ok(()) => return Ok(socket),
+                Err(e) if e.kind() == std::io::ErrorKind::AddrInUse => continue,
+                Err(e) => return Err(e)
+            }
+        }
+
+        Err(std::io::Error::new(std::io::ErrorKind::AddrInUse, format!("Every source port in `{}` is in use.", range)))
+    }
+
+    // Binds the socket to a device (`SO_BINDTODEVICE`), so that it egresses that interface whatever the routing table says.
+    #[cfg(target_os = "linux")]
+    fn bind_device(socket: &TcpSocket, device: &str) -> std::io::Result<()> {
+        socket2::SockRef::from(socket).bind_device(Some(device.as_bytes()))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn bind_device(_socket: &TcpSocket, device: &str) -> std::io::Result<()> {
+        Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("Cannot bind to the device `{}`, because binding to a device is only supported on Linux.", device)))
+    }
+
+    // Renders bytes as space-separated hex, truncated to `limit` bytes (with a note of how many were left out).
+    pub fn hexdump(data: &[u8], limit: usize) -> String {
+        let shown = data.iter().take(limit).map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ");
+
+        match data.len().checked_sub(limit) {
+            Some(more) if more > 0 => format!("{} ... ({} more)", shown, more),
+            _ => shown
+        }
+    }
+
+    // Picks the first local IP of the endpoint's family (or the unspecified address of that family, if there is none).
+    pub fn get_local_addr_for(local_ips: &[IpAddr], endpoint_addr: &SocketAddr) -> SocketAddr {
+        let unspecified = if endpoint_addr.is_ipv6() {
+            IpAddr::V6(Ipv6Addr::UNSPECIFIED)
         } else {
-            TcpSocket::new_v6().ok()?
+            IpAddr::V4(Ipv4Addr::UNSPECIFIED)
         };
 
-        socket.bind(local_addr).ok()?;
+        let local_ip = local_ips.iter().find(|i| i.is_ipv6() == endpoint_addr.is_ipv6()).copied().unwrap_or(unspecified);
 
-        Some(EndpointPair {
-            socket,
-            address: endpoint_addr
-        })
+        SocketAddr::new(local_ip, 0)
     }
 }
 
@@ -237,4 +454,303 @@ impl Error for GenericError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         None
     }
-}
\ No newline at end of file
+}
+
+// Distinguishes timeouts from other failures (e.g., when reporting how a connection ended).
+#[derive(Debug)]
+pub struct TimeoutError {
+    message: String
+}
+
+impl TimeoutError {
+    pub fn boxed<T>(message: T) -> Box<dyn Error>
+        where T: ToString
+    {
+        Box::new(TimeoutError { message: message.to_string() })
+    }
+}
+
+impl Display for TimeoutError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for TimeoutError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn port_round_trips_through_bytes() {
+        for port in [0u16, 1, 80, 255, 256, 1080, 0x1234, u16::MAX] {
+            let (high, low) = Helpers::port_to_bytes(port);
+
+            assert_eq!(port, Helpers::bytes_to_port(&[high, low]).unwrap());
+        }
+    }
+
+    #[test]
+    fn bytes_to_port_is_big_endian() {
+        assert_eq!(0x0438, Helpers::bytes_to_port(&[0x04, 0x38]).unwrap());
+        assert_eq!((0x04, 0x38), Helpers::port_to_bytes(1080));
+    }
+
+    #[test]
+    fn conversions_reject_wrong_lengths() {
+        assert!(Helpers::bytes_to_port(&[1]).is_err());
+        assert!(Helpers::bytes_to_port(&[1, 2, 3]).is_err());
+        assert!(Helpers::slice_to_u32(&[1, 2, 3]).is_err());
+        assert!(Helpers::slice_to_u128(&[0; 15]).is_err());
+    }
+
+    #[test]
+    fn slice_conversions_match_std_addresses() {
+        let ipv4 = Ipv4Addr::new(192, 168, 1, 2);
+        let ipv6 = "2001:db8::1".parse::<Ipv6Addr>().unwrap();
+
+        assert_eq!(u32::from(ipv4), Helpers::slice_to_u32(&ipv4.octets()).unwrap());
+        assert_eq!(u128::from(ipv6), Helpers::slice_to_u128(&ipv6.octets()).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn get_socks_reply_maps_errno() {
+        assert_eq!(0x00, Helpers::get_socks_reply(0));
+        assert_eq!(0x03, Helpers::get_socks_reply(libc::ENETUNREACH));
+        assert_eq!(0x04, Helpers::get_socks_reply(libc::EHOSTUNREACH));
+        assert_eq!(0x05, Helpers::get_socks_reply(libc::ECONNREFUSED));
+        assert_eq!(0x06, Helpers::get_socks_reply(libc::ETIMEDOUT));
+        assert_eq!(0x01, Helpers::get_socks_reply(libc::EPERM));
+    }
+
+    #[test]
+    fn is_host_match_exact() {
+        assert!(Helpers::is_host_match("example.com", "example.com"));
+        assert!(Helpers::is_host_match("Example.COM.", "example.com"));
+        assert!(!Helpers::is_host_match("example.com", "www.example.com"));
+        assert!(Helpers::is_host_match("10.0.0.1", "10.0.0.1"));
+    }
+
+    #[test]
+    fn is_host_match_wildcard() {
+        assert!(Helpers::is_host_match("*.example.com", "www.example.com"));
+        assert!(Helpers::is_host_match("*.example.com", "a.b.example.com"));
+        assert!(!Helpers::is_host_match("*.example.com", "example.com"));
+        assert!(!Helpers::is_host_match("*.example.com", "badexample.com"));
+    }
+
+    #[test]
+    fn interleave_families_alternates() {
+        let addrs: Vec<SocketAddr> = vec!["[::1]:80", "[::2]:80", "[::3]:80", "10.0.0.1:80", "10.0.0.2:80"].into_iter().map(|a| a.parse().unwrap()).collect();
+        let expected: Vec<SocketAddr> = vec!["[::1]:80", "10.0.0.1:80", "[::2]:80", "10.0.0.2:80", "[::3]:80"].into_iter().map(|a| a.parse().unwrap()).collect();
+
+        assert_eq!(expected, Helpers::interleave_families(addrs));
+    }
+
+    #[test]
+    fn families_names_each_family_present() {
+        let ips = ["10.0.0.1", "::1", "10.0.0.2"].iter().map(|i| i.parse::<IpAddr>().unwrap()).collect::<Vec<_>>();
+
+        assert_eq!("IPv4 and IPv6", Helpers::families(ips.iter().copied()));
+        assert_eq!("IPv4", Helpers::families(ips.iter().copied().filter(IpAddr::is_ipv4)));
+        assert_eq!("IPv6", Helpers::families(ips.iter().copied().filter(IpAddr::is_ipv6)));
+        assert_eq!("no", Helpers::families(std::iter::empty()));
+    }
+
+    #[test]
+    fn is_usable_ip_skips_link_local_and_loopback() {
+        assert!(Helpers::is_usable_ip(&"192.168.1.10".parse().unwrap(), false));
+        assert!(Helpers::is_usable_ip(&"2001:db8::1".parse().unwrap(), false));
+        assert!(!Helpers::is_usable_ip(&"169.254.1.1".parse().unwrap(), false));
+        assert!(!Helpers::is_usable_ip(&"fe80::1".parse().unwrap(), false));
+        assert!(!Helpers::is_usable_ip(&"127.0.0.1".parse().unwrap(), false));
+        assert!(Helpers::is_usable_ip(&"127.0.0.1".parse().unwrap(), true));
+    }
+
+    #[test]
+    fn get_id_has_length() {
+        assert_eq!(8, Helpers::get_id(8).len());
+        assert!(Helpers::get_id(16).chars().all(|c| c.is_ascii_alphanumeric()));
+    }
+
+    #[test]
+    fn parse_cidr_with_mask() {
+        let cidr = Helpers::parse_cidr("10.1.2.3/8").unwrap();
+
+        assert!(matches!(cidr, Cidr::V4(0x0a00_0000, 0xff00_0000)));
+    }
+
+    #[test]
+    fn parse_cidr_without_mask_is_host_route() {
+        let v4 = Helpers::parse_cidr("10.0.0.1").unwrap();
+        let v6 = Helpers::parse_cidr("::1").unwrap();
+
+        assert!(matches!(v4, Cidr::V4(0x0a00_0001, u32::MAX)));
+        assert!(matches!(v6, Cidr::V6(1, u128::MAX)));
+    }
+
+    #[test]
+    fn parse_cidr_rejects_empty() {
+        assert!(Helpers::parse_cidr("").is_err());
+        assert!(Helpers::parse_cidr("   ").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_list_splits_on_commas() {
+        let cidrs = Helpers::parse_cidr_list("10.0.0.0/8, 192.168.0.0/16,::1").unwrap();
+        assert_eq!(vec!["10.0.0.0/8", "192.168.0.0/16", "::1/128"], cidrs.iter().map(Cidr::to_string).collect::<Vec<_>>());
+
+        assert_eq!(1, Helpers::parse_cidr_list("10.0.0.0/8").unwrap().len());
+        assert!(Helpers::parse_cidr_list("10.0.0.0/8,").is_err());
+        assert!(Helpers::parse_cidr_list("10.0.0.0/8,bad").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_non_numeric_mask() {
+        assert!(Helpers::parse_cidr("10.0.0.0/eight").is_err());
+        assert!(Helpers::parse_cidr("10.0.0.0/").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_too_many_slashes() {
+        assert!(Helpers::parse_cidr("10.0.0.0/8/8").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_bad_address() {
+        assert!(Helpers::parse_cidr("10.0.0/8").is_err());
+        assert!(Helpers::parse_cidr("/8").is_err());
+    }
+
+    #[test]
+    fn parse_cidr_rejects_oversized_mask() {
+        assert!(Helpers::parse_cidr("10.0.0.0/33").is_err());
+        assert!(Helpers::parse_cidr("::/129").is_err());
+    }
+
+    #[test]
+    fn cidr_display_round_trips() {
+        assert_eq!("10.0.0.0/8", Helpers::parse_cidr("10.1.2.3/8").unwrap().to_string());
+        assert_eq!("0.0.0.0/0", Helpers::parse_cidr("0.0.0.0/0").unwrap().to_string());
+        assert_eq!("192.168.1.1/32", Helpers::parse_cidr("192.168.1.1").unwrap().to_string());
+        assert_eq!("2001:db8::/32", Helpers::parse_cidr("2001:db8:1::1/32").unwrap().to_string());
+        assert_eq!("::1/128", Helpers::parse_cidr("::1").unwrap().to_string());
+    }
+
+    #[test]
+    fn cidr_zero_mask_contains_everything_in_family() {
+        let v4 = Helpers::parse_cidr("0.0.0.0/0").unwrap();
+        let v6 = Helpers::parse_cidr("::/0").unwrap();
+
+        assert!(v4.is_trivial());
+        assert!(v6.is_trivial());
+        assert!(Helpers::is_ip_in_cidr(&"255.255.255.255".parse().unwrap(), &v4).unwrap());
+        assert!(Helpers::is_ip_in_cidr(&"ffff::1".parse().unwrap(), &v6).unwrap());
+    }
+
+    #[test]
+    fn cidr_full_mask_contains_only_the_host() {
+        let v4 = Helpers::parse_cidr("10.0.0.1/32").unwrap();
+        let v6 = Helpers::parse_cidr("2001:db8::1/128").unwrap();
+
+        assert!(Helpers::is_ip_in_cidr(&"10.0.0.1".parse().unwrap(), &v4).unwrap());
+        assert!(!Helpers::is_ip_in_cidr(&"10.0.0.2".parse().unwrap(), &v4).unwrap());
+        assert!(Helpers::is_ip_in_cidr(&"2001:db8::1".parse().unwrap(), &v6).unwrap());
+        assert!(!Helpers::is_ip_in_cidr(&"2001:db8::2".parse().unwrap(), &v6).unwrap());
+    }
+
+    #[test]
+    fn cidr_range_boundaries() {
+        let v4 = Helpers::parse_cidr("192.168.4.0/22").unwrap();
+        let v6 = Helpers::parse_cidr("2001:db8:10::/44").unwrap();
+
+        assert!(!Helpers::is_ip_in_cidr(&"192.168.3.255".parse().unwrap(), &v4).unwrap());
+        assert!(Helpers::is_ip_in_cidr(&"192.168.4.0".parse().unwrap(), &v4).unwrap());
+        assert!(Helpers::is_ip_in_cidr(&"192.168.7.255".parse().unwrap(), &v4).unwrap());
+        assert!(!Helpers::is_ip_in_cidr(&"192.168.8.0".parse().unwrap(), &v4).unwrap());
+
+        assert!(!Helpers::is_ip_in_cidr(&"2001:db8:f:ffff::".parse().unwrap(), &v6).unwrap());
+        assert!(Helpers::is_ip_in_cidr(&"2001:db8:10::".parse().unwrap(), &v6).unwrap());
+        assert!(Helpers::is_ip_in_cidr(&"2001:db8:1f:ffff::".parse().unwrap(), &v6).unwrap());
+        assert!(!Helpers::is_ip_in_cidr(&"2001:db8:20::".parse().unwrap(), &v6).unwrap());
+    }
+
+    #[test]
+    fn cidr_family_mismatch() {
+        let v4 = Helpers::parse_cidr("0.0.0.0/0").unwrap();
+        let v6 = Helpers::parse_cidr("::/0").unwrap();
+
+        assert!(Helpers::is_ip_in_cidr(&"::1".parse().unwrap(), &v4).is_err());
+        assert!(Helpers::is_ip_in_cidr(&"127.0.0.1".parse().unwrap(), &v6).is_err());
+        assert!(!v4.contains(&"::1".parse().unwrap()));
+        assert!(!v6.contains(&"127.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn cidr_matches_ipv4_mapped_clients_once_canonical() {
+        let v4 = Helpers::parse_cidr("10.0.0.0/8").unwrap();
+        let mapped = "::ffff:10.1.2.3".parse::<IpAddr>().unwrap();
+
+        assert!(!v4.contains(&mapped));
+        assert!(v4.contains(&mapped.to_canonical()));
+    }
+
+    #[test]
+    fn host_port_brackets_ipv6() {
+        assert_eq!("example.com:443", Helpers::host_port("example.com", 443));
+        assert_eq!("10.0.0.1:80", Helpers::host_port("10.0.0.1", 80));
+        assert_eq!("[::1]:1080", Helpers::host_port("::1", 1080));
+    }
+
+    #[test]
+    fn hexdump_truncates_to_the_limit() {
+        assert_eq!("05 01 00", Helpers::hexdump(&[5, 1, 0], 3));
+        assert_eq!("05 01 ... (1 more)", Helpers::hexdump(&[5, 1, 0], 2));
+        assert_eq!("", Helpers::hexdump(&[], 2));
+    }
+
+    #[test]
+    fn port_range_parses_ranges_and_single_ports() {
+        assert_eq!(PortRange { start: 40000, end: 40999 }, "40000-40999".parse().unwrap());
+        assert_eq!(PortRange { start: 40000, end: 40000 }, "40000".parse().unwrap());
+        assert_eq!(1000, "40000-40999".parse::<PortRange>().unwrap().port_count());
+
+        assert!("0-10".parse::<PortRange>().is_err());
+        assert!("20-10".parse::<PortRange>().is_err());
+        assert!("10-".parse::<PortRange>().is_err());
+        assert!("10-70000".parse::<PortRange>().is_err());
+    }
+
+    #[tokio::test]
+    async fn create_local_socket_skips_ports_in_use() {
+        let endpoint_addr = "127.0.0.1:80".parse::<SocketAddr>().unwrap();
+        let local_ips = ["127.0.0.1".parse::<IpAddr>().unwrap()];
+
+        // Find a free port, and take it.
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let range = PortRange { start: port, end: port };
+
+        let error = Helpers::create_local_socket(&local_ips, &endpoint_addr, Some(range), None).err().unwrap();
+        assert_eq!(std::io::ErrorKind::AddrInUse, error.kind());
+
+        drop(taken);
+
+        let socket = Helpers::create_local_socket(&local_ips, &endpoint_addr, Some(range), None).unwrap();
+        assert_eq!(port, socket.local_addr().unwrap().port());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn create_local_socket_binds_the_device() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint_addr = listener.local_addr().unwrap();
+
+        let socket = Helpers::create_local_socket(&[], &endpoint_addr, None, Some("lo")).unwrap();
+        assert_eq!(Some(b"lo".to_vec()), socket2::SockRef::from(&socket).device().unwrap());
+
+        socket.connect(endpoint_addr).await.unwrap();
+    }
+}
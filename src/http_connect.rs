@@ -0,0 +1,91 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::helpers::{Res, IntoError};
+use crate::request::{Request, Destination, MAX_DOMAIN_LENGTH};
+
+// The longest request line and headers that are accepted (enough for any real CONNECT request, and bounded well below a
+// large buffer).
+pub const MAX_REQUEST_SIZE: usize = 8 * 1024;
+
+// Whether the data starts an HTTP CONNECT request (rather than a SOCKS handshake).
+pub fn is_connect(data: &[u8]) -> bool {
+    data.starts_with(b"CONNECT ")
+}
+
+// Returns the length of the request line and headers, once the blank line that ends them has arrived.
+pub fn header_length(data: &[u8]) -> Option<usize> {
+    data.windows(4).position(|w| w == b"\r\n\r\n").map(|p| p + 4)
+}
+
+// Parses `CONNECT host:port HTTP/1.x` into the equivalent SOCKS5 CONNECT request (the headers are ignored).
+pub fn parse_request(data: &[u8]) -> Res<Request> {
+    let text = std::str::from_utf8(data)?;
+    let request_line = text.lines().next().unwrap_or_default();
+
+    let target = match request_line.split(' ').collect::<Vec<_>>()[..] {
+        ["CONNECT", target, version] if version.starts_with("HTTP/1.") => target,
+        _ => return format!("The HTTP request line `{}` is not a valid CONNECT.", request_line).into_error()
+    };
+
+    let (host, port) = match target.rsplit_once(':') {
+        Some((host, port)) => (host.trim_start_matches('[').trim_end_matches(']'), port.parse::<u16>()?),
+        None => return format!("The CONNECT target `{}` has no port.", target).into_error()
+    };
+
+    let (address_type, destination) = if let Ok(ipv4) = host.parse::<Ipv4Addr>() {
+        (0x01, Destination::Ipv4Addr(ipv4))
+    } else if let Ok(ipv6) = host.parse::<Ipv6Addr>() {
+        (0x04, Destination::Ipv6Addr(ipv6))
+    } else if !host.is_empty() && host.len() <= MAX_DOMAIN_LENGTH {
+        (0x03, Destination::Domain(host.to_owned()))
+    } else {
+        return format!("The CONNECT host `{}` is not valid.", host).into_error();
+    };
+
+    Ok(Request { version: 0x05, command: 0x01, reserved: 0x00, address_type, port, destination })
+}
+
+// The response for a request that could not be parsed.
+pub const BAD_REQUEST: &str = "HTTP/1.1 400 Bad Request\r\nConnection: close\r\n\r\n";
+
+// The response for a SOCKS reply code.
+pub fn response(reply: u8) -> &'static str {
+    match reply {
+        0x00 => "HTTP/1.1 200 Connection Established\r\n\r\n",
+        0x02 => "HTTP/1.1 403 Forbidden\r\nConnection: close\r\n\r\n",
+        0x06 => "HTTP/1.1 504 Gateway Timeout\r\nConnection: close\r\n\r\n",
+        0x07 => "HTTP/1.1 405 Method Not Allowed\r\nConnection: close\r\n\r\n",
+        _ => "HTTP/1.1 502 Bad Gateway\r\nConnection: close\r\n\r\n"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_request_reads_domains_and_ips() {
+        let request = parse_request(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+        assert_eq!(("example.com".to_owned(), 443, 0x03), (request.destination.to_string(), request.port, request.address_type));
+
+        let request = parse_request(b"CONNECT 10.0.0.1:22 HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(("10.0.0.1".to_owned(), 22, 0x01), (request.destination.to_string(), request.port, request.address_type));
+
+        let request = parse_request(b"CONNECT [::1]:8080 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(("::1".to_owned(), 8080, 0x04), (request.destination.to_string(), request.port, request.address_type));
+    }
+
+    #[test]
+    fn parse_request_rejects_malformed() {
+        assert!(parse_request(b"GET / HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request(b"CONNECT example.com HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request(b"CONNECT example.com:99999 HTTP/1.1\r\n\r\n").is_err());
+        assert!(parse_request(b"CONNECT :443 HTTP/1.1\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn header_length_waits_for_the_blank_line() {
+        assert_eq!(None, header_length(b"CONNECT a:1 HTTP/1.1\r\n"));
+        assert_eq!(Some(24), header_length(b"CONNECT a:1 HTTP/1.1\r\n\r\nxy"));
+    }
+}
@@ -0,0 +1,26 @@
+#![warn(rust_2018_idioms)]
+#![warn(clippy::all)]
+
+mod connection;
+pub mod handshake;
+pub mod helpers;
+pub mod request;
+mod custom_pump;
+mod copy_pump;
+mod activity;
+mod rate_limit;
+pub mod buffer_pool;
+pub mod config;
+pub mod logger;
+pub mod server;
+mod upstream;
+mod metrics;
+mod stream;
+mod access_log;
+mod resolver;
+mod ban_list;
+mod tls;
+mod health;
+mod http_connect;
+mod auth;
+pub mod proxy_protocol;
@@ -0,0 +1,106 @@
+use std::io::Write;
+
+use log::{Log, Metadata, Record, SetLoggerError};
+use log::kv::{Error, Key, Value, VisitSource};
+use serde_json::{Map, Value as JsonValue};
+
+use crate::helpers::{Res, IntoError};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => format!("Unknown log format `{}` (expected `text` or `json`).", s).into_error()
+        }
+    }
+}
+
+pub struct Logger {
+    format: LogFormat
+}
+
+impl Logger {
+    pub fn init(format: LogFormat) -> Result<(), SetLoggerError> {
+        log::set_boxed_logger(Box::new(Logger { format }))
+    }
+
+    fn format_text(record: &Record<'_>) -> String {
+        // Only the connection id is rendered in text mode; the other fields are already part of the message.
+        let id = match record.key_values().get(Key::from_str("id")) {
+            Some(id) => format!("[{}] ", id),
+            None => String::new()
+        };
+
+        format!("{} {:<5} [{}] {}{}", timestamp(), record.level(), record.target(), id, record.args())
+    }
+
+    fn format_json(record: &Record<'_>) -> String {
+        let mut fields = Map::new();
+
+        fields.insert("timestamp".to_owned(), JsonValue::from(timestamp()));
+        fields.insert("level".to_owned(), JsonValue::from(record.level().as_str()));
+        fields.insert("target".to_owned(), JsonValue::from(record.target()));
+        fields.insert("message".to_owned(), JsonValue::from(record.args().to_string()));
+
+        let mut visitor = JsonVisitor { fields: &mut fields };
+        record.key_values().visit(&mut visitor).unwrap_or_default();
+
+        JsonValue::Object(fields).to_string()
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            LogFormat::Text => Logger::format_text(record),
+            LogFormat::Json => Logger::format_json(record)
+        };
+
+        writeln!(std::io::stdout(), "{}", line).unwrap_or_default();
+    }
+
+    fn flush(&self) {
+        std::io::stdout().flush().unwrap_or_default();
+    }
+}
+
+struct JsonVisitor<'a> {
+    fields: &'a mut Map<String, JsonValue>
+}
+
+impl<'kvs> VisitSource<'kvs> for JsonVisitor<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), Error> {
+        let value = if let Some(n) = value.to_u64() {
+            JsonValue::from(n)
+        } else if let Some(b) = value.to_bool() {
+            JsonValue::from(b)
+        } else {
+            JsonValue::from(value.to_string())
+        };
+
+        self.fields.insert(key.to_string(), value);
+
+        Ok(())
+    }
+}
+
+fn timestamp() -> String {
+    chrono::Local::now().format("%Y-%m-%d %H:%M:%S,%3f").to_string()
+}
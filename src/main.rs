@@ -1,75 +1,101 @@
 #![warn(rust_2018_idioms)]
 #![warn(clippy::all)]
-//#![feature(test)]
 
-mod connection;
-mod handshake;
-mod helpers;
-mod request;
-//mod custom_pump;
-mod copy_pump;
-mod buffer_pool;
-mod config;
+use std::sync::Arc;
+use clap::Parser;
+use log::{info, LevelFilter};
 
-use tokio::{io::AsyncWriteExt, net::TcpListener};
-use log::{info, debug, warn, LevelFilter};
+use rusty_socks::{config, server};
+use rusty_socks::helpers::Helpers;
+use rusty_socks::logger::Logger;
 
-use connection::Connection;
-use helpers::Helpers;
-use buffer_pool::BufferPool;
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Take the systemd sockets while this is the only thread (it unsets their environment variables).
+    let activation = server::Activation::take();
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args: Vec<String> = std::env::args().collect();
-    
-    // Compute config.
+    tokio::runtime::Builder::new_multi_thread().enable_all().build()?.block_on(run(activation))
+}
 
-    let config_file: Option<&str> = if args.len() == 2 {
-        Some(&args[1])
-    } else {
-        None
-    };
+async fn run(activation: server::Activation) -> Result<(), Box<dyn std::error::Error>> {
+    // Compute config.
 
-    let config = config::from_file_and_env(config_file).await?;
+    let args = config::Args::parse();
+    let config = Arc::new(config::from_args_file_and_env(args.clone()).await?);
     
-    // Set the log level.
-    simple_logger::init().unwrap();
-    log::set_max_level(LevelFilter::Info);
+    // Set the log level (a check always prints the config).
+    Logger::init(config.log_format)?;
+    log::set_max_level(if args.is_check() { config.log_level.max(LevelFilter::Info) } else { config.log_level });
     
-    info!("Version:      2.0.0");
-    info!("Listen IP:    {}", config.listen_ip);
-    info!("Endpoint IP:  {}", config.endpoint_ip);
-    info!("Port:         {}", config.port);
-    info!("Buffer Size:  {}", config.buffer_size);
-    info!("Read Timeout: {}", config.read_timeout);
-    info!("Accept CIDR:  {}", config.accept_cidr);
-
-    // Calculate the CIDR prefix and mask.
-    let cidr = Helpers::parse_cidr(&config.accept_cidr)?;
-    let cidr_is_trivial = cidr.is_trivial();
+    // The values start in a fixed column (wide enough for longer labels), so a new setting adds one line.
+    info!("Version:                      2.0.0");
+    info!("Listen IP:                    {}", config.listen_ip);
+    info!("Listen IPv6:                  {}", config.listen_ipv6);
+    info!("Dual Stack:                   {}", config.dual_stack);
+    info!("Endpoint IPs:                 {}", if config.endpoint_ips.is_empty() { "any".to_owned() } else { Helpers::join(&config.endpoint_ips) });
+    info!("Endpoint Device:              {}", config.endpoint_device.as_deref().unwrap_or("any"));
+    info!("Ports:                        {}", Helpers::join(&config.ports));
+    info!("Buffer Size:                  {}", config.buffer_size);
+    info!("Buffer Split:                 {} up / {} down", config.buffer_split().0, config.buffer_split().1);
+    info!("Idle Timeout:                 {}", config.idle_timeout);
+    info!("Handshake Timeout:            {}", config.handshake_timeout);
+    info!("Write Timeout:                {}", config.write_timeout);
+    info!("Accept CIDR:                  {}", config.accept_cidr);
+    info!("Deny CIDRs:                   {}", Helpers::join(&config.deny_cidrs));
+    info!("Max Conns:                    {}", config.max_connections);
+    info!("Max Buffers:                  {}", config.max_buffers);
+    info!("Buffer Memory:                {}", config.max_buffer_memory().map_or("unlimited".to_owned(), |m| format!("up to {} bytes", m)));
+    info!("Zero Buffers:                 {}", config.zero_buffers_on_return);
+    info!("No Delay:                     {}", config.no_delay);
+    info!("Shutdown Grace:               {}", config.shutdown_grace);
+    info!("Connect Timeout:              {}", config.connect_timeout);
+    info!("Rate Limit:                   {}", config.rate_limit_bytes_per_sec.map_or("unlimited".to_owned(), |r| r.to_string()));
+    info!("Upstream Proxy:               {}", config.upstream_proxy.as_ref().map_or("none", |u| u.address.as_str()));
+    info!("Send PROXY Header:            {}", config.send_proxy_protocol.map_or("off".to_owned(), |v| v.to_string()));
+    info!("Accept PROXY Header:          {}", config.accept_proxy_protocol);
+    info!("Trusted Proxies:              {}", Helpers::join(&config.trusted_proxies));
+    info!("Allowed Ports:                {}", Helpers::join(&config.allowed_ports));
+    info!("Denied Ports:                 {}", Helpers::join(&config.denied_ports));
+    info!("Allowed Domains:              {}", Helpers::join(&config.allowed_domains));
+    info!("Denied Domains:               {}", Helpers::join(&config.denied_domains));
+    info!("Metrics Port:                 {}", config.metrics_port.map_or("none".to_owned(), |p| p.to_string()));
+    info!("Pump Mode:                    {}", config.pump_mode);
+    info!("Reuse Port:                   {}", config.reuse_port);
+    info!("Happy Eyeballs Delay:         {}", config.happy_eyeballs_delay);
+    info!("ID Length:                    {}", config.id_length);
+    info!("Listen Unix:                  {}", config.listen_unix.as_deref().unwrap_or("none"));
+    info!("Access Log:                   {}", config.access_log.as_deref().unwrap_or("none"));
+    info!("Max Domain Length:            {}", config.max_domain_length);
+    info!("DNS Cache Size:               {}", config.dns_cache_size);
+    info!("DNS Cache TTL:                {}", config.dns_cache_ttl);
+    info!("DNS Server:                   {}", config.dns_server.map_or("system".to_owned(), |s| s.to_string()));
+    info!("Resolve Domains:              {}", config.resolve_domains);
+    info!("Ban Threshold:                {}", config.ban_threshold);
+    info!("Ban Window:                   {}", config.ban_window);
+    info!("Ban Duration:                 {}", config.ban_duration);
+    info!("TLS Cert:                     {}", config.tls_cert.as_deref().unwrap_or("none"));
+    info!("TCP Keepalive:                {}", config.tcp_keepalive.map_or("off".to_owned(), |k| k.to_string()));
+    info!("Health Port:                  {}", config.health_port.map_or("none".to_owned(), |p| p.to_string()));
+    info!("Limit Behavior:               {}", config.limit_behavior);
+    info!("HTTP CONNECT:                 {}", config.enable_http_connect);
+    info!("Source Port Range:            {}", config.source_port_range.map_or("any".to_owned(), |r| r.to_string()));
+    info!("Diagnostic Hostname:          {}", config.diagnostic_hostname.as_deref().unwrap_or("none"));
+    info!("Pool Stats Interval:          {}", config.pool_stats_interval.map_or("off".to_owned(), |i| i.to_string()));
+    info!("Connect Retries:              {}", config.connect_retries);
+    info!("Connect Retry Delay:          {}", config.connect_retry_delay);
+    info!("Auth:                         {}", if config.credentials.is_some() { "username/password" } else { "none" });
+    info!("Auth Methods:                 {}", Helpers::join(&config.auth_methods));
+    info!("Require Auth:                 {}", config.require_auth);
+    info!("Trace Bytes:                  {}", config.trace_bytes);
+    info!("Log Level:                    {}", config.log_level);
+    info!("Log Sampling:                 1 in {}", config.log_sampling);
 
-    // Create a buffer pool (doubled so that each half of the connection achieves the desired size).
-    let mut pool = BufferPool::new(2 * config.buffer_size);
+    // A check stops here (any error above exits non-zero).
+    if args.is_check() {
+        server::check(&config)?;
+        info!("The config is valid.");
 
-    // Start the server.
-    let listener = TcpListener::bind(format!("{}:{}", config.listen_ip, config.port)).await?;
-    info!("Listening on tcp://{}:{} ... ", config.listen_ip, config.port);
-
-    // Server loop.
-    loop {
-        debug!("Buffer pool: {} leased / {} total.", pool.leased_count(), pool.total_count());
-
-        // Accept new connections.
-        let (mut stream, _) = listener.accept().await?;
-        let remote_ip = stream.peer_addr()?.ip();
-        
-        // Drop connections that do not match the accept CIDR.
-        if !cidr_is_trivial && !Helpers::is_ip_in_cidr(&remote_ip, &cidr)? {
-            warn!("Request from {} does not match {}: dropping connection.", remote_ip, config.accept_cidr);
-            stream.shutdown().await.unwrap_or_default();
-            continue;
-        }
-        
-        Connection::from(stream, config.endpoint_ip.to_owned(), pool.lease(), config.read_timeout).handle();
+        return Ok(());
     }
-}
\ No newline at end of file
+
+    server::serve(config, args, activation).await
+}
@@ -0,0 +1,179 @@
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use log::{info, warn};
+
+use crate::connection::ERRORS;
+use crate::helpers::Void;
+
+// Replies 0 through 8 are defined by RFC 1928; anything else is counted as 9.
+const REPLY_CODES: usize = 10;
+
+// The process-wide counters, updated by the connections and rendered by the metrics server.
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    connections_total: AtomicU64,
+    connections_active: AtomicU64,
+    bytes_up: AtomicU64,
+    bytes_down: AtomicU64,
+    handshake_failures: AtomicU64,
+    connect_replies: [AtomicU64; REPLY_CODES]
+}
+
+impl Metrics {
+    #[allow(clippy::declare_interior_mutable_const)]
+    const fn new() -> Self {
+        const ZERO: AtomicU64 = AtomicU64::new(0);
+
+        Metrics {
+            connections_total: ZERO,
+            connections_active: ZERO,
+            bytes_up: ZERO,
+            bytes_down: ZERO,
+            handshake_failures: ZERO,
+            connect_replies: [ZERO; REPLY_CODES]
+        }
+    }
+
+    pub fn connection_started(&self) {
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        self.connections_active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_ended(&self) {
+        self.connections_active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn connections_active(&self) -> u64 {
+        self.connections_active.load(Ordering::Relaxed)
+    }
+
+    pub fn handshake_failed(&self) {
+        self.handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connect_replied(&self, reply: u8) {
+        let index = usize::from(reply).min(REPLY_CODES - 1);
+        self.connect_replies[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn transferred(&self, bytes_up: u64, bytes_down: u64) {
+        self.bytes_up.fetch_add(bytes_up, Ordering::Relaxed);
+        self.bytes_down.fetch_add(bytes_down, Ordering::Relaxed);
+    }
+
+    // Renders the counters in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let mut metric = |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+            let _ = writeln!(out, "# HELP {} {}", name, help);
+            let _ = writeln!(out, "# TYPE {} {}", name, kind);
+            let _ = writeln!(out, "{} {}", name, value.load(Ordering::Relaxed));
+        };
+
+        metric("rusty_socks_connections_total", "counter", "Connections accepted.", &self.connections_total);
+        metric("rusty_socks_connections_active", "gauge", "Connections currently being handled.", &self.connections_active);
+        metric("rusty_socks_bytes_up_total", "counter", "Bytes sent from clients to endpoints.", &self.bytes_up);
+        metric("rusty_socks_bytes_down_total", "counter", "Bytes sent from endpoints to clients.", &self.bytes_down);
+        metric("rusty_socks_handshake_failures_total", "counter", "Handshakes or requests that failed.", &self.handshake_failures);
+
+        let _ = writeln!(out, "# HELP rusty_socks_connect_replies_total CONNECT requests by SOCKS reply code.");
+        let _ = writeln!(out, "# TYPE rusty_socks_connect_replies_total counter");
+
+        // The reason labels the code (e.g., to tell refusals from unreachable hosts without looking up the codes).
+        for (reply, value) in self.connect_replies.iter().enumerate() {
+            let reason = u8::try_from(reply).ok().and_then(|r| ERRORS.get(&r)).unwrap_or(&"Unknown");
+            let _ = writeln!(out, "rusty_socks_connect_replies_total{{reply=\"{}\",reason=\"{}\"}} {}", reply, reason, value.load(Ordering::Relaxed));
+        }
+
+        out
+    }
+}
+
+// Counts as an active connection until dropped (so the count stays accurate even when a connection errors or panics).
+pub struct ActiveConnection;
+
+impl ActiveConnection {
+    pub fn new() -> Self {
+        METRICS.connection_started();
+        ActiveConnection
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        METRICS.connection_ended();
+    }
+}
+
+// Serves `GET /metrics` on the listener until the process exits.
+pub async fn serve(listener: TcpListener) {
+    if let Ok(addr) = listener.local_addr() {
+        info!("Serving metrics on http://{}/metrics ... ", addr);
+    }
+
+    loop {
+        let stream = match listener.accept().await {
+            Ok((s, _)) => s,
+            Err(e) => {
+                warn!("Could not accept a metrics connection.  {}", e);
+                continue;
+            }
+        };
+
+        tokio::spawn(async move {
+            if let Err(e) = respond(stream).await {
+                warn!("Could not serve a metrics request.  {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream) -> Void {
+    // Only the request line matters, so a single read is enough.
+    let mut buffer = [0u8; 1024];
+    let read = stream.read(&mut buffer).await?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+
+    let (status, body) = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+        ["GET", "/metrics"] => ("200 OK", METRICS.render()),
+        _ => ("404 Not Found", "Not Found\n".to_owned())
+    };
+
+    write_response(&mut stream, status, "text/plain; version=0.0.4", &body).await
+}
+
+// Writes a complete HTTP response, and closes the stream.
+pub async fn write_response(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) -> Void {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, content_type, body.len(), body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_labels_each_reply_code() {
+        let metrics = Metrics::new();
+        metrics.connect_replied(0x05);
+        metrics.connect_replied(0x05);
+        metrics.connect_replied(0xFF);
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("rusty_socks_connect_replies_total{reply=\"0\",reason=\"Succeeded\"} 0\n"));
+        assert!(rendered.contains("rusty_socks_connect_replies_total{reply=\"5\",reason=\"Connection Refused\"} 2\n"));
+        assert!(rendered.contains("rusty_socks_connect_replies_total{reply=\"9\",reason=\"Unknown\"} 1\n"));
+    }
+}
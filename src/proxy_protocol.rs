@@ -0,0 +1,254 @@
+use std::convert::TryFrom;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::helpers::{Res, IntoError};
+
+// The PROXY protocol (https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt) versions: v1 is a text line, and v2
+// is binary.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Version {
+    V1,
+    V2
+}
+
+impl std::fmt::Display for Version {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Version::V1 => write!(f, "v1"),
+            Version::V2 => write!(f, "v2")
+        }
+    }
+}
+
+impl std::str::FromStr for Version {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "v1" => Ok(Version::V1),
+            "v2" => Ok(Version::V2),
+            _ => format!("Unknown PROXY protocol version `{}` (expected `v1` or `v2`).", s).into_error()
+        }
+    }
+}
+
+// Every v2 header starts with this signature.
+pub const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The v2 version (high nibble) and command (low nibble): PROXY carries addresses, and LOCAL does not.
+const V2_PROXY: u8 = 0x21;
+const V2_LOCAL: u8 = 0x20;
+
+// The v2 address family (high nibble) and transport (low nibble).
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+const V2_UNSPEC: u8 = 0x00;
+
+// The shortest header (`PROXY UNKNOWN\r\n`), which is read first so that the read never goes past the header.
+const MIN_LENGTH: usize = 15;
+
+// The longest v1 header (including the `\r\n`).
+const V1_MAX_LENGTH: usize = 107;
+
+// Returns the header that tells the endpoint about the client (`source`) and the address it connected to
+// (`destination`).  Without both addresses (e.g., for a Unix socket client), the header says the connection's origin is
+// unknown.
+pub fn header(version: Version, source: Option<SocketAddr>, destination: Option<SocketAddr>) -> Vec<u8> {
+    let addresses = match (source, destination) {
+        (Some(s), Some(d)) => Some(same_family(s, d)),
+        _ => None
+    };
+
+    match version {
+        Version::V1 => v1_header(addresses).into_bytes(),
+        Version::V2 => v2_header(addresses)
+    }
+}
+
+fn v1_header(addresses: Option<(SocketAddr, SocketAddr)>) -> String {
+    match addresses {
+        Some((s, d)) => {
+            let protocol = if s.is_ipv4() { "TCP4" } else { "TCP6" };
+
+            format!("PROXY {} {} {} {} {}\r\n", protocol, s.ip(), d.ip(), s.port(), d.port())
+        },
+        None => "PROXY UNKNOWN\r\n".to_owned()
+    }
+}
+
+fn v2_header(addresses: Option<(SocketAddr, SocketAddr)>) -> Vec<u8> {
+    let (command, family, body) = match addresses {
+        Some((SocketAddr::V4(s), SocketAddr::V4(d))) => (V2_PROXY, V2_TCP4, [&s.ip().octets()[..], &d.ip().octets()].concat()),
+        Some((SocketAddr::V6(s), SocketAddr::V6(d))) => (V2_PROXY, V2_TCP6, [&s.ip().octets()[..], &d.ip().octets()].concat()),
+        _ => (V2_LOCAL, V2_UNSPEC, Vec::new())
+    };
+
+    let ports = addresses.map(|(s, d)| [s.port().to_be_bytes(), d.port().to_be_bytes()].concat()).unwrap_or_default();
+    let length = (body.len() + ports.len()) as u16;
+
+    [&V2_SIGNATURE[..], &[command, family], &length.to_be_bytes(), &body, &ports].concat()
+}
+
+// Reads the header from the start of a connection, and returns the client address it names (none, when the sender
+// does not know it, like for a load balancer's own health checks).  Only the header is read (v1 a byte at a time past
+// the shortest header, since its length is not known up front), so the client's data stays in the stream.
+pub async fn read_header<S>(stream: &mut S) -> Res<Option<SocketAddr>>
+    where S: AsyncRead + Unpin
+{
+    let mut start = [0u8; MIN_LENGTH];
+    stream.read_exact(&mut start).await?;
+
+    if start[..V2_SIGNATURE.len()] == V2_SIGNATURE {
+        let mut length = [start[14], 0];
+        stream.read_exact(&mut length[1..]).await?;
+
+        let mut addresses = vec![0u8; usize::from(u16::from_be_bytes(length))];
+        stream.read_exact(&mut addresses).await?;
+
+        return parse_v2(start[12], start[13], &addresses);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return "The connection did not start with a PROXY protocol header.".into_error();
+    }
+
+    let mut line = start.to_vec();
+
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return format!("The PROXY protocol v1 header exceeds {} bytes.", V1_MAX_LENGTH).into_error();
+        }
+
+        line.push(stream.read_u8().await?);
+    }
+
+    parse_v1(&line)
+}
+
+// Parses `PROXY TCP4|TCP6 source destination source_port destination_port\r\n` (or `PROXY UNKNOWN ...\r\n`).
+fn parse_v1(line: &[u8]) -> Res<Option<SocketAddr>> {
+    let text = std::str::from_utf8(line)?.trim_end_matches("\r\n");
+
+    match text.split(' ').collect::<Vec<_>>()[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), source, _, source_port, _] => {
+            let ip = source.parse::<IpAddr>()?;
+
+            if ip.is_ipv4() != (protocol == "TCP4") {
+                return format!("The PROXY protocol v1 source `{}` is not {}.", source, protocol).into_error();
+            }
+
+            Ok(Some(SocketAddr::new(ip, source_port.parse()?)))
+        },
+        _ => format!("The PROXY protocol v1 header `{}` is not valid.", text).into_error()
+    }
+}
+
+// Parses the v2 addresses (which start with the source address, and may be followed by TLVs, which are ignored).
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> Res<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return format!("The PROXY protocol v2 header has a bad version ({}).", version_command >> 4).into_error();
+    }
+
+    match version_command {
+        V2_LOCAL => return Ok(None),
+        V2_PROXY => {},
+        _ => return format!("The PROXY protocol v2 header has an unknown command ({}).", version_command & 0x0F).into_error()
+    }
+
+    // TCP and UDP (the low nibble) carry the same addresses, and other families (like Unix sockets) have no IP.
+    let source = match family >> 4 {
+        0x1 => addresses.get(..12).map(|a| (IpAddr::V4(Ipv4Addr::from(<[u8; 4]>::try_from(&a[..4]).unwrap())), [a[8], a[9]])),
+        0x2 => addresses.get(..36).map(|a| (IpAddr::V6(Ipv6Addr::from(<[u8; 16]>::try_from(&a[..16]).unwrap())), [a[32], a[33]])),
+        _ => return Ok(None)
+    };
+
+    match source {
+        Some((ip, port)) => Ok(Some(SocketAddr::new(ip, u16::from_be_bytes(port)))),
+        None => "The PROXY protocol v2 header is shorter than its addresses.".into_error()
+    }
+}
+
+// Both addresses of a header must be of one family, so IPv4-mapped addresses (from a dual-stack listener) are
+// unmapped, and an IPv4 address is mapped if the other is IPv6.
+fn same_family(source: SocketAddr, destination: SocketAddr) -> (SocketAddr, SocketAddr) {
+    let canonical = |a: SocketAddr| SocketAddr::new(a.ip().to_canonical(), a.port());
+    let mapped = |a: SocketAddr| match a.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(IpAddr::V6(ip.to_ipv6_mapped()), a.port()),
+        IpAddr::V6(_) => a
+    };
+
+    let (source, destination) = (canonical(source), canonical(destination));
+
+    if source.is_ipv4() == destination.is_ipv4() {
+        (source, destination)
+    } else {
+        (mapped(source), mapped(destination))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Option<SocketAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test]
+    fn v1_header_names_both_addresses() {
+        assert_eq!(b"PROXY TCP4 10.0.0.1 10.0.0.2 5000 1080\r\n".to_vec(), header(Version::V1, addr("10.0.0.1:5000"), addr("10.0.0.2:1080")));
+        assert_eq!(b"PROXY TCP6 ::1 ::2 5000 1080\r\n".to_vec(), header(Version::V1, addr("[::1]:5000"), addr("[::2]:1080")));
+        assert_eq!(b"PROXY TCP4 10.0.0.1 10.0.0.2 5000 1080\r\n".to_vec(), header(Version::V1, addr("[::ffff:10.0.0.1]:5000"), addr("10.0.0.2:1080")));
+        assert_eq!(b"PROXY TCP6 ::ffff:10.0.0.1 ::2 5000 1080\r\n".to_vec(), header(Version::V1, addr("10.0.0.1:5000"), addr("[::2]:1080")));
+        assert_eq!(b"PROXY UNKNOWN\r\n".to_vec(), header(Version::V1, None, addr("10.0.0.2:1080")));
+    }
+
+    #[test]
+    fn v2_header_encodes_both_addresses() {
+        let expected = [&V2_SIGNATURE[..], &[0x21, 0x11, 0x00, 0x0C, 10, 0, 0, 1, 10, 0, 0, 2, 0x13, 0x88, 0x04, 0x38]].concat();
+        assert_eq!(expected, header(Version::V2, addr("10.0.0.1:5000"), addr("10.0.0.2:1080")));
+
+        let v6 = header(Version::V2, addr("[::1]:5000"), addr("[::2]:1080"));
+        assert_eq!((&[0x21, 0x21, 0x00, 0x24][..], 16 + 36), (&v6[12..16], v6.len()));
+
+        assert_eq!([&V2_SIGNATURE[..], &[0x20, 0x00, 0x00, 0x00]].concat(), header(Version::V2, None, None));
+    }
+
+    #[tokio::test]
+    async fn read_header_reads_only_the_header() {
+        for version in [Version::V1, Version::V2] {
+            let data = [header(version, addr("10.0.0.1:5000"), addr("10.0.0.2:1080")), vec![0x05, 0x01, 0x00]].concat();
+            let mut stream = &data[..];
+
+            assert_eq!(addr("10.0.0.1:5000"), read_header(&mut stream).await.unwrap());
+            assert_eq!(&[0x05, 0x01, 0x00], stream);
+        }
+
+        let data = header(Version::V2, addr("[::1]:5000"), addr("[::2]:1080"));
+        assert_eq!(addr("[::1]:5000"), read_header(&mut &data[..]).await.unwrap());
+
+        assert_eq!(None, read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap());
+        assert_eq!(None, read_header(&mut &header(Version::V2, None, None)[..]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn read_header_rejects_other_data() {
+        assert!(read_header(&mut &b"\x05\x01\x00\x05\x01\x00\x01\x7f\x00\x00\x01\x00\x50\x00\x00"[..]).await.is_err());
+        assert!(read_header(&mut &b"PROXY TCP4 ::1 ::2 5000 1080\r\n"[..]).await.is_err());
+        assert!(read_header(&mut &[&b"PROXY TCP4 "[..], &[b'1'; 200]].concat()[..]).await.is_err());
+
+        let mut truncated = header(Version::V2, addr("10.0.0.1:5000"), addr("10.0.0.2:1080"));
+        truncated[15] = 4;
+        assert!(read_header(&mut &truncated[..]).await.is_err());
+    }
+
+    #[test]
+    fn version_parses_its_names() {
+        assert_eq!(Version::V1, "v1".parse().unwrap());
+        assert_eq!(Version::V2, "v2".parse().unwrap());
+        assert!("v3".parse::<Version>().is_err());
+    }
+}
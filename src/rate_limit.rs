@@ -0,0 +1,100 @@
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::{sleep, Duration, Instant};
+
+// A token bucket that refills at `rate` bytes per second, and holds at most one second's worth of tokens.
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let rate = bytes_per_sec as f64;
+
+        TokenBucket { rate, tokens: rate, last_refill: Instant::now() }
+    }
+
+    // Takes `n` tokens, waiting for the bucket to refill if it runs into debt.
+    pub async fn take(&mut self, n: u64) {
+        self.refill();
+        self.tokens -= n as f64;
+
+        if self.tokens < 0.0 {
+            sleep(Duration::from_secs_f64(-self.tokens / self.rate)).await;
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+}
+
+// Copies through `buffer` (like `tokio::io::copy`), but paces the writes to stay under `bytes_per_sec`.
+pub async fn throttled_copy<R, W>(from: &mut R, to: &mut W, buffer: &mut [u8], bytes_per_sec: u64) -> std::io::Result<u64>
+    where R: AsyncRead + Unpin, W: AsyncWrite + Unpin
+{
+    let mut bucket = TokenBucket::new(bytes_per_sec);
+    let chunk_size = (bytes_per_sec.max(1) as usize).min(buffer.len());
+    let mut total = 0u64;
+
+    loop {
+        let read = from.read(&mut buffer[..chunk_size]).await?;
+
+        if read == 0 {
+            return Ok(total);
+        }
+
+        bucket.take(read as u64).await;
+        to.write_all(&buffer[..read]).await?;
+
+        total += read as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn take_waits_out_the_debt() {
+        let mut bucket = TokenBucket::new(100);
+        let start = Instant::now();
+
+        // The bucket starts full, so the first second's worth is free.
+        bucket.take(100).await;
+        assert_eq!(Duration::ZERO, start.elapsed());
+
+        // Half a second's worth of debt takes half a second to repay.
+        bucket.take(50).await;
+        assert_eq!(500, start.elapsed().as_millis());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_tracks_the_elapsed_time() {
+        let mut bucket = TokenBucket::new(100);
+        bucket.take(100).await;
+
+        tokio::time::advance(Duration::from_millis(250)).await;
+        let start = Instant::now();
+
+        bucket.take(25).await;
+        assert_eq!(Duration::ZERO, start.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn refill_is_capped_at_the_burst() {
+        let mut bucket = TokenBucket::new(100);
+
+        // An idle bucket holds no more than one second's worth.
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let start = Instant::now();
+
+        bucket.take(150).await;
+        assert_eq!(500, start.elapsed().as_millis());
+    }
+}
@@ -3,6 +3,12 @@ use std::net::{Ipv4Addr, Ipv6Addr};
 
 use crate::helpers::{Helpers, Res, IntoError};
 
+// The largest possible request: the header, a length-prefixed 255-byte domain, and the port.
+pub const MAX_REQUEST_SIZE: usize = 4 + 1 + MAX_DOMAIN_LENGTH + 2;
+
+// The longest domain name that fits the one-byte length prefix (RFC 1928).
+pub const MAX_DOMAIN_LENGTH: usize = 255;
+
 pub struct Request {
     pub version: u8,
     pub command: u8,
@@ -29,15 +35,27 @@ impl Display for Destination {
 }
 
 impl Request {
+    // Returns the declared length of a domain name request (without parsing the rest), so that it can be checked early.
+    pub fn domain_length(data: &[u8]) -> Option<usize> {
+        match data {
+            [_, _, _, 0x03, length, ..] => Some(*length as usize),
+            _ => None
+        }
+    }
+
     pub fn from_data(data: &[u8]) -> Res<Self> {
+        if data.len() < 4 {
+            return "The request is too short.".into_error();
+        }
+
         let version = data[0];
         let command = data[1];
         let reserved = data[2];
         let address_type = data[3];
 
         if address_type == 0x01 /* IPv4 */ {
-            let address = Ipv4Addr::from(Helpers::slice_to_u32(&data[4..8])?);
-            let port = Helpers::bytes_to_port(&data[8..10])?;
+            let address = Ipv4Addr::from(Helpers::slice_to_u32(Request::slice(data, 4, 4)?)?);
+            let port = Helpers::bytes_to_port(Request::slice(data, 8, 2)?)?;
             
             return Ok(Request {
                 version,
@@ -50,9 +68,9 @@ impl Request {
         }
 
         if address_type == 0x03 /* Domain Name */ {
-            let name_length = data[4] as usize;
-            let name = std::str::from_utf8(&data[5..(5 + name_length)])?.to_owned();
-            let port = Helpers::bytes_to_port(&data[(5 + name_length)..(5 + name_length + 2)])?;
+            let name_length = Request::slice(data, 4, 1)?[0] as usize;
+            let name = std::str::from_utf8(Request::slice(data, 5, name_length)?)?.to_owned();
+            let port = Helpers::bytes_to_port(Request::slice(data, 5 + name_length, 2)?)?;
             
             return Ok(Request {
                 version,
@@ -65,8 +83,8 @@ impl Request {
         }
 
         if address_type == 0x04 /* IPv6 */ {
-            let address = Ipv6Addr::from(Helpers::slice_to_u128(&data[4..20])?);
-            let port = Helpers::bytes_to_port(&data[20..22])?;
+            let address = Ipv6Addr::from(Helpers::slice_to_u128(Request::slice(data, 4, 16)?)?);
+            let port = Helpers::bytes_to_port(Request::slice(data, 20, 2)?)?;
 
             return Ok(Request {
                 version,
@@ -80,4 +98,62 @@ impl Request {
 
         "Unknown request type, or data corrupt.".into_error()
     }
-}
\ No newline at end of file
+
+    // Gets `length` bytes at `start`, or an error if the request is too short to contain them.
+    fn slice(data: &[u8], start: usize, length: usize) -> Res<&[u8]> {
+        match data.get(start..(start + length)) {
+            Some(s) => Ok(s),
+            None => "The request is shorter than its address type requires.".into_error()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn from_data_parses_ipv4() {
+        let request = Request::from_data(&[5, 1, 0, 1, 127, 0, 0, 1, 0x1f, 0x90]).unwrap();
+
+        assert_eq!(8080, request.port);
+        assert_eq!("127.0.0.1", request.destination.to_string());
+    }
+
+    #[test]
+    fn from_data_parses_domain() {
+        let request = Request::from_data(&[5, 1, 0, 3, 3, b'a', b'.', b'b', 0, 80]).unwrap();
+
+        assert_eq!(80, request.port);
+        assert_eq!("a.b", request.destination.to_string());
+    }
+
+    #[test]
+    fn from_data_rejects_truncated() {
+        assert!(Request::from_data(&[5, 1, 0]).is_err());
+        assert!(Request::from_data(&[5, 1, 0, 1, 127, 0, 0, 1, 0]).is_err());
+        assert!(Request::from_data(&[5, 1, 0, 3, 10, b'a']).is_err());
+        assert!(Request::from_data(&[5, 1, 0, 4, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn domain_length_reads_only_domain_requests() {
+        assert_eq!(Some(200), Request::domain_length(&[5, 1, 0, 3, 200]));
+        assert_eq!(None, Request::domain_length(&[5, 1, 0, 1, 200]));
+        assert_eq!(None, Request::domain_length(&[5, 1, 0, 3]));
+    }
+
+    proptest! {
+        #[test]
+        fn from_data_never_panics(data in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let _ = Request::from_data(&data);
+        }
+
+        #[test]
+        fn from_data_never_panics_with_valid_header(address_type in 0u8..6, rest in proptest::collection::vec(any::<u8>(), 0..300)) {
+            let data = [&[5u8, 1, 0, address_type][..], &rest].concat();
+            let _ = Request::from_data(&data);
+        }
+    }
+}
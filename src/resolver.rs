@@ -0,0 +1,183 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::OnceCell;
+use tokio::time::{Duration, Instant};
+use trust_dns_resolver::TokioAsyncResolver;
+use trust_dns_resolver::config::{NameServerConfigGroup, ResolverConfig, ResolverOpts};
+
+use crate::helpers::{IntoError, Res};
+
+// The port used when a DNS server is given without one.
+const DNS_PORT: u16 = 53;
+
+// Resolves endpoint names (through the system resolver, or a specific DNS server), optionally through an in-process cache.
+pub struct Resolver {
+    dns: Option<TokioAsyncResolver>,
+    cache: Option<Cache>
+}
+
+struct Cache {
+    entries: Mutex<HashMap<String, Arc<Slot>>>,
+    size: usize,
+    ttl: Duration
+}
+
+// A cached name.  Concurrent lookups of the same name share the slot, so only the first one actually resolves it.
+struct Slot {
+    created: Instant,
+    addresses: OnceCell<Vec<IpAddr>>
+}
+
+impl Resolver {
+    // A cache size of zero disables the cache.  Without a DNS server, names are resolved by the system.
+    pub fn new(cache_size: usize, cache_ttl: u64, dns_server: Option<SocketAddr>) -> Self {
+        let dns = dns_server.map(|server| {
+            let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+            TokioAsyncResolver::tokio(ResolverConfig::from_parts(None, vec![], servers), ResolverOpts::default())
+        });
+
+        let cache = if cache_size == 0 {
+            None
+        } else {
+            Some(Cache { entries: Mutex::new(HashMap::new()), size: cache_size, ttl: Duration::from_millis(cache_ttl) })
+        };
+
+        Resolver { dns, cache }
+    }
+
+    // Resolves a `host:port` string (IP literals are returned as-is, and never cached).
+    pub async fn resolve(&self, string_to_connect: &str) -> io::Result<Vec<SocketAddr>> {
+        let (host, port) = split_host_port(string_to_connect)?;
+
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(vec![SocketAddr::new(ip, port)]);
+        }
+
+        let cache = match &self.cache {
+            Some(c) => c,
+            None => return Ok(self.lookup(host).await?.into_iter().map(|ip| SocketAddr::new(ip, port)).collect())
+        };
+
+        // Failed lookups leave the slot empty, so they are retried rather than cached.
+        let slot = cache.slot(host);
+        let addresses = slot.addresses.get_or_try_init(|| self.lookup(host)).await?;
+
+        Ok(addresses.iter().map(|ip| SocketAddr::new(*ip, port)).collect())
+    }
+
+    async fn lookup(&self, host: &str) -> io::Result<Vec<IpAddr>> {
+        match &self.dns {
+            Some(dns) => Ok(dns.lookup_ip(host).await.map_err(io::Error::other)?.iter().collect()),
+            // The system resolver wants a port, but only the IPs are kept.
+            None => Ok(tokio::net::lookup_host((host, 0)).await?.map(|a| a.ip()).collect())
+        }
+    }
+
+    // Parses a DNS server as `ip:port`, or just `ip` (for port 53).
+    pub fn parse_server(server: &str) -> Res<SocketAddr> {
+        if let Ok(addr) = server.parse::<SocketAddr>() {
+            return Ok(addr);
+        }
+
+        match server.parse::<IpAddr>() {
+            Ok(ip) => Ok(SocketAddr::new(ip, DNS_PORT)),
+            Err(_) => format!("The DNS server `{}` must be an IP, or `ip:port`.", server).into_error()
+        }
+    }
+}
+
+impl Cache {
+    // Returns the live slot for the name, or a new one (evicting the oldest entry when the cache is full).
+    fn slot(&self, host: &str) -> Arc<Slot> {
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(slot) = entries.get(host).filter(|s| s.created.elapsed() < self.ttl) {
+            return slot.clone();
+        }
+
+        if !entries.contains_key(host) && entries.len() >= self.size {
+            entries.retain(|_, s| s.created.elapsed() < self.ttl);
+
+            if entries.len() >= self.size {
+                if let Some(oldest) = entries.iter().min_by_key(|(_, s)| s.created).map(|(h, _)| h.clone()) {
+                    entries.remove(&oldest);
+                }
+            }
+        }
+
+        let slot = Arc::new(Slot { created: Instant::now(), addresses: OnceCell::new() });
+        entries.insert(host.to_owned(), slot.clone());
+
+        slot
+    }
+}
+
+// Splits at the last colon (so that unbracketed IPv6 literals keep their colons), and strips any brackets from the host.
+fn split_host_port(string_to_connect: &str) -> io::Result<(&str, u16)> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("`{}` is not a valid `host:port`.", string_to_connect));
+
+    let (host, port) = string_to_connect.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+
+    Ok((host.trim_start_matches('[').trim_end_matches(']'), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn resolve_returns_ip_literals_without_caching() {
+        let resolver = Resolver::new(8, 60_000, None);
+
+        assert_eq!(vec!["127.0.0.1:80".parse::<SocketAddr>().unwrap()], resolver.resolve("127.0.0.1:80").await.unwrap());
+        assert_eq!(vec!["[::1]:443".parse::<SocketAddr>().unwrap()], resolver.resolve("::1:443").await.unwrap());
+        assert_eq!(0, resolver.cache.unwrap().entries.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn resolve_caches_names_and_applies_the_port() {
+        let resolver = Resolver::new(8, 60_000, None);
+
+        let first = resolver.resolve("localhost:80").await.unwrap();
+        let second = resolver.resolve("localhost:8080").await.unwrap();
+
+        assert!(!first.is_empty());
+        assert!(second.iter().all(|a| a.port() == 8080));
+        assert_eq!(first.iter().map(|a| a.ip()).collect::<Vec<_>>(), second.iter().map(|a| a.ip()).collect::<Vec<_>>());
+        assert_eq!(1, resolver.cache.unwrap().entries.lock().unwrap().len());
+    }
+
+    #[test]
+    fn parse_server_defaults_the_port() {
+        assert_eq!("10.0.0.53:53".parse::<SocketAddr>().unwrap(), Resolver::parse_server("10.0.0.53").unwrap());
+        assert_eq!("10.0.0.53:5353".parse::<SocketAddr>().unwrap(), Resolver::parse_server("10.0.0.53:5353").unwrap());
+        assert_eq!("[::1]:53".parse::<SocketAddr>().unwrap(), Resolver::parse_server("::1").unwrap());
+        assert!(Resolver::parse_server("dns.example").is_err());
+    }
+
+    #[test]
+    fn slot_is_shared_until_it_expires() {
+        let cache = Resolver::new(8, 60_000, None).cache.unwrap();
+        assert!(Arc::ptr_eq(&cache.slot("a"), &cache.slot("a")));
+
+        let cache = Resolver::new(8, 0, None).cache.unwrap();
+        assert!(!Arc::ptr_eq(&cache.slot("a"), &cache.slot("a")));
+    }
+
+    #[test]
+    fn slot_evicts_the_oldest_entry_when_full() {
+        let cache = Resolver::new(2, 60_000, None).cache.unwrap();
+
+        cache.slot("a");
+        cache.slot("b");
+        cache.slot("c");
+
+        let entries = cache.entries.lock().unwrap();
+        assert_eq!(2, entries.len());
+        assert!(!entries.contains_key("a"));
+    }
+}
@@ -0,0 +1,597 @@
+use std::{net::{IpAddr, SocketAddr}, sync::Arc, time::Instant};
+use tokio::{io::AsyncWriteExt, net::{TcpListener, TcpSocket}, sync::{Notify, Semaphore}, task::JoinHandle, time::{sleep, Duration}};
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use log::{info, debug, warn};
+
+use crate::access_log::AccessLog;
+use crate::buffer_pool::BufferPool;
+use crate::config::{self, Args, Config};
+use crate::connection::{Cidrs, Connection, Context};
+pub use crate::connection::{ConnectionSummary, OnComplete, Outcome};
+pub use crate::auth::{AuthRecord, Method};
+use crate::helpers::{IntoError, Res, Void};
+use crate::health;
+use crate::metrics;
+use crate::resolver::Resolver;
+use crate::ban_list::BanList;
+use crate::tls;
+use crate::stream::{Accepted, Listener};
+
+// The largest permit count a Tokio semaphore supports.
+const UNLIMITED_CONNECTIONS: usize = usize::MAX >> 3;
+
+// How long to pause accepting when the process (or system) is out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+// Selects what happens to a new connection when the connection limit is reached.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LimitBehavior {
+    // Waits for an active connection to end.
+    Queue,
+    // Closes the new connection immediately.
+    Reject
+}
+
+impl std::fmt::Display for LimitBehavior {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LimitBehavior::Queue => write!(f, "queue"),
+            LimitBehavior::Reject => write!(f, "reject")
+        }
+    }
+}
+
+impl std::str::FromStr for LimitBehavior {
+    type Err = Box<dyn std::error::Error>;
+
+    fn from_str(s: &str) -> Res<Self> {
+        match s {
+            "queue" => Ok(LimitBehavior::Queue),
+            "reject" => Ok(LimitBehavior::Reject),
+            _ => format!("Unknown limit behavior `{}` (expected `queue` or `reject`).", s).into_error()
+        }
+    }
+}
+
+// Serves until a shutdown signal arrives (reloading the config from `args` on SIGHUP).
+pub async fn serve(config: Arc<Config>, args: Args, activation: Activation) -> Void {
+    let listen_ip = config.listen_ip.parse::<IpAddr>()?;
+
+    // Use the sockets passed by systemd, if this process was socket-activated (otherwise, bind them ourselves).
+    let activated = activated_listeners(activation)?;
+    let is_activated = activated.is_some();
+
+    let listeners = match activated {
+        Some(listeners) => listeners,
+        None => bind_listeners(&config, listen_ip)?
+    };
+
+    // Start the metrics server, if one is configured.
+    if let Some(port) = config.metrics_port {
+        let listener = bind(SocketAddr::new(listen_ip, port), false, config.dual_stack)?;
+        tokio::spawn(metrics::serve(listener));
+    }
+
+    // Start the health check, if one is configured.
+    if let Some(port) = config.health_port {
+        let listener = bind(SocketAddr::new(listen_ip, port), false, config.dual_stack)?;
+        tokio::spawn(health::serve(listener, Instant::now()));
+    }
+
+    let result = serve_listeners(listeners, config.clone(), Some(args), log_summaries(), shutdown_signal()).await;
+
+    // The socket file belongs to systemd when activated.
+    #[cfg(unix)]
+    if let (Some(path), false) = (&config.listen_unix, is_activated) {
+        std::fs::remove_file(path).unwrap_or_default();
+    }
+    #[cfg(not(unix))]
+    let _ = is_activated;
+
+    result
+}
+
+// Binds a listener for each port (and the Unix socket, if one is configured).
+fn bind_listeners(config: &Config, listen_ip: IpAddr) -> std::io::Result<Vec<Listener>> {
+    let mut listeners = Vec::<Listener>::new();
+
+    let options = [Some("SO_REUSEADDR"), config.reuse_port.then_some("SO_REUSEPORT"), config.dual_stack.then_some("dual-stack")];
+    let options = options.iter().flatten().copied().collect::<Vec<_>>().join(", ");
+
+    for port in &config.ports {
+        let listener = bind(SocketAddr::new(listen_ip, *port), config.reuse_port, config.dual_stack)?;
+
+        // The bound address is logged, so that a port of zero shows the port that was picked.
+        info!("Listening on tcp://{} ({}) ... ", listener.local_addr()?, options);
+        listeners.push(Listener::Tcp(listener));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = &config.listen_unix {
+        remove_stale_socket(path)?;
+        listeners.push(Listener::Unix(UnixListener::bind(path)?));
+        info!("Listening on unix://{} ... ", path);
+    }
+
+    Ok(listeners)
+}
+
+// The listening sockets passed by systemd (see `sd_listen_fds(3)`), when `LISTEN_PID` names this process.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Activation {
+    fds: Vec<i32>
+}
+
+impl Activation {
+    // Passed file descriptors start right after stdin, stdout, and stderr.
+    const LISTEN_FDS_START: i32 = 3;
+
+    // Reads (and unsets) the activation variables, which must happen before the runtime starts any threads.
+    pub fn take() -> Activation {
+        let activation = Activation::from_values(std::env::var("LISTEN_PID").ok().as_deref(), std::env::var("LISTEN_FDS").ok().as_deref(), std::process::id());
+
+        // Unset the variables, so that they are not inherited by child processes.
+        if !activation.fds.is_empty() {
+            for name in &["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+                std::env::remove_var(name);
+            }
+        }
+
+        activation
+    }
+
+    fn from_values(pid: Option<&str>, count: Option<&str>, own_pid: u32) -> Activation {
+        let pid = pid.and_then(|p| p.parse::<u32>().ok());
+        let count = count.and_then(|n| n.parse::<i32>().ok()).unwrap_or(0);
+
+        if pid != Some(own_pid) || count < 1 || !cfg!(unix) {
+            return Activation::default();
+        }
+
+        Activation { fds: (Activation::LISTEN_FDS_START..Activation::LISTEN_FDS_START + count).collect() }
+    }
+}
+
+// Takes over the activated sockets, if there are any.
+#[cfg(unix)]
+fn activated_listeners(activation: Activation) -> std::io::Result<Option<Vec<Listener>>> {
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+
+    if activation.fds.is_empty() {
+        return Ok(None);
+    }
+
+    let mut listeners = Vec::<Listener>::new();
+
+    for fd in activation.fds {
+        // The descriptor is owned by this process from here on (systemd only passes listening sockets).
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+
+        // A Unix socket has no IP address, so that is how the two kinds are told apart.
+        match tcp.local_addr() {
+            Ok(addr) => {
+                tcp.set_nonblocking(true)?;
+                listeners.push(Listener::Tcp(TcpListener::from_std(tcp)?));
+                info!("Listening on tcp://{} (fd {}, from systemd) ... ", addr, fd);
+            },
+            Err(_) => {
+                let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+                unix.set_nonblocking(true)?;
+                listeners.push(Listener::Unix(UnixListener::from_std(unix)?));
+                info!("Listening on unix socket (fd {}, from systemd) ... ", fd);
+            }
+        }
+    }
+
+    Ok(Some(listeners))
+}
+
+// Socket activation is a systemd feature.
+#[cfg(not(unix))]
+fn activated_listeners(_activation: Activation) -> std::io::Result<Option<Vec<Listener>>> {
+    Ok(None)
+}
+
+// Runs the startup checks that go beyond `Config::validate` (e.g., loading the TLS certificate and key), without binding anything.
+pub fn check(config: &Config) -> Void {
+    if let (Some(cert), Some(key)) = (&config.tls_cert, &config.tls_key) {
+        tls::acceptor(cert, key)?;
+    }
+
+    Ok(())
+}
+
+// Serves on TCP listeners that are already bound (e.g., to ephemeral ports), until a shutdown signal arrives.
+pub async fn serve_on(listeners: Vec<TcpListener>, config: Arc<Config>) -> Void {
+    serve_on_with_callback(listeners, config, log_summaries()).await
+}
+
+// Like `serve_on`, but calls `on_complete` with the summary of each completed connection (instead of logging it).
+pub async fn serve_on_with_callback(listeners: Vec<TcpListener>, config: Arc<Config>, on_complete: OnComplete) -> Void {
+    serve_listeners(listeners.into_iter().map(Listener::Tcp).collect(), config, None, on_complete, shutdown_signal()).await
+}
+
+// A server started by `serve_with_handle`, which can be stopped from code (rather than only by a signal).
+pub struct ServerHandle {
+    local_addrs: Vec<SocketAddr>,
+    stop: Arc<Notify>,
+    task: JoinHandle<Result<(), String>>
+}
+
+impl ServerHandle {
+    // The address of the first port's listener, as bound (so a port of zero reads as the port that was picked), or none
+    // when the server only listens on a Unix socket.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addrs.first().copied()
+    }
+
+    // The address of each port's listener, in the order of the ports.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    // Stops accepting, and waits for the active connections to finish (up to the shutdown grace, like on a signal).
+    pub async fn shutdown(self) -> Void {
+        self.stop.notify_one();
+
+        match self.task.await {
+            Ok(result) => result.or_else(|e| e.into_error()),
+            Err(e) => Err(e.into())
+        }
+    }
+}
+
+// Binds the configured listeners, and serves in the background until the returned handle (or a signal) shuts the server
+// down.  Unlike `serve`, there are no metrics or health servers, and no reloads.
+pub async fn serve_with_handle(config: Arc<Config>) -> Res<ServerHandle> {
+    let listen_ip = config.listen_ip.parse::<IpAddr>()?;
+    let listeners = bind_listeners(&config, listen_ip)?;
+    let local_addrs = listeners.iter().filter_map(Listener::local_addr).collect();
+
+    let stop = Arc::new(Notify::new());
+    let stopped = {
+        let stop = stop.clone();
+
+        async move {
+            tokio::select! {
+                _ = stop.notified() => {},
+                _ = shutdown_signal() => {}
+            }
+        }
+    };
+
+    let task = tokio::spawn(async move {
+        let result = serve_listeners(listeners, config.clone(), None, log_summaries(), stopped).await.map_err(|e| e.to_string());
+
+        #[cfg(unix)]
+        if let Some(path) = &config.listen_unix {
+            std::fs::remove_file(path).unwrap_or_default();
+        }
+
+        result
+    });
+
+    Ok(ServerHandle { local_addrs, stop, task })
+}
+
+// The default callback, which logs each summary.
+fn log_summaries() -> OnComplete {
+    Arc::new(|summary| Box::pin(async move { Connection::log_summary(&summary) }))
+}
+
+// Serves until `shutdown` resolves.
+async fn serve_listeners<S>(listeners: Vec<Listener>, mut config: Arc<Config>, args: Option<Args>, on_complete: OnComplete, shutdown: S) -> Void
+    where S: std::future::Future<Output = ()>
+{
+    // Create a buffer pool (doubled so that each half of the connection achieves the desired size).
+    let pool = BufferPool::new(2 * config.buffer_size, config.max_buffers, config.zero_buffers_on_return);
+
+    if let Some(interval) = config.pool_stats_interval {
+        pool.spawn_stats_logger(Duration::from_millis(interval));
+    }
+
+    // Limit the number of concurrent connections (zero means unlimited).
+    let max_connections = if config.max_connections == 0 { UNLIMITED_CONNECTIONS } else { config.max_connections };
+    let connection_permits = Arc::new(Semaphore::new(max_connections));
+
+    let context = Arc::new(Context::new(
+        // Open the access log once, and share it with every connection.
+        match &config.access_log {
+            Some(path) => Some(AccessLog::open(path).await?),
+            None => None
+        },
+        // Share one resolver (and so one DNS cache) across the connections.
+        Resolver::new(config.dns_cache_size, config.dns_cache_ttl, config.dns_server),
+        // Sources that fail too many handshakes are dropped for a while.
+        BanList::new(config.ban_threshold, config.ban_window, config.ban_duration),
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => Some(tls::acceptor(cert, key)?),
+            _ => None
+        },
+        on_complete,
+        // Calculate the CIDR prefixes and masks.
+        Cidrs::parse(&config)?
+    ));
+
+    tokio::pin!(shutdown);
+
+    let mut reloader = match args {
+        Some(args) => Some(Reloader::new(args)?),
+        None => None
+    };
+
+    // Server loop.
+    loop {
+        let stats = pool.stats();
+        debug!("Buffer pool: {} leased / {} total.  Connections: {} active.", stats.leased, stats.total, Connection::active_count());
+
+        // Accept new connections (until a shutdown is requested).
+        let accepted = tokio::select! {
+            accepted = accept_any(&listeners) => match accepted {
+                Ok(a) => Some(a),
+                // An accept error only affects the one connection, so it should not take the server (and the active connections) down.
+                Err(e) => {
+                    warn!("Could not accept a connection.  {}", e);
+
+                    // Running out of file descriptors persists until some connections close, so back off rather than spin.
+                    if is_out_of_files(&e) {
+                        sleep(ACCEPT_BACKOFF).await;
+                    }
+
+                    continue;
+                }
+            },
+            _ = reload_requested(&mut reloader) => None,
+            _ = &mut shutdown => break
+        };
+
+        // Swap in the reloaded config for new connections (the active ones keep the config they started with).
+        let accepted = match accepted {
+            Some(a) => a,
+            None => {
+                if let Some(reloaded) = reload(&reloader, &config).await {
+                    context.set_cidrs(Cidrs::parse(&reloaded)?);
+                    config = reloaded;
+                }
+
+                continue;
+            }
+        };
+
+        // IPv4 clients of an IPv6 listener appear as IPv4-mapped addresses, so match them as the IPv4 addresses they are.
+        let client_ip = match &accepted {
+            Accepted::Tcp(stream) => match stream.peer_addr() {
+                Ok(a) => Some(a.ip().to_canonical()),
+                // The client may already be gone (e.g., it reset the connection).
+                Err(e) => {
+                    debug!("Could not get the address of an accepted connection: dropping connection.  {}", e);
+                    continue;
+                }
+            },
+            #[cfg(unix)]
+            Accepted::Unix(_) => None
+        };
+
+        // The CIDRs only apply to TCP clients (and, behind a trusted proxy, to the client named in its header, which the connection checks).
+        if let Some(ip) = &client_ip {
+            let cidrs = context.cidrs();
+            let is_proxied = config.accept_proxy_protocol && cidrs.is_trusted_proxy(ip);
+
+            if !is_proxied && !Connection::is_admitted(ip, &context.ban_list, &cidrs) {
+                accepted.into_stream().shutdown().await.unwrap_or_default();
+                continue;
+            }
+        }
+        
+        // Wait for a free connection slot (or give up right away, when rejecting); the permit is released when the connection task ends.
+        let permit = match config.limit_behavior {
+            LimitBehavior::Queue => tokio::select! {
+                permit = connection_permits.clone().acquire_owned() => permit?,
+                _ = &mut shutdown => break
+            },
+            LimitBehavior::Reject => match connection_permits.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    let client = accepted.into_stream();
+                    warn!("Request from {} exceeds the connection limit ({}): dropping connection.", client.peer_name().unwrap_or_else(|_| "unknown".to_owned()), config.max_connections);
+                    continue;
+                }
+            }
+        };
+
+        // Wait for a free buffer as well (there may be fewer buffers than connection slots).
+        let buffer = tokio::select! {
+            buffer = pool.lease() => buffer,
+            _ = &mut shutdown => break
+        };
+
+        Connection::from(accepted.into_stream(), client_ip, config.clone(), buffer, context.clone(), permit).handle();
+    }
+
+    // Stop accepting, and give the active connections a chance to finish.
+    drop(listeners);
+    drain(&connection_permits, max_connections, config.shutdown_grace).await;
+
+    Ok(())
+}
+
+// Waits for SIGHUP (Unix only), and remembers the arguments that the config is re-read from.
+struct Reloader {
+    args: Args,
+    #[cfg(unix)]
+    hangup: tokio::signal::unix::Signal
+}
+
+impl Reloader {
+    fn new(args: Args) -> std::io::Result<Self> {
+        Ok(Reloader {
+            args,
+            #[cfg(unix)]
+            hangup: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?
+        })
+    }
+}
+
+// Resolves when a reload is requested (never, without a reloader).
+async fn reload_requested(reloader: &mut Option<Reloader>) {
+    match reloader {
+        #[cfg(unix)]
+        Some(r) => {
+            r.hangup.recv().await;
+        },
+        _ => futures::future::pending().await
+    }
+}
+
+// Re-reads the config, and returns it if it is valid (settings that only apply at startup are reported, but not applied).
+async fn reload(reloader: &Option<Reloader>, current: &Config) -> Option<Arc<Config>> {
+    let args = reloader.as_ref()?.args.clone();
+
+    match config::from_args_file_and_env(args).await {
+        Ok(reloaded) => {
+            for setting in current.restart_required(&reloaded) {
+                warn!("The `{}` setting changed, but requires a restart to apply.", setting);
+            }
+
+            log::set_max_level(reloaded.log_level);
+
+            info!("Reloaded the config for new connections.");
+            Some(Arc::new(reloaded.with_startup_settings_of(current)))
+        },
+        Err(e) => {
+            warn!("Could not reload the config (keeping the current one).  {}", e);
+            None
+        }
+    }
+}
+
+// Binds a listener with `SO_REUSEADDR` (so a restart is not blocked by sockets in `TIME_WAIT`), and optionally `SO_REUSEPORT`
+// (and, for a dual-stack listener, without `IPV6_V6ONLY`).
+fn bind(addr: SocketAddr, reuse_port: bool, dual_stack: bool) -> std::io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+
+    socket.set_reuseaddr(true)?;
+
+    // Accept IPv4 clients (as IPv4-mapped addresses) on the IPv6 socket, whatever the system default is.
+    if dual_stack && addr.is_ipv6() {
+        socket2::SockRef::from(&socket).set_only_v6(false)?;
+    }
+
+    #[cfg(unix)]
+    socket.set_reuseport(reuse_port)?;
+    #[cfg(not(unix))]
+    let _ = reuse_port;
+
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
+// Removes a socket file left behind by a previous run (which would block the bind), but never any other kind of file.
+#[cfg(unix)]
+fn remove_stale_socket(path: &str) -> std::io::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    match std::fs::symlink_metadata(path) {
+        Ok(m) if m.file_type().is_socket() => std::fs::remove_file(path),
+        _ => Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_out_of_files(error: &std::io::Error) -> bool {
+    matches!(error.raw_os_error(), Some(libc::EMFILE) | Some(libc::ENFILE))
+}
+
+#[cfg(windows)]
+fn is_out_of_files(error: &std::io::Error) -> bool {
+    error.raw_os_error() == Some(10024) // WSAEMFILE.
+}
+
+// Accepts the next connection from whichever listener receives one first.
+async fn accept_any(listeners: &[Listener]) -> std::io::Result<Accepted> {
+    let accepts = listeners.iter().map(|l| Box::pin(l.accept()));
+
+    futures::future::select_all(accepts).await.0
+}
+
+async fn drain(connection_permits: &Semaphore, max_connections: usize, shutdown_grace: u64) {
+    let active = max_connections - connection_permits.available_permits();
+    info!("Shutting down: waiting up to {} ms for {} active connection(s) ...", shutdown_grace, active);
+
+    let drained = async {
+        while connection_permits.available_permits() < max_connections {
+            sleep(Duration::from_millis(100)).await;
+        }
+    };
+
+    tokio::select! {
+        _ = drained => info!("All connections finished."),
+        _ = sleep(Duration::from_millis(shutdown_grace)) => {
+            warn!("Shutdown grace period elapsed: dropping {} active connection(s).", max_connections - connection_permits.available_permits());
+        },
+        _ = shutdown_signal() => {
+            warn!("Received a second signal: dropping {} active connection(s).", max_connections - connection_permits.available_permits());
+        }
+    }
+}
+
+// Resolves on SIGINT (or SIGTERM, on Unix).
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {}
+            },
+            Err(_) => tokio::signal::ctrl_c().await.unwrap_or_default()
+        }
+    }
+
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.unwrap_or_default();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn activation_requires_this_process() {
+        assert_eq!(vec![3, 4], Activation::from_values(Some("42"), Some("2"), 42).fds);
+        assert_eq!(Activation::default(), Activation::from_values(Some("41"), Some("2"), 42));
+        assert_eq!(Activation::default(), Activation::from_values(None, Some("2"), 42));
+        assert_eq!(Activation::default(), Activation::from_values(Some("42"), Some("0"), 42));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn activated_listeners_take_over_tcp_and_unix_sockets() {
+        use std::os::unix::io::IntoRawFd;
+
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let path = std::env::temp_dir().join(format!("rusty_socks_activated_{}.sock", std::process::id()));
+        std::fs::remove_file(&path).unwrap_or_default();
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+
+        let activation = Activation { fds: vec![tcp.into_raw_fd(), unix.into_raw_fd()] };
+        let listeners = activated_listeners(activation).unwrap().unwrap();
+
+        match &listeners[..] {
+            [Listener::Tcp(tcp), Listener::Unix(_)] => assert_eq!(addr, tcp.local_addr().unwrap()),
+            _ => panic!("expected a TCP and a Unix listener")
+        }
+
+        // The adopted sockets accept connections.
+        let _client = tokio::net::TcpStream::connect(addr).await.unwrap();
+        assert!(matches!(listeners[0].accept().await.unwrap(), Accepted::Tcp(_)));
+        let _client = tokio::net::UnixStream::connect(&path).await.unwrap();
+        assert!(matches!(listeners[1].accept().await.unwrap(), Accepted::Unix(_)));
+
+        std::fs::remove_file(&path).unwrap_or_default();
+    }
+}
@@ -0,0 +1,188 @@
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+// A stream that a client can connect over (TCP, or a Unix socket).
+pub trait ClientStream: AsyncRead + AsyncWrite + Unpin + Send {
+    fn peer_name(&self) -> io::Result<String>;
+    fn local_name(&self) -> io::Result<String>;
+    fn set_no_delay(&self, no_delay: bool) -> io::Result<()>;
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()>;
+}
+
+impl ClientStream for TcpStream {
+    fn peer_name(&self) -> io::Result<String> {
+        Ok(self.peer_addr()?.to_string())
+    }
+
+    fn local_name(&self) -> io::Result<String> {
+        Ok(self.local_addr()?.to_string())
+    }
+
+    fn set_no_delay(&self, no_delay: bool) -> io::Result<()> {
+        self.set_nodelay(no_delay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        socket2::SockRef::from(self).set_tcp_keepalive(&socket2::TcpKeepalive::new().with_time(idle))
+    }
+}
+
+#[cfg(unix)]
+impl ClientStream for UnixStream {
+    // Unix socket clients are usually unnamed, so fall back to a generic name.
+    fn peer_name(&self) -> io::Result<String> {
+        Ok(self.peer_addr()?.as_pathname().map_or("unix".to_owned(), |p| format!("unix:{}", p.display())))
+    }
+
+    fn local_name(&self) -> io::Result<String> {
+        Ok(self.local_addr()?.as_pathname().map_or("unix".to_owned(), |p| format!("unix:{}", p.display())))
+    }
+
+    // Nagle's algorithm does not apply to Unix sockets.
+    fn set_no_delay(&self, _: bool) -> io::Result<()> {
+        Ok(())
+    }
+
+    // Neither do keepalives (there is no network in between to drop the connection).
+    fn set_keepalive(&self, _: Duration) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A client connection with TLS terminated by the proxy (the names and options are those of the underlying stream).
+impl ClientStream for tokio_rustls::server::TlsStream<Box<dyn ClientStream>> {
+    fn peer_name(&self) -> io::Result<String> {
+        self.get_ref().0.peer_name()
+    }
+
+    fn local_name(&self) -> io::Result<String> {
+        self.get_ref().0.local_name()
+    }
+
+    fn set_no_delay(&self, no_delay: bool) -> io::Result<()> {
+        self.get_ref().0.set_no_delay(no_delay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.get_ref().0.set_keepalive(idle)
+    }
+}
+
+// A client connection behind a proxy that sent a PROXY protocol header, so the peer is the client that the header
+// names (or the proxy itself, when the header names none).
+pub struct ProxiedStream {
+    inner: Box<dyn ClientStream>,
+    source: Option<SocketAddr>
+}
+
+impl ProxiedStream {
+    pub fn new(inner: Box<dyn ClientStream>, source: Option<SocketAddr>) -> Self {
+        ProxiedStream { inner, source }
+    }
+}
+
+impl AsyncRead for ProxiedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ProxiedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl ClientStream for ProxiedStream {
+    fn peer_name(&self) -> io::Result<String> {
+        match self.source {
+            Some(source) => Ok(source.to_string()),
+            None => self.inner.peer_name()
+        }
+    }
+
+    fn local_name(&self) -> io::Result<String> {
+        self.inner.local_name()
+    }
+
+    fn set_no_delay(&self, no_delay: bool) -> io::Result<()> {
+        self.inner.set_no_delay(no_delay)
+    }
+
+    fn set_keepalive(&self, idle: Duration) -> io::Result<()> {
+        self.inner.set_keepalive(idle)
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener)
+}
+
+pub enum Accepted {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream)
+}
+
+impl Accepted {
+    pub fn into_stream(self) -> Box<dyn ClientStream> {
+        match self {
+            Accepted::Tcp(s) => Box::new(s),
+            #[cfg(unix)]
+            Accepted::Unix(s) => Box::new(s)
+        }
+    }
+}
+
+impl Listener {
+    // The bound address (only TCP listeners have one).
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Listener::Tcp(l) => l.local_addr().ok(),
+            #[cfg(unix)]
+            Listener::Unix(_) => None
+        }
+    }
+
+    pub async fn accept(&self) -> io::Result<Accepted> {
+        match self {
+            Listener::Tcp(l) => Ok(Accepted::Tcp(l.accept().await?.0)),
+            #[cfg(unix)]
+            Listener::Unix(l) => Ok(Accepted::Unix(l.accept().await?.0))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn set_keepalive_enables_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+
+        stream.set_keepalive(Duration::from_secs(30)).unwrap();
+
+        let socket = socket2::SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+    }
+}
@@ -0,0 +1,29 @@
+use std::sync::Arc;
+
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+use crate::helpers::{IntoError, Res};
+
+pub use tokio_rustls::TlsAcceptor;
+
+// Builds the acceptor that terminates TLS on client connections, from a PEM certificate chain and private key.
+pub fn acceptor(cert_path: &str, key_path: &str) -> Res<TlsAcceptor> {
+    let certs = CertificateDer::pem_file_iter(cert_path).and_then(|certs| certs.collect::<Result<Vec<_>, _>>());
+
+    let certs = match certs {
+        Ok(c) if !c.is_empty() => c,
+        Ok(_) => return format!("The TLS certificate file `{}` has no certificates.", cert_path).into_error(),
+        Err(e) => return format!("Could not read the TLS certificate file `{}`.  {}", cert_path, e).into_error()
+    };
+
+    let key = match PrivateKeyDer::from_pem_file(key_path) {
+        Ok(k) => k,
+        Err(e) => return format!("Could not read the TLS key file `{}`.  {}", key_path, e).into_error()
+    };
+
+    let config = ServerConfig::builder().with_no_client_auth().with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
@@ -0,0 +1,121 @@
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::helpers::Helpers;
+use crate::request::Destination;
+
+// Another SOCKS5 proxy that CONNECTs are forwarded through.
+pub struct UpstreamProxy {
+    pub address: String,
+    pub username: Option<String>,
+    pub password: Option<String>
+}
+
+impl UpstreamProxy {
+    pub fn new(address: String, username: Option<String>, password: Option<String>) -> Self {
+        UpstreamProxy { address, username, password }
+    }
+
+    // Performs the client side of the SOCKS5 handshake and CONNECT, returning the SOCKS reply code on failure.
+    pub async fn negotiate(&self, socket: &mut TcpStream, destination: &Destination, port: u16) -> Result<(), u8> {
+        self.negotiate_method(socket).await?;
+        UpstreamProxy::negotiate_connect(socket, destination, port).await
+    }
+
+    async fn negotiate_method(&self, socket: &mut TcpStream) -> Result<(), u8> {
+        let credentials = match (&self.username, &self.password) {
+            (Some(u), Some(p)) => Some((u, p)),
+            _ => None
+        };
+
+        // Offer NO AUTH, and USERNAME/PASSWORD when there are credentials.
+        let greeting: &[u8] = if credentials.is_some() { &[0x05, 0x02, 0x00, 0x02] } else { &[0x05, 0x01, 0x00] };
+        socket.write_all(greeting).await.map_err(|_| 1u8)?;
+
+        let mut selection = [0u8; 2];
+        socket.read_exact(&mut selection).await.map_err(|_| 1u8)?;
+
+        match (selection[1], credentials) {
+            (0x00, _) => Ok(()),
+            (0x02, Some((username, password))) => UpstreamProxy::negotiate_user_pass(socket, username, password).await,
+            _ => Err(1u8) // General SOCKS server failure (no acceptable methods).
+        }
+    }
+
+    async fn negotiate_user_pass(socket: &mut TcpStream, username: &str, password: &str) -> Result<(), u8> {
+        if username.len() > 255 || password.len() > 255 {
+            return Err(1u8);
+        }
+
+        let mut message = vec![0x01, username.len() as u8];
+        message.extend_from_slice(username.as_bytes());
+        message.push(password.len() as u8);
+        message.extend_from_slice(password.as_bytes());
+
+        socket.write_all(&message).await.map_err(|_| 1u8)?;
+
+        let mut status = [0u8; 2];
+        socket.read_exact(&mut status).await.map_err(|_| 1u8)?;
+
+        if status[1] != 0x00 {
+            return Err(1u8);
+        }
+
+        Ok(())
+    }
+
+    async fn negotiate_connect(socket: &mut TcpStream, destination: &Destination, port: u16) -> Result<(), u8> {
+        let mut message = vec![0x05, 0x01, 0x00]; // VERSION, CONNECT, RESERVED.
+
+        match destination {
+            Destination::Ipv4Addr(ipv4) => {
+                message.push(0x01);
+                message.extend_from_slice(&ipv4.octets());
+            },
+            Destination::Ipv6Addr(ipv6) => {
+                message.push(0x04);
+                message.extend_from_slice(&ipv6.octets());
+            },
+            Destination::Domain(domain) => {
+                if domain.len() > 255 {
+                    return Err(1u8);
+                }
+
+                message.push(0x03);
+                message.push(domain.len() as u8);
+                message.extend_from_slice(domain.as_bytes());
+            }
+        }
+
+        let (port_high, port_low) = Helpers::port_to_bytes(port);
+        message.push(port_high);
+        message.push(port_low);
+
+        socket.write_all(&message).await.map_err(|_| 1u8)?;
+
+        // Read the reply header, and then the bound address (which is discarded).
+        let mut header = [0u8; 4];
+        socket.read_exact(&mut header).await.map_err(|_| 1u8)?;
+
+        if header[1] != 0x00 {
+            return Err(header[1]);
+        }
+
+        let address_length = match header[3] {
+            0x01 => 4,
+            0x04 => 16,
+            0x03 => {
+                let mut length = [0u8; 1];
+                socket.read_exact(&mut length).await.map_err(|_| 1u8)?;
+
+                length[0] as usize
+            },
+            _ => return Err(1u8)
+        };
+
+        let mut bound = vec![0u8; address_length + 2];
+        socket.read_exact(&mut bound).await.map_err(|_| 1u8)?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,67 @@
+use clap::Parser;
+
+use rusty_socks::config::{self, Args};
+
+const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/config.toml");
+
+// The config reads the process environment, so the tests take turns (a test that sets variables would leak them into the others).
+static ENV: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+#[tokio::test]
+async fn file_config_reads_lists_and_tables() {
+    let _env = ENV.lock().await;
+    let args = Args::try_parse_from(["rusty_socks", "--config", FIXTURE]).unwrap();
+    let config = config::from_args_file_and_env(args).await.unwrap();
+
+    assert_eq!(vec![1080, 1081], config.ports);
+    assert_eq!(5000, config.handshake_timeout);
+    assert_eq!(vec![443], config.allowed_ports);
+    assert_eq!(vec!["*.internal".to_owned(), "localhost".to_owned()], config.denied_domains);
+    assert_eq!(vec!["10.0.0.0/8".to_owned(), "192.168.0.0/16".to_owned()], config.deny_cidrs);
+
+    let credentials = config.credentials.unwrap();
+    assert!(credentials.verify(b"alice", b"wonderland").await);
+    assert!(credentials.verify(b"bob", b"builder").await);
+    assert!(!credentials.verify(b"bob", b"wonderland").await);
+}
+
+#[tokio::test]
+async fn cli_values_take_precedence_over_the_file() {
+    let _env = ENV.lock().await;
+    let args = Args::try_parse_from(["rusty_socks", "--config", FIXTURE, "--port", "2080", "--deny-cidrs", "172.16.0.0/12"]).unwrap();
+    let config = config::from_args_file_and_env(args).await.unwrap();
+
+    assert_eq!(vec![2080], config.ports);
+    assert_eq!(vec!["172.16.0.0/12".to_owned()], config.deny_cidrs);
+    assert!(config.credentials.is_some());
+}
+
+#[tokio::test]
+async fn env_values_take_precedence_over_the_file() {
+    let _env = ENV.lock().await;
+    std::env::set_var("RS_CONNECT_RETRIES", "3");
+    std::env::set_var("RS_DENIED_DOMAINS", "example.com");
+
+    let args = Args::try_parse_from(["rusty_socks", "--config", FIXTURE]).unwrap();
+    let config = config::from_args_file_and_env(args).await.unwrap();
+
+    std::env::remove_var("RS_CONNECT_RETRIES");
+    std::env::remove_var("RS_DENIED_DOMAINS");
+
+    assert_eq!(3, config.connect_retries);
+    assert_eq!(vec!["example.com".to_owned()], config.denied_domains);
+}
+
+#[test]
+fn check_exits_non_zero_on_an_invalid_config() {
+    // The binary runs with an empty environment, so the variables that other tests set do not leak in.
+    let check = |extra_args: &[&str]| std::process::Command::new(env!("CARGO_BIN_EXE_rusty_socks")).arg("--check").args(extra_args).env_clear().output().unwrap();
+
+    let valid = check(&["--config", FIXTURE]);
+    assert!(valid.status.success());
+    assert!(String::from_utf8_lossy(&valid.stdout).contains("The config is valid."));
+
+    let invalid = check(&["--config", FIXTURE, "--accept-cidr", "10.0.0.0/33"]);
+    assert!(!invalid.status.success());
+    assert!(String::from_utf8_lossy(&invalid.stderr).contains("The accept CIDR `10.0.0.0/33` is invalid"));
+}
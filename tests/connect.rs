@@ -0,0 +1,573 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use clap::Parser;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use rusty_socks::config::{self, Args};
+use rusty_socks::server;
+
+// Starts the proxy on an ephemeral port (with any extra CLI arguments), and returns its address.
+async fn start_proxy(extra_args: &[&str]) -> SocketAddr {
+    let args = Args::try_parse_from(["rusty_socks"].iter().chain(extra_args)).unwrap();
+    let config = Arc::new(config::from_args_file_and_env(args).await.unwrap());
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        server::serve_on(vec![listener], config).await.map_err(|e| e.to_string()).unwrap();
+    });
+
+    addr
+}
+
+// Starts a server that echoes everything back, and returns its address.
+async fn start_echo() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            tokio::spawn(async move {
+                let (mut read, mut write) = stream.split();
+                tokio::io::copy(&mut read, &mut write).await.unwrap_or_default();
+            });
+        }
+    });
+
+    addr
+}
+
+// Performs the no-auth handshake, sends the CONNECT request, and returns the reply code.
+async fn connect(proxy: SocketAddr, request: &[u8]) -> (TcpStream, u8) {
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let reply = negotiate(&mut client, request).await;
+
+    (client, reply)
+}
+
+async fn negotiate<S: AsyncRead + AsyncWrite + Unpin>(client: &mut S, request: &[u8]) -> u8 {
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+
+    let mut method = [0u8; 2];
+    client.read_exact(&mut method).await.unwrap();
+    assert_eq!([0x05, 0x00], method);
+
+    client.write_all(request).await.unwrap();
+
+    // The reply is 10 bytes for an IPv4 bound address, and 22 for IPv6.
+    let mut reply = [0u8; 4];
+    client.read_exact(&mut reply).await.unwrap();
+
+    let mut rest = vec![0u8; if reply[3] == 0x04 { 18 } else { 6 }];
+    client.read_exact(&mut rest).await.unwrap();
+
+    reply[1]
+}
+
+fn ipv4_request(addr: SocketAddr) -> Vec<u8> {
+    let ip = match addr {
+        SocketAddr::V4(a) => a.ip().octets(),
+        SocketAddr::V6(_) => panic!("Expected an IPv4 address.")
+    };
+
+    [&[0x05, 0x01, 0x00, 0x01][..], &ip, &addr.port().to_be_bytes()].concat()
+}
+
+fn domain_request(domain: &str, port: u16) -> Vec<u8> {
+    [&[0x05, 0x01, 0x00, 0x03, domain.len() as u8][..], domain.as_bytes(), &port.to_be_bytes()].concat()
+}
+
+async fn assert_round_trips<S: AsyncRead + AsyncWrite + Unpin>(client: &mut S) {
+    client.write_all(b"hello, socks").await.unwrap();
+
+    let mut echoed = [0u8; 12];
+    client.read_exact(&mut echoed).await.unwrap();
+
+    assert_eq!(b"hello, socks", &echoed);
+}
+
+#[tokio::test]
+async fn connect_ipv4_round_trips() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&[]).await;
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn connect_ipv6_round_trips() {
+    let echo = TcpListener::bind("[::1]:0").await.unwrap();
+    let echo_addr = echo.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = echo.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        tokio::io::copy(&mut read, &mut write).await.unwrap_or_default();
+    });
+
+    let args = Args::try_parse_from(["rusty_socks", "--listen-ipv6", "true"]).unwrap();
+    let config = Arc::new(config::from_args_file_and_env(args).await.unwrap());
+    assert_eq!("::", config.listen_ip);
+
+    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        server::serve_on(vec![listener], config).await.map_err(|e| e.to_string()).unwrap();
+    });
+
+    let ip = match echo_addr.ip() {
+        std::net::IpAddr::V6(ip) => ip.octets(),
+        std::net::IpAddr::V4(_) => panic!("Expected an IPv6 address.")
+    };
+    let request = [&[0x05, 0x01, 0x00, 0x04][..], &ip, &echo_addr.port().to_be_bytes()].concat();
+
+    let (mut client, reply) = connect(proxy, &request).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn connect_domain_round_trips() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&[]).await;
+
+    let (mut client, reply) = connect(proxy, &domain_request("localhost", echo.port())).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn connect_custom_pump_round_trips() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--pump-mode", "custom"]).await;
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn connect_refused_replies_with_error() {
+    // Bind and drop a listener to find a port that is (almost certainly) closed.
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let proxy = start_proxy(&[]).await;
+
+    let (_, reply) = connect(proxy, &ipv4_request(closed)).await;
+
+    assert_eq!(0x05, reply);
+}
+
+#[tokio::test]
+async fn connect_denied_port_replies_not_allowed() {
+    let echo = start_echo().await;
+    let port = echo.port().to_string();
+    let proxy = start_proxy(&["--denied-ports", &port]).await;
+
+    let (_, reply) = connect(proxy, &ipv4_request(echo)).await;
+
+    assert_eq!(0x02, reply);
+}
+
+#[tokio::test]
+async fn bind_and_udp_associate_reply_command_not_supported() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&[]).await;
+
+    // BIND, then UDP ASSOCIATE.
+    for command in [0x02, 0x03] {
+        let mut request = ipv4_request(echo);
+        request[1] = command;
+
+        let (_, reply) = connect(proxy, &request).await;
+
+        assert_eq!(0x07, reply);
+    }
+}
+
+#[tokio::test]
+async fn gssapi_only_clients_get_no_acceptable_methods() {
+    let proxy = start_proxy(&[]).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+
+    client.write_all(&[0x05, 0x01, 0x01]).await.unwrap();
+
+    // The proxy replies that no method is acceptable, and then closes the connection.
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+
+    assert_eq!(vec![0x05, 0xFF], reply);
+}
+
+#[tokio::test]
+async fn connect_over_tls_round_trips() {
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::rustls::pki_types::ServerName;
+    use tokio_rustls::TlsConnector;
+    use std::convert::TryFrom;
+
+    // Write a self-signed certificate (and its key) for the proxy.
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("rusty_socks_tls_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--tls-cert", cert_path.to_str().unwrap(), "--tls-key", key_path.to_str().unwrap()]).await;
+
+    let mut roots = RootCertStore::empty();
+    roots.add(certified.cert.der().clone()).unwrap();
+    let connector = TlsConnector::from(Arc::new(ClientConfig::builder().with_root_certificates(roots).with_no_client_auth()));
+
+    let tcp = TcpStream::connect(proxy).await.unwrap();
+    let mut client = connector.connect(ServerName::try_from("localhost").unwrap(), tcp).await.unwrap();
+
+    assert_eq!(0x00, negotiate(&mut client, &ipv4_request(echo)).await);
+    assert_round_trips(&mut client).await;
+
+    std::fs::remove_dir_all(&dir).unwrap_or_default();
+}
+
+#[tokio::test]
+async fn reject_closes_connections_over_the_limit() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--max-connections", "1", "--limit-behavior", "reject"]).await;
+
+    // Hold the only slot.
+    let (mut held, reply) = connect(proxy, &ipv4_request(echo)).await;
+    assert_eq!(0x00, reply);
+
+    let mut rejected = TcpStream::connect(proxy).await.unwrap();
+    rejected.write_all(&[0x05, 0x01, 0x00]).await.unwrap_or_default();
+
+    let mut buffer = [0u8; 2];
+    assert_eq!(0, rejected.read(&mut buffer).await.unwrap_or(0));
+
+    assert_round_trips(&mut held).await;
+}
+
+#[tokio::test]
+async fn http_connect_round_trips() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--enable-http-connect", "true"]).await;
+
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(format!("CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n", echo.port()).as_bytes()).await.unwrap();
+
+    let mut response = [0u8; 39];
+    client.read_exact(&mut response).await.unwrap();
+    assert_eq!(&b"HTTP/1.1 200 Connection Established\r\n\r\n"[..], &response[..]);
+
+    assert_round_trips(&mut client).await;
+
+    // SOCKS clients are still served on the same port.
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn diagnostic_hostname_reports_the_client() {
+    let proxy = start_proxy(&["--diagnostic-hostname", "whoami.proxy"]).await;
+
+    let (mut client, reply) = connect(proxy, &domain_request("WHOAMI.proxy", 80)).await;
+    assert_eq!(0x00, reply);
+
+    let mut report = String::new();
+    client.read_to_string(&mut report).await.unwrap();
+
+    assert_eq!(format!("client: {}\nprotocol: SOCKS5\nmethods: NoAuth\n", client.local_addr().unwrap()), report);
+}
+
+#[tokio::test]
+async fn connect_retries_until_the_endpoint_listens() {
+    // Find a closed port, and only start listening on it after the first attempt has been refused.
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+    let proxy = start_proxy(&["--connect-retries", "3", "--connect-retry-delay", "200"]).await;
+
+    tokio::spawn(async move {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let listener = TcpListener::bind(closed).await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        tokio::io::copy(&mut read, &mut write).await.unwrap_or_default();
+    });
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(closed)).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn on_complete_receives_the_summary() {
+    let echo = start_echo().await;
+
+    let args = Args::try_parse_from(["rusty_socks"]).unwrap();
+    let config = Arc::new(config::from_args_file_and_env(args).await.unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+
+    let (sender, mut summaries) = tokio::sync::mpsc::unbounded_channel();
+    let on_complete: server::OnComplete = Arc::new(move |summary| {
+        let sender = sender.clone();
+        Box::pin(async move { sender.send(summary).unwrap_or_default() })
+    });
+
+    tokio::spawn(async move {
+        server::serve_on_with_callback(vec![listener], config, on_complete).await.map_err(|e| e.to_string()).unwrap();
+    });
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+    drop(client);
+
+    let summary = summaries.recv().await.unwrap();
+
+    assert_eq!(Some(echo.ip().to_string()), summary.destination);
+    assert_eq!(Some(echo.port()), summary.port);
+    assert_eq!(Some(0x00), summary.reply_code);
+    assert_eq!((12, 12), (summary.bytes_up, summary.bytes_down));
+    assert_eq!(server::Outcome::Normal, summary.outcome);
+}
+
+#[tokio::test]
+async fn builder_config_round_trips() {
+    let echo = start_echo().await;
+
+    assert!(config::Config::builder().ports(Vec::new()).build().is_err());
+
+    let config = Arc::new(config::Config::builder().connect_retries(1).id_length(4).build().unwrap());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let proxy = listener.local_addr().unwrap();
+
+    tokio::spawn(async move {
+        server::serve_on(vec![listener], config).await.map_err(|e| e.to_string()).unwrap();
+    });
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+}
+
+#[tokio::test]
+async fn send_proxy_protocol_precedes_the_client_data() {
+    let endpoint = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint_addr = endpoint.local_addr().unwrap();
+    let proxy = start_proxy(&["--send-proxy-protocol", "v1"]).await;
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(endpoint_addr)).await;
+    assert_eq!(0x00, reply);
+    client.write_all(b"hello").await.unwrap();
+
+    let (mut stream, _) = endpoint.accept().await.unwrap();
+    let expected = format!("PROXY TCP4 127.0.0.1 127.0.0.1 {} {}\r\nhello", client.local_addr().unwrap().port(), proxy.port());
+    let mut received = vec![0u8; expected.len()];
+    stream.read_exact(&mut received).await.unwrap();
+
+    assert_eq!(expected.as_bytes(), &received[..]);
+}
+
+#[tokio::test]
+async fn accept_proxy_protocol_checks_the_client_in_the_header() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--accept-proxy-protocol", "true", "--trusted-proxies", "127.0.0.1", "--accept-cidr", "10.0.0.0/8"]).await;
+
+    // The proxy itself connects from 127.0.0.1, but the header names an admitted client.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(b"PROXY TCP4 10.1.2.3 127.0.0.1 5000 1080\r\n").await.unwrap();
+
+    assert_eq!(0x00, negotiate(&mut client, &ipv4_request(echo)).await);
+    assert_round_trips(&mut client).await;
+
+    // A client outside the accept CIDR is dropped before the handshake.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(b"PROXY TCP4 192.168.1.1 127.0.0.1 5000 1080\r\n").await.unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.is_empty());
+}
+
+#[tokio::test]
+async fn accept_proxy_protocol_checks_a_proxy_that_names_no_client() {
+    let proxy = start_proxy(&["--accept-proxy-protocol", "true", "--trusted-proxies", "127.0.0.1", "--accept-cidr", "10.0.0.0/8"]).await;
+
+    // Without a client in the header, the proxy (at 127.0.0.1, outside the accept CIDR) is the client.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(b"PROXY UNKNOWN\r\n\x05\x01\x00").await.unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap_or_default();
+    assert!(reply.is_empty());
+}
+
+#[tokio::test]
+async fn accept_proxy_protocol_ignores_untrusted_peers() {
+    let proxy = start_proxy(&["--accept-proxy-protocol", "true", "--trusted-proxies", "10.0.0.1", "--accept-cidr", "10.0.0.0/8"]).await;
+
+    // A peer that is not a trusted proxy cannot name an admitted client, and is checked itself.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(b"PROXY TCP4 10.1.2.3 127.0.0.1 5000 1080\r\n\x05\x01\x00").await.unwrap_or_default();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap_or_default();
+    assert!(reply.is_empty());
+}
+
+#[tokio::test]
+async fn serve_with_handle_shuts_down_on_request() {
+    let echo = start_echo().await;
+    let config = config::Config::builder().listen_ip("127.0.0.1".into()).ports(vec![0]).build().unwrap();
+    let handle = server::serve_with_handle(Arc::new(config)).await.unwrap();
+
+    // Port zero picks a free port, which the handle reports.
+    let proxy = handle.local_addr().unwrap();
+    assert_ne!(0, proxy.port());
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+    drop(client);
+
+    handle.shutdown().await.unwrap();
+    assert!(TcpStream::connect(proxy).await.is_err());
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn serve_with_handle_has_no_local_addr_on_a_unix_socket_alone() {
+    let path = std::env::temp_dir().join(format!("rusty_socks_handle_{}.sock", std::process::id()));
+    let config = config::Config { ports: Vec::new(), listen_unix: Some(path.to_str().unwrap().to_owned()), ..config::Config::default() };
+    let handle = server::serve_with_handle(Arc::new(config)).await.unwrap();
+
+    assert_eq!(None, handle.local_addr());
+    assert!(handle.local_addrs().is_empty());
+
+    handle.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn connect_without_an_endpoint_ip_of_the_family_replies_address_type_not_supported() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--endpoint-ip", "::1"]).await;
+
+    let (_, reply) = connect(proxy, &ipv4_request(echo)).await;
+    assert_eq!(0x08, reply);
+}
+
+#[tokio::test]
+async fn accept_cidr_admits_clients_matching_any_listed_cidr() {
+    let echo = start_echo().await;
+    let proxy = start_proxy(&["--accept-cidr", "10.0.0.0/8,127.0.0.0/8"]).await;
+
+    let (mut client, reply) = connect(proxy, &ipv4_request(echo)).await;
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+
+    // Without a matching CIDR, the client is dropped before the handshake.
+    let proxy = start_proxy(&["--accept-cidr", "10.0.0.0/8,192.168.0.0/16"]).await;
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+
+    let mut reply = Vec::new();
+    client.read_to_end(&mut reply).await.unwrap();
+    assert!(reply.is_empty());
+}
+
+// Starts a SOCKS5 upstream that records the address in the first CONNECT it gets, and then echoes.
+async fn start_upstream() -> (SocketAddr, tokio::sync::oneshot::Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (sender, receiver) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting).await.unwrap();
+        stream.write_all(&[0x05, 0x00]).await.unwrap();
+
+        // The address type decides how much of the request is left.
+        let mut header = [0u8; 5];
+        stream.read_exact(&mut header).await.unwrap();
+        let remaining = match header[3] {
+            0x01 => 3 + 2,
+            0x03 => header[4] as usize + 2,
+            _ => 15 + 2
+        };
+        let mut rest = vec![0u8; remaining];
+        stream.read_exact(&mut rest).await.unwrap();
+        sender.send([&header[3..], &rest[..rest.len() - 2]].concat()).unwrap();
+
+        stream.write_all(&[0x05, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]).await.unwrap();
+
+        let (mut read, mut write) = stream.split();
+        tokio::io::copy(&mut read, &mut write).await.unwrap_or_default();
+    });
+
+    (addr, receiver)
+}
+
+#[tokio::test]
+async fn upstream_proxy_receives_domains_resolved_by_default() {
+    let (upstream, requested) = start_upstream().await;
+    let proxy = start_proxy(&["--upstream-proxy", &upstream.to_string(), "--endpoint-ip", "127.0.0.1"]).await;
+
+    let (mut client, reply) = connect(proxy, &domain_request("localhost", 9)).await;
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+    assert_eq!(vec![0x01, 127, 0, 0, 1], requested.await.unwrap());
+}
+
+#[tokio::test]
+async fn upstream_proxy_receives_domains_unresolved_when_resolve_domains_is_false() {
+    let (upstream, requested) = start_upstream().await;
+    let proxy = start_proxy(&["--upstream-proxy", &upstream.to_string(), "--resolve-domains", "false"]).await;
+
+    let (mut client, reply) = connect(proxy, &domain_request("localhost", 9)).await;
+    assert_eq!(0x00, reply);
+    assert_round_trips(&mut client).await;
+    assert_eq!([&[0x03, 9][..], b"localhost"].concat(), requested.await.unwrap());
+}
+
+#[tokio::test]
+async fn failed_tls_handshakes_ban_the_client() {
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+    let dir = std::env::temp_dir().join(format!("rusty_socks_tls_ban_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+    std::fs::write(&cert_path, certified.cert.pem()).unwrap();
+    std::fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+
+    let proxy = start_proxy(&["--tls-cert", cert_path.to_str().unwrap(), "--tls-key", key_path.to_str().unwrap(), "--ban-threshold", "1"]).await;
+
+    // A client that does not speak TLS fails the handshake.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    client.write_all(&[0x05, 0x01, 0x00]).await.unwrap();
+    client.read_to_end(&mut Vec::new()).await.unwrap_or_default();
+
+    // Now banned, it is dropped before the handshake starts.
+    let mut client = TcpStream::connect(proxy).await.unwrap();
+    let mut reply = Vec::new();
+    let read = tokio::time::timeout(std::time::Duration::from_secs(2), client.read_to_end(&mut reply)).await;
+    assert!(matches!(read, Ok(Ok(0))));
+
+    std::fs::remove_dir_all(&dir).unwrap_or_default();
+}